@@ -0,0 +1,93 @@
+//! Async adapter over `SimpleDB`, behind the `async` feature. Every call
+//! runs the blocking operation with `tokio::task::spawn_blocking`, so it has
+//! to be awaited from within a tokio runtime.
+
+use crate::db::SimpleDB;
+use crate::error::DatabaseError;
+
+use std::panic;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::task;
+
+/// Async adapter over a `SimpleDB`. Every operation runs the blocking call
+/// on tokio's blocking thread pool and completes once it returns, so
+/// awaiting it never blocks the executor.
+#[derive(Clone)]
+pub struct AsyncDb {
+    db: Arc<SimpleDB>,
+}
+
+impl AsyncDb {
+    pub fn new(db: SimpleDB) -> Self {
+        AsyncDb { db: Arc::new(db) }
+    }
+
+    pub async fn open(path: PathBuf) -> Result<Self, DatabaseError> {
+        let db = spawn_blocking(move || SimpleDB::open(path)).await?;
+        Ok(AsyncDb::new(db))
+    }
+
+    pub async fn get<S: Into<Vec<u8>>>(&self, key: S) -> Option<Vec<u8>> {
+        let key: Vec<u8> = key.into();
+        let db = Arc::clone(&self.db);
+        spawn_blocking(move || db.get(key)).await
+    }
+
+    pub async fn put<S: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &self,
+        key: S,
+        value: V,
+    ) -> Result<(), DatabaseError> {
+        let key: Vec<u8> = key.into();
+        let value: Vec<u8> = value.into();
+        let db = Arc::clone(&self.db);
+        spawn_blocking(move || db.put(key, value)).await
+    }
+
+    pub async fn delete<S: Into<Vec<u8>>>(&self, key: S) -> Result<(), DatabaseError> {
+        let key: Vec<u8> = key.into();
+        let db = Arc::clone(&self.db);
+        spawn_blocking(move || db.delete(key)).await
+    }
+
+    pub async fn commit(&self) -> Result<u64, DatabaseError> {
+        let db = Arc::clone(&self.db);
+        spawn_blocking(move || db.commit()).await
+    }
+
+    /// The underlying database, for calls that are fine to block on.
+    pub fn inner(&self) -> &SimpleDB {
+        &self.db
+    }
+}
+
+/// Runs `f` on the blocking pool and returns its result, a panic in `f` is
+/// resumed by the task awaiting it.
+async fn spawn_blocking<T, F>(f: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    match task::spawn_blocking(f).await {
+        Ok(value) => value,
+        Err(error) => panic::resume_unwind(error.into_panic()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{delete_db_files, get_temp_test_current_path};
+    use serial_test::serial;
+
+    #[tokio::test]
+    #[serial]
+    async fn test_async_db() {
+        let db = AsyncDb::open(get_temp_test_current_path()).await.unwrap();
+        db.put("key", "value").await.unwrap();
+        assert_eq!(db.get("key").await, Some("value".into()));
+        drop(db);
+        delete_db_files();
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+
+/// A fixed-size bloom filter over keys. It never reports an inserted key as
+/// absent, and reports absent keys as present with roughly the false
+/// positive rate it was sized for, as long as it holds no more keys than
+/// that.
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        let expected_items = expected_items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-expected_items * false_positive_rate.ln() / (ln2 * ln2))
+            .ceil()
+            .max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / expected_items) * ln2).round().max(1.0) as u32;
+
+        BloomFilter {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for bit in self.bit_indices(key) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// Returns false only if `key` was never inserted.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.bit_indices(key)
+            .all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Derives the filter's hashes from the two halves of a single hash.
+    fn bit_indices(&self, key: &[u8]) -> impl Iterator<Item = u64> {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(key);
+        let hash = hasher.finish();
+        let (h1, h2) = (hash & 0xffff_ffff, hash >> 32);
+        let num_bits = self.num_bits;
+
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut filter = BloomFilter::new(1000, 0.01);
+        for i in 0..1000 {
+            filter.insert(format!("key{}", i).as_bytes());
+        }
+
+        assert!((0..1000).all(|i| filter.may_contain(format!("key{}", i).as_bytes())));
+        let false_positives = (0..10_000)
+            .filter(|i| filter.may_contain(format!("absent{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+}
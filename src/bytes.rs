@@ -1,35 +1,142 @@
+use std::convert::TryFrom;
 use std::io::Read;
-use std::io::Seek;
-use std::io::Write;
+
+use crate::cipher::{self, Cipher};
+use crate::compression::{self, Compression};
 
 pub const U64_BYTES_LEN: usize = 8;
 
-pub fn write_encoded_char_to_buffer(c: char, buf: &mut Vec<u8>) -> () {
-    buf.extend((1 as u64).to_be_bytes());
+/// Set on a value's length prefix when the value starts with a codec tag,
+/// see `compression`.
+const COMPRESSED_FLAG: u64 = 1 << 63;
+
+/// Set on a value's length prefix when the value is a nonce followed by the
+/// encrypted encoding of the value, see `cipher`.
+const ENCRYPTED_FLAG: u64 = 1 << 62;
+
+/// Default cap on the declared length of a key or value read from the log,
+/// longer ones are taken as a corrupt length prefix rather than allocated.
+pub const DEFAULT_MAX_ENTRY_BYTES: u64 = 1 << 30;
+
+#[cfg(test)]
+pub fn write_encoded_char_to_buffer(c: char, buf: &mut Vec<u8>) {
+    buf.extend(1u64.to_be_bytes());
     buf.push(c as u8);
 }
 
-pub fn write_encoded_bytes_to_buffer(bytes: Vec<u8>, buf: &mut Vec<u8>) -> () {
+pub fn write_encoded_bytes_to_buffer(bytes: &[u8], buf: &mut Vec<u8>) {
     buf.extend(encode_be_u64(bytes.len()));
-    buf.extend(bytes);
+    buf.extend_from_slice(bytes);
 }
 
-pub fn read_bytes_from_log<T: Read + Seek>(
+/// Writes `value` compressed with `compression`, then encrypted with
+/// `cipher` if there is one.
+pub fn write_encoded_value_to_buffer(
+    value: &[u8],
+    compression: Compression,
+    cipher: Option<&dyn Cipher>,
+    buf: &mut Vec<u8>,
+) {
+    if let Some(cipher) = cipher {
+        let mut encoded = vec![];
+        write_encoded_value_to_buffer(value, compression, None, &mut encoded);
+        let nonce = cipher::next_nonce();
+        let encrypted = cipher.encrypt(nonce, &encoded);
+        write_u64_to_buffer((encrypted.len() + U64_BYTES_LEN) as u64 | ENCRYPTED_FLAG, buf);
+        write_u64_to_buffer(nonce, buf);
+        buf.extend(encrypted);
+        return;
+    }
+
+    match compression::compress(value, compression) {
+        Some((tag, compressed)) => {
+            write_u64_to_buffer((compressed.len() as u64 + 1) | COMPRESSED_FLAG, buf);
+            buf.push(tag);
+            buf.extend(compressed);
+        }
+        None => write_encoded_bytes_to_buffer(value, buf),
+    }
+}
+
+pub fn write_u64_to_buffer(n: u64, buf: &mut Vec<u8>) {
+    buf.extend(n.to_be_bytes());
+}
+
+pub fn read_bytes_from_log<T: Read>(
     reader: &mut T,
     bytes_length: u64,
 ) -> std::io::Result<Vec<u8>> {
-    let mut buf: Vec<u8> = vec![0u8; bytes_length as usize];
+    let bytes_length = usize::try_from(bytes_length).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Length of {} bytes doesn't fit in memory", bytes_length),
+        )
+    })?;
+    let mut buf: Vec<u8> = vec![0u8; bytes_length];
     reader.read_exact(&mut buf)?;
     Ok(buf)
 }
 
-pub fn read_u64_from_log<T: Read + Seek>(reader: &mut T) -> u64 {
+pub fn read_value_from_log<T: Read>(
+    reader: &mut T,
+    cipher: Option<&dyn Cipher>,
+) -> std::io::Result<Vec<u8>> {
+    let length = read_u64_from_log(reader)?;
+    read_value_with_length(reader, length, cipher)
+}
+
+/// Length in bytes of the value stored after the length prefix `length`.
+pub fn value_len(length: u64) -> u64 {
+    length & !(COMPRESSED_FLAG | ENCRYPTED_FLAG)
+}
+
+/// Reads the value following the already read length prefix `length`.
+/// Encrypted values are decrypted with `cipher`, and fail to read without
+/// one.
+pub fn read_value_with_length<T: Read>(
+    reader: &mut T,
+    length: u64,
+    cipher: Option<&dyn Cipher>,
+) -> std::io::Result<Vec<u8>> {
+    if length & ENCRYPTED_FLAG != 0 {
+        let encrypted = read_bytes_from_log(reader, value_len(length))?;
+        let cipher = cipher.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Value is encrypted but no cipher was given",
+            )
+        })?;
+        if encrypted.len() < U64_BYTES_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Encrypted value is missing its nonce",
+            ));
+        }
+        let (nonce, ciphertext) = encrypted.split_at(U64_BYTES_LEN);
+        let nonce = read_u64_from_log(&mut &nonce[..])?;
+        let encoded = cipher.decrypt(nonce, ciphertext)?;
+        return read_value_from_log(&mut &encoded[..], None);
+    }
+
+    if length & COMPRESSED_FLAG == 0 {
+        return read_bytes_from_log(reader, length);
+    }
+
+    let bytes = read_bytes_from_log(reader, length & !COMPRESSED_FLAG)?;
+    match bytes.split_first() {
+        Some((tag, compressed)) => compression::decompress(*tag, compressed),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Compressed value is missing its codec tag",
+        )),
+    }
+}
+
+pub fn read_u64_from_log<T: Read>(reader: &mut T) -> std::io::Result<u64> {
     let mut len_buf = [0; U64_BYTES_LEN];
-    reader
-        .read_exact(&mut len_buf)
-        .expect("Failed reading u64 from log");
+    reader.read_exact(&mut len_buf)?;
 
-    u64::from_be_bytes(len_buf)
+    Ok(u64::from_be_bytes(len_buf))
 }
 
 fn encode_be_u64(n: usize) -> [u8; U64_BYTES_LEN] {
@@ -0,0 +1,42 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Encryption applied to values before they are written to the log and the
+/// checkpoint, see `DbOptions::encryption`. Every value is encrypted with a
+/// nonce of its own, stored next to it in plaintext.
+pub trait Cipher: Send + Sync {
+    fn encrypt(&self, nonce: u64, plaintext: &[u8]) -> Vec<u8>;
+
+    /// Reverses `encrypt`, an `InvalidData` error if `ciphertext` doesn't
+    /// decrypt, e.g. because it was written with another key.
+    fn decrypt(&self, nonce: u64, ciphertext: &[u8]) -> std::io::Result<Vec<u8>>;
+}
+
+/// The `Cipher` registered through `DbOptions::encryption`.
+#[derive(Clone)]
+pub(crate) struct CipherHook(pub(crate) Arc<dyn Cipher>);
+
+impl fmt::Debug for CipherHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CipherHook")
+    }
+}
+
+static LAST_NONCE: AtomicU64 = AtomicU64::new(0);
+
+/// A nonce for the next encrypted value: the current time in nanoseconds,
+/// bumped past the last one handed out so nonces never repeat within a
+/// process, nor across restarts unless the clock goes back.
+pub(crate) fn next_nonce() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos() as u64)
+        .unwrap_or_default();
+    let next = |last: u64| now.max(last.wrapping_add(1));
+    let last = LAST_NONCE
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| Some(next(last)))
+        .unwrap_or_else(|last| last);
+    next(last)
+}
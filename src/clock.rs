@@ -0,0 +1,17 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Source of wall-clock time, in milliseconds since the unix epoch.
+pub trait Clock: Send + Sync {
+    fn now_millis(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_millis(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or(0)
+    }
+}
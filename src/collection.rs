@@ -0,0 +1,52 @@
+use crate::bytes;
+use crate::db::SimpleDB;
+use crate::error::DatabaseError;
+
+/// A named keyspace inside a `SimpleDB`, see `SimpleDB::collection`.
+///
+/// Keys are stored prefixed with the length-prefixed collection name, so
+/// equal keys in different collections never collide.
+pub struct Collection<'a> {
+    db: &'a SimpleDB,
+    prefix: Vec<u8>,
+}
+
+impl<'a> Collection<'a> {
+    pub(crate) fn new(db: &'a SimpleDB, name: &str) -> Self {
+        let mut prefix = vec![];
+        bytes::write_encoded_bytes_to_buffer(name.as_bytes(), &mut prefix);
+        Collection { db, prefix }
+    }
+
+    pub fn get<S: Into<Vec<u8>>>(&self, key: S) -> Option<Vec<u8>> {
+        self.db.get(self.prefixed(key))
+    }
+
+    pub fn put<S: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &self,
+        key: S,
+        value: V,
+    ) -> Result<(), DatabaseError> {
+        self.db.put(self.prefixed(key), value)
+    }
+
+    pub fn delete<S: Into<Vec<u8>>>(&self, key: S) -> Result<(), DatabaseError> {
+        self.db.delete(self.prefixed(key))
+    }
+
+    /// Returns every record of this collection whose key starts with
+    /// `prefix`, with keys relative to the collection.
+    pub fn scan_prefix<S: Into<Vec<u8>>>(&self, prefix: S) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.db
+            .scan_prefix(self.prefixed(prefix))
+            .into_iter()
+            .map(|(key, value)| (key[self.prefix.len()..].to_vec(), value))
+            .collect()
+    }
+
+    fn prefixed<S: Into<Vec<u8>>>(&self, key: S) -> Vec<u8> {
+        let mut prefixed = self.prefix.clone();
+        prefixed.extend(key.into());
+        prefixed
+    }
+}
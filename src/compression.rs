@@ -0,0 +1,138 @@
+use crate::bytes::DEFAULT_MAX_ENTRY_BYTES;
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use std::io::{Read, Write};
+
+/// Codec applied to values before they are written to the log and the
+/// checkpoint. Compressed values carry a one-byte tag naming their codec, so
+/// values written with any setting can be read back regardless of the
+/// current one.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Compression {
+    /// Values are stored as-is.
+    #[default]
+    None,
+    /// Runs of repeated bytes are stored as (run length, byte) pairs. Only
+    /// pays off for values made of long runs of one byte.
+    RunLength,
+    /// DEFLATE, as used by zlib and gzip, which suits text and JSON.
+    Deflate,
+}
+
+const RUN_LENGTH_TAG: u8 = 1;
+const DEFLATE_TAG: u8 = 2;
+
+/// Compresses `value`, returning the codec tag and the compressed bytes, or
+/// `None` when the value is better stored uncompressed.
+pub fn compress(value: &[u8], compression: Compression) -> Option<(u8, Vec<u8>)> {
+    let (tag, compressed) = match compression {
+        Compression::None => return None,
+        Compression::RunLength => (RUN_LENGTH_TAG, run_length_encode(value)),
+        Compression::Deflate => (DEFLATE_TAG, deflate(value).ok()?),
+    };
+
+    if compressed.len() + 1 < value.len() {
+        Some((tag, compressed))
+    } else {
+        None
+    }
+}
+
+pub fn decompress(tag: u8, compressed: &[u8]) -> std::io::Result<Vec<u8>> {
+    match tag {
+        RUN_LENGTH_TAG => run_length_decode(compressed),
+        DEFLATE_TAG => inflate(compressed),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Unknown compression codec tag: {}", tag),
+        )),
+    }
+}
+
+fn run_length_encode(value: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![];
+    let mut bytes = value.iter().peekable();
+    while let Some(&byte) = bytes.next() {
+        let mut run: u8 = 1;
+        while run < u8::MAX && bytes.peek() == Some(&&byte) {
+            bytes.next();
+            run += 1;
+        }
+        encoded.push(run);
+        encoded.push(byte);
+    }
+    encoded
+}
+
+fn run_length_decode(encoded: &[u8]) -> std::io::Result<Vec<u8>> {
+    let pairs = encoded.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Truncated run-length encoded value",
+        ));
+    }
+
+    let mut decoded = vec![];
+    for pair in pairs {
+        decoded.resize(decoded.len() + pair[0] as usize, pair[1]);
+    }
+    Ok(decoded)
+}
+
+fn deflate(value: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(value)?;
+    encoder.finish()
+}
+
+/// Inflates `compressed`, failing rather than allocating more than the
+/// largest value a log entry may hold.
+fn inflate(compressed: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut decoded = vec![];
+    DeflateDecoder::new(compressed)
+        .take(DEFAULT_MAX_ENTRY_BYTES + 1)
+        .read_to_end(&mut decoded)?;
+    if decoded.len() as u64 > DEFAULT_MAX_ENTRY_BYTES {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Deflated value is over the entry size limit",
+        ));
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::json_value;
+
+    #[test]
+    fn test_run_length_round_trip() {
+        let value: Vec<u8> = [vec![b'a'; 1000], b"hello".to_vec(), vec![0; 300]].concat();
+
+        let (tag, compressed) = compress(&value, Compression::RunLength).unwrap();
+        assert!(compressed.len() < value.len());
+        assert_eq!(decompress(tag, &compressed).unwrap(), value);
+    }
+
+    #[test]
+    fn test_deflate_round_trip() {
+        let value = json_value();
+
+        let (tag, compressed) = compress(&value, Compression::Deflate).unwrap();
+        assert!(compressed.len() * 4 < value.len());
+        assert_eq!(decompress(tag, &compressed).unwrap(), value);
+        assert_eq!(compress(&value, Compression::RunLength), None);
+        assert!(decompress(DEFLATE_TAG, b"not deflate").is_err());
+    }
+
+    #[test]
+    fn test_incompressible_value_is_stored_raw() {
+        assert_eq!(compress(b"abcdef", Compression::RunLength), None);
+        assert_eq!(compress(b"abcdef", Compression::Deflate), None);
+        assert_eq!(compress(&[b'a'; 100], Compression::None), None);
+    }
+
+}
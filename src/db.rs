@@ -1,334 +1,7184 @@
-use std::fs::remove_file;
-use crate::error::{DatabaseError, LockKind};
-use crate::log::{Log, LogOperation};
+use crate::bloom::BloomFilter;
+use crate::cipher::Cipher;
+use crate::clock::{Clock, SystemClock};
+use crate::collection::Collection;
+use crate::entry::Entry;
+use crate::error::{DatabaseError, LockKind, LogError};
+use crate::index;
+use crate::log::{
+    self, compact_operations, Log, LogFile, LogOpRef, LogOperation, SyncPolicy,
+};
+use crate::metrics::{Counters, Metrics, OpCounters};
+use crate::options::{DbOptions, FileLayout};
+use crate::residency::Residency;
+use crate::snapshot::Snapshot;
 
 use crate::bytes;
 
-use std::collections::HashMap;
-use std::fs::{create_dir, File};
-use std::io::{Read, Seek, Write};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::hash::Hasher;
+use std::fs::{create_dir, File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{
+    Arc, Condvar, LockResult, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard,
+    RwLockWriteGuard, TryLockError, TryLockResult,
+};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-type SimpleCollection = HashMap<Vec<u8>, Vec<u8>>;
-type Records = Arc<RwLock<SimpleCollection>>;
+pub(crate) type SimpleCollection = HashMap<Vec<u8>, Vec<u8>>;
+/// Shared with snapshots, writers copy the map first if a snapshot still
+/// holds it.
+type Records = RwLock<Arc<SimpleCollection>>;
+pub(crate) type Expiries = HashMap<Vec<u8>, u64>;
+type Checksums = HashMap<Vec<u8>, u64>;
+type EvictedEntries = Vec<(Vec<u8>, Vec<u8>)>;
+/// Offset of the record of every key in a checkpoint.
+type CheckpointIndex = HashMap<Vec<u8>, u64>;
+/// Channels of `watch_key`, with the key each one watches.
+type KeyWatcherList = Vec<(Vec<u8>, Sender<Option<Vec<u8>>>)>;
+type KeyWatchers = Mutex<KeyWatcherList>;
 
-const CHECKPOINT_FILE_NAME: &str = "checkpoint";
-const LOG_FILE_NAME: &str = "logfile";
-const VERSION_FILE_NAME: &str = "version";
-const NEW_VERSION_FILE_NAME: &str = "new_version";
+pub(crate) const CHECKPOINT_FILE_NAME: &str = "checkpoint";
+/// Checkpoints start with the magic bytes and the format version they were
+/// written in, checkpoints without them predate the header.
+pub const CHECKPOINT_MAGIC: &[u8; 4] = b"SDBC";
+pub const CHECKPOINT_FORMAT_VERSION: u64 = 1;
+pub(crate) const LOG_FILE_NAME: &str = "logfile";
+pub(crate) const COMPACTED_LOG_FILE_NAME: &str = "logfile_compacted";
+pub(crate) const VERSION_FILE_NAME: &str = "version";
+pub(crate) const LOCK_FILE_NAME: &str = "LOCK";
+pub(crate) const NEW_VERSION_FILE_NAME: &str = "new_version";
+pub(crate) const INDEX_FILE_NAME: &str = "index";
+/// How long `lock_within` waits between attempts at a contended lock.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Result of `SimpleDB::verify`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct VerifyReport {
+    pub checkpoint_valid: bool,
+    pub checkpoint_records: usize,
+    pub valid_log_entries: usize,
+    pub invalid_log_entries: usize,
+    /// Whether the log ends in an entry cut short, e.g. by a crash mid-append.
+    pub partial_tail: bool,
+}
+
+impl VerifyReport {
+    pub fn is_healthy(&self) -> bool {
+        self.checkpoint_valid && self.invalid_log_entries == 0
+    }
+}
+
+/// Result of `check_integrity`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct IntegrityReport {
+    /// Version the version file points to, `None` if it can't be read.
+    pub version: Option<u64>,
+    pub anomalies: Vec<Anomaly>,
+}
+
+impl IntegrityReport {
+    pub fn is_healthy(&self) -> bool {
+        self.anomalies.is_empty()
+    }
+}
+
+/// A problem found by `check_integrity`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Anomaly {
+    /// The version file is missing or doesn't hold a version.
+    BadVersionFile,
+    MissingCheckpoint { version: u64 },
+    CorruptCheckpoint { version: u64 },
+    MissingLog { version: u64 },
+    /// An entry of the log fails to decode, the entries after it are lost.
+    CorruptLog { valid_entries: usize },
+    /// The log ends in an entry cut short, e.g. by a crash mid-append.
+    /// Opening the database for writing cuts it off the log, read-only opens
+    /// skip it.
+    PartialLogTail { valid_entries: usize },
+}
+
+/// Point-in-time counters returned by `SimpleDB::stats`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DatabaseStats {
+    pub version: u64,
+    pub num_records: usize,
+    pub log_size_bytes: u64,
+    pub commit_in_progress: bool,
+}
+
+/// Result of `SimpleDB::log_redundancy`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LogRedundancy {
+    pub total_entries: usize,
+    /// Keys whose last operation in the log leaves them with a value.
+    pub live_keys: usize,
+    /// How much smaller `compact_log` would make the log.
+    pub reclaimable_bytes: u64,
+}
+
+/// Result of `SimpleDB::status`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum KeyStatus {
+    Present(Vec<u8>),
+    /// Deleted since the last commit.
+    Deleted,
+    /// Never written, or deleted before the last commit.
+    Unknown,
+}
+
+/// The records a commit writes out, a snapshot of the live ones or the ones
+/// `rollback_to_version` restores or `bulk_load` loads.
+struct CommitSource {
+    records: Arc<SimpleCollection>,
+    expiries: Expiries,
+    evicted: EvictedEntries,
+}
+
+/// Points in `commit` and `compact_log` where a test can inject a crash, see
+/// `test_utils::crash_commit_at`, or pause the call with `set_pause_point`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum CrashPoint {
+    BeforeCheckpointRename,
+    AfterCheckpointWrite,
+    AfterNewVersionFile,
+    BeforeRename,
+    BeforeCompactedLogInstall,
+}
 
 pub struct SimpleDB {
     records: Records,
-    log: Log<File>,
+    expiries: RwLock<Expiries>,
+    clock: Arc<dyn Clock>,
+    log: Mutex<Log<LogFile>>,
     path: PathBuf,
-    version: u64,
-    commit_in_progress: bool,
+    /// Set for databases from `open_in_memory`, which never touch disk.
+    in_memory: bool,
+    version: AtomicU64,
+    /// When the current version was committed, in milliseconds since the
+    /// unix epoch, unknown for versions committed before it was recorded.
+    last_commit_millis: Mutex<Option<u64>>,
+    commit_in_progress: AtomicBool,
+    /// Whether the records may differ from the current checkpoint, commits
+    /// reuse the checkpoint while they don't.
+    changed_since_commit: AtomicBool,
+    /// Writes logged since the last commit, see `DbOptions::auto_commit_ops`.
+    ops_since_commit: AtomicUsize,
+    counters: Counters,
+    sequence: AtomicU64,
+    /// Keys deleted since the last commit, rebuilt from the log on open.
+    tombstones: Mutex<HashSet<Vec<u8>>>,
+    /// The last `sequence` known to be synced, see `put_deferred`.
+    durable_sequence: Mutex<u64>,
+    durable: Condvar,
+    subscribers: Mutex<Vec<Sender<LogOperation>>>,
+    key_watchers: KeyWatchers,
+    /// Dropping the sender stops the background compaction thread.
+    compaction_stop: Mutex<Option<Sender<()>>>,
+    /// Every key put since the last load or commit, if enabled through
+    /// `DbOptions::bloom_false_positive_rate`.
+    bloom: RwLock<Option<BloomFilter>>,
+    /// Checksum of every value, if enabled through
+    /// `DbOptions::checksum_values`.
+    checksums: RwLock<Option<Checksums>>,
+    /// Which records are in memory, if capped by `DbOptions::max_records`.
+    residency: Mutex<Option<Residency>>,
+    options: DbOptions,
+    /// Holds the advisory lock on the `LOCK` file, read-only handles don't
+    /// take it since they never modify the database files.
+    lock_file: Option<File>,
+    #[cfg(test)]
+    crash_point: Option<CrashPoint>,
+    #[cfg(test)]
+    pause_point: Option<(CrashPoint, Arc<std::sync::Barrier>)>,
 }
 
-unsafe impl Send for SimpleDB {}
-unsafe impl Sync for SimpleDB {}
-
 impl SimpleDB {
     pub fn open(path: PathBuf) -> Result<Self, DatabaseError> {
-        if path.exists() {
-            SimpleDB::try_load_from_existing(&path)
+        DbOptions::default().open(path)
+    }
+
+    pub(crate) fn open_with_options(
+        path: PathBuf,
+        options: DbOptions,
+    ) -> Result<Self, DatabaseError> {
+        options.validate()?;
+        if path.exists() && !path.is_dir() {
+            return Err(DatabaseError::NotADatabase { path });
+        }
+        if path.exists() || options.read_only {
+            let db = SimpleDB::try_load_from_existing(&path, options)?;
+            if db.options.compact_on_open && !db.options.read_only && db.log_entry_count()? > 0 {
+                db.commit()?;
+            }
+            Ok(db)
         } else {
-            let records = Arc::new(RwLock::new(HashMap::new()));
-            let version = 0;
-            create_dir(&path)?;
-            create_version_file(&path, version, false)?;
-
-            create_db_file(&path, version, CHECKPOINT_FILE_NAME)?;
-            create_db_file(&path, version, LOG_FILE_NAME)?;
-
-            let log_path = get_db_file_path(&path, Some(version), LOG_FILE_NAME);
-            let log = Log::<File>::open(&log_path)?;
-
-            Ok(SimpleDB {
-                records,
-                log,
-                path,
-                version,
-                commit_in_progress: false,
-            })
+            SimpleDB::create(path, HashMap::new(), HashMap::new(), options)
         }
     }
 
+    /// Creates a new database at `path` from a stream written by `backup_to`.
+    /// The restored records become the version 0 checkpoint.
+    pub fn restore_from<R: Read + Seek>(
+        path: PathBuf,
+        reader: &mut R,
+    ) -> Result<Self, DatabaseError> {
+        let records = SimpleDB::read_records_from_file(reader, None)?;
+        SimpleDB::create(path, records, HashMap::new(), DbOptions::default())
+    }
+
+    /// Opens an existing database without write access, `put`, `delete` and
+    /// `commit` on the returned handle fail with `DatabaseError::ReadOnly`.
+    pub fn open_read_only(path: PathBuf) -> Result<Self, DatabaseError> {
+        DbOptions::new().read_only(true).open(path)
+    }
+
+    /// Opens `version` of the database at `path` read-only, as a handle
+    /// would have seen it until the next commit: its checkpoint with its log
+    /// replayed. Only versions kept through `DbOptions::retain_versions` can
+    /// be opened, purged ones fail with `DatabaseError::MissingCheckpoint`.
+    /// Databases written with non-default options are opened through
+    /// `DbOptions::open_version`.
+    pub fn open_version(path: PathBuf, version: u64) -> Result<Self, DatabaseError> {
+        DbOptions::new().open_version(path, version)
+    }
+
+    pub(crate) fn open_version_with_options(
+        path: PathBuf,
+        version: u64,
+        options: DbOptions,
+    ) -> Result<Self, DatabaseError> {
+        options.validate()?;
+        let options = options.read_only(true);
+        let checkpoint =
+            SimpleDB::load_checkpoint(&path, &options.layout, version, options.cipher())?;
+        let version_file = VersionFile {
+            version,
+            committed_at: None,
+        };
+        SimpleDB::replay_version(&path, options, None, version_file, checkpoint)
+    }
+
+    /// Creates an empty database that lives only in memory, no files are
+    /// created. `commit` starts a new log in memory instead of writing a
+    /// checkpoint, and everything is lost once the handle is dropped.
+    pub fn open_in_memory() -> SimpleDB {
+        let mut db = SimpleDB::from_parts(
+            PathBuf::new(),
+            0,
+            HashMap::new(),
+            HashMap::new(),
+            Log::in_memory(),
+            DbOptions::default(),
+            None,
+        );
+        db.in_memory = true;
+        *db.get_last_commit_millis() = Some(db.clock.now_millis());
+        db
+    }
+
     pub fn get<S: Into<Vec<u8>>>(&self, key: S) -> Option<Vec<u8>> {
-        self.records
-            .read()
+        self.get_ref(&key.into())
+    }
+
+    /// Like `get`, but looks `key` up without copying it into a `Vec`.
+    pub fn get_ref(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.read_value(key, |value| value.to_vec()).ok().flatten()
+    }
+
+    /// Like `get`, but fails with `DatabaseError::Lock` if the records can't
+    /// be read, where `get` returns `None` as if the key were missing.
+    pub fn try_get<S: Into<Vec<u8>>>(&self, key: S) -> Result<Option<Vec<u8>>, DatabaseError> {
+        self.read_value(&key.into(), |value| value.to_vec())
+    }
+
+    /// Like `get`, but copies the value into `buf`, replacing its contents,
+    /// so one buffer can be reused across reads. Returns whether the key was
+    /// found, `buf` is left empty if it wasn't.
+    pub fn get_into<S: Into<Vec<u8>>>(&self, key: S, buf: &mut Vec<u8>) -> bool {
+        buf.clear();
+        self.read_value(&key.into(), |value| buf.extend_from_slice(value))
+            .ok()
+            .flatten()
+            .is_some()
+    }
+
+    /// Looks `key` up and passes its value to `f`, resident values are
+    /// passed without being copied out of the records.
+    fn read_value<T, F: FnOnce(&[u8]) -> T>(
+        &self,
+        key: &[u8],
+        f: F,
+    ) -> Result<Option<T>, DatabaseError> {
+        match &self.options.key_transform {
+            Some(transform) => self.read_transformed(&(transform.0)(key), f),
+            None => self.read_transformed(key, f),
+        }
+    }
+
+    /// Like `read_value`, for a key `DbOptions::key_transform` was already
+    /// applied to.
+    fn read_transformed<T, F: FnOnce(&[u8]) -> T>(
+        &self,
+        key: &[u8],
+        f: F,
+    ) -> Result<Option<T>, DatabaseError> {
+        if !self.may_contain(key) || self.remove_if_expired(key) {
+            self.record_get(false);
+            return Ok(None);
+        }
+
+        let mut f = Some(f);
+        let resident = self
+            .read_records()?
+            .get(key)
+            .and_then(|value| f.take().map(|f| f(value)));
+        let value = match resident {
+            Some(value) => {
+                self.touch(key);
+                Some(value)
+            }
+            None => self
+                .get_evicted(key)
+                .and_then(|value| f.take().map(|f| f(&value))),
+        };
+        self.record_get(value.is_some());
+        Ok(value)
+    }
+
+    /// Like `get`, for a key `DbOptions::key_transform` was already applied
+    /// to.
+    fn get_transformed(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.read_transformed(key, |value| value.to_vec()).ok().flatten()
+    }
+
+    fn record_get(&self, hit: bool) {
+        self.counters.on_get(hit);
+        if let Some(metrics) = self.metrics() {
+            if hit {
+                metrics.on_get_hit();
+            } else {
+                metrics.on_get_miss();
+            }
+        }
+    }
+
+    /// Gets, puts, deletes and commits made through this handle since it
+    /// was opened or `reset_counters` was last called. Unlike `Metrics`,
+    /// these are always counted.
+    pub fn op_counters(&self) -> OpCounters {
+        self.counters.snapshot()
+    }
+
+    pub fn reset_counters(&self) {
+        self.counters.reset();
+    }
+
+    /// Tells a key deleted since the last commit apart from one that was
+    /// never written, e.g. to replicate deletes. Deletes are forgotten on
+    /// `commit`, as are the keys wiped by `clear`.
+    pub fn status<S: Into<Vec<u8>>>(&self, key: S) -> KeyStatus {
+        let key = self.transform_key(key.into());
+        if let Some(value) = self.get_transformed(&key) {
+            return KeyStatus::Present(value);
+        }
+        let deleted = self.get_tombstones().map(|tombstones| tombstones.contains(&key));
+        if deleted.unwrap_or(false) {
+            KeyStatus::Deleted
+        } else {
+            KeyStatus::Unknown
+        }
+    }
+
+    /// Like `get`, but verifies the value against the checksum computed
+    /// when it was written, see `DbOptions::checksum_values`. Without that
+    /// option it behaves like `get`.
+    pub fn get_checked<S: Into<Vec<u8>>>(
+        &self,
+        key: S,
+    ) -> Result<Option<Vec<u8>>, DatabaseError> {
+        let key = self.transform_key(key.into());
+        let now = self.clock.now_millis();
+        let resident = {
+            let records = self.read_records()?;
+            let expiries = self.read_expiries()?;
+            match records.get(&key) {
+                Some(value) if !is_expired(&expiries, &key, now) => {
+                    // Verified under the records lock, so a concurrent write
+                    // can't change the value between reading and checking.
+                    self.verify_checksum(&key, value)?;
+                    Some(value.clone())
+                }
+                _ => None,
+            }
+        };
+        if resident.is_some() {
+            self.touch(&key);
+            return Ok(resident);
+        }
+
+        let value = self.get_transformed(&key);
+        if let Some(value) = &value {
+            self.verify_checksum(&key, value)?;
+        }
+        Ok(value)
+    }
+
+    /// Looks up every key of `keys` under a single read lock, the map only
+    /// holds the keys that are present.
+    pub fn get_map<S: Into<Vec<u8>>>(&self, keys: Vec<S>) -> HashMap<Vec<u8>, Vec<u8>> {
+        let keys: Vec<Vec<u8>> = keys
+            .into_iter()
+            .map(|key| self.transform_key(key.into()))
+            .collect();
+        let now = self.clock.now_millis();
+        let mut map = HashMap::new();
+        let mut missing = vec![];
+        {
+            let (records, expiries) = match (self.read_records(), self.read_expiries()) {
+                (Ok(records), Ok(expiries)) => (records, expiries),
+                _ => return map,
+            };
+            for key in keys {
+                if is_expired(&expiries, &key, now) {
+                    continue;
+                }
+                match records.get(&key) {
+                    Some(value) => {
+                        map.insert(key.clone(), value.clone());
+                    }
+                    None => missing.push(key),
+                }
+            }
+        }
+
+        for key in map.keys() {
+            self.touch(key);
+        }
+        for key in missing {
+            if let Some(value) = self.get_evicted(&key) {
+                map.insert(key, value);
+            }
+        }
+        map
+    }
+
+    /// Length of the value stored under `key`, without copying the value.
+    pub fn value_len<S: Into<Vec<u8>>>(&self, key: S) -> Option<usize> {
+        self.value_len_transformed(&self.transform_key(key.into()))
+    }
+
+    /// Like `value_len`, for a key `DbOptions::key_transform` was already
+    /// applied to.
+    fn value_len_transformed(&self, key: &[u8]) -> Option<usize> {
+        if !self.may_contain(key) || self.remove_if_expired(key) {
+            return None;
+        }
+
+        let len = self
+            .read_records()
             .ok()
-            .and_then(|records| records.get(&key.into()).map(|val| val.clone()))
+            .and_then(|records| records.get(key).map(Vec::len));
+        match len {
+            Some(len) => {
+                self.touch(key);
+                Some(len)
+            }
+            None => self.get_evicted(key).map(|value| value.len()),
+        }
+    }
+
+    pub fn contains_key<S: Into<Vec<u8>>>(&self, key: S) -> bool {
+        let key = self.transform_key(key.into());
+        if !self.may_contain(&key) || self.remove_if_expired(&key) {
+            return false;
+        }
+
+        let resident = self
+            .read_records()
+            .map(|records| records.contains_key(&key))
+            .unwrap_or(false);
+        resident || self.get_evicted(&key).is_some()
+    }
+
+    /// Returns every record whose key starts with `prefix`, in no particular
+    /// order.
+    pub fn scan_prefix<S: Into<Vec<u8>>>(&self, prefix: S) -> Vec<(Vec<u8>, Vec<u8>)> {
+        self.scan_prefix_limited(prefix, usize::MAX)
+    }
+
+    /// Like `scan_prefix`, but stops after collecting `limit` records.
+    pub fn scan_prefix_limited<S: Into<Vec<u8>>>(
+        &self,
+        prefix: S,
+        limit: usize,
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let prefix: Vec<u8> = prefix.into();
+        let now = self.clock.now_millis();
+        let mut scanned: Vec<(Vec<u8>, Vec<u8>)> = match (self.read_records(), self.read_expiries()) {
+            (Ok(records), Ok(expiries)) => records
+                .iter()
+                .filter(|(key, _)| key.starts_with(&prefix))
+                .filter(|(key, _)| !is_expired(&expiries, key, now))
+                .take(limit)
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+            _ => vec![],
+        };
+
+        let remaining = limit - scanned.len();
+        scanned.extend(
+            self.evicted_entries()
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|(key, _)| key.starts_with(&prefix))
+                .take(remaining),
+        );
+        scanned
+    }
+
+    /// Tells whether any record's key starts with `prefix`, stopping at the
+    /// first one found.
+    pub fn contains_prefix<S: Into<Vec<u8>>>(&self, prefix: S) -> bool {
+        let prefix: Vec<u8> = prefix.into();
+        let now = self.clock.now_millis();
+        let resident = match (self.read_records(), self.read_expiries()) {
+            (Ok(records), Ok(expiries)) => records
+                .keys()
+                .any(|key| key.starts_with(&prefix) && !is_expired(&expiries, key, now)),
+            _ => false,
+        };
+
+        let evicted = self.evicted_keys().unwrap_or_default();
+        resident || evicted.iter().any(|key| key.starts_with(&prefix))
+    }
+
+    /// Returns a handle to the keyspace `name`, whose keys are isolated from
+    /// those of every other collection.
+    pub fn collection(&self, name: &str) -> Collection<'_> {
+        Collection::new(self, name)
+    }
+
+    /// Copies every live record into a new map. It is a point-in-time copy,
+    /// later writes don't show up in it. Like `get`, it finds no records if
+    /// they can't be read.
+    pub fn to_map(&self) -> HashMap<Vec<u8>, Vec<u8>> {
+        let (records, expiries) = match (self.read_records(), self.read_expiries()) {
+            (Ok(records), Ok(expiries)) => (records, expiries),
+            _ => return HashMap::new(),
+        };
+        let now = self.clock.now_millis();
+        let mut map: HashMap<Vec<u8>, Vec<u8>> = records
+            .iter()
+            .filter(|(key, _)| !is_expired(&expiries, key, now))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        drop(expiries);
+        drop(records);
+
+        match self.evicted_entries() {
+            Ok(evicted) => map.extend(evicted),
+            Err(_) => return HashMap::new(),
+        }
+        map
+    }
+
+    /// Every live record, in ascending byte order of the keys.
+    pub fn entries_sorted(&self) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut entries: Vec<(Vec<u8>, Vec<u8>)> = self.to_map().into_iter().collect();
+        entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        entries
+    }
+
+    /// Every live key, in ascending byte order. No values are copied.
+    pub fn keys_sorted(&self) -> Vec<Vec<u8>> {
+        let now = self.clock.now_millis();
+        let (mut keys, records, expiries) =
+            match (self.evicted_keys(), self.read_records(), self.read_expiries()) {
+                (Ok(evicted), Ok(records), Ok(expiries)) => (evicted, records, expiries),
+                _ => return vec![],
+            };
+        keys.extend(
+            records
+                .keys()
+                .filter(|key| !is_expired(&expiries, key, now))
+                .cloned(),
+        );
+        keys.sort_unstable();
+        keys
+    }
+
+    /// The record with the smallest key, `None` if there are no records.
+    /// The records aren't kept in key order, so this scans every key, but
+    /// only the returned record is copied.
+    pub fn first_key_value(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.edge_key_value(false)
+    }
+
+    /// The record with the largest key, see `first_key_value`.
+    pub fn last_key_value(&self) -> Option<(Vec<u8>, Vec<u8>)> {
+        self.edge_key_value(true)
+    }
+
+    fn edge_key_value(&self, last: bool) -> Option<(Vec<u8>, Vec<u8>)> {
+        let now = self.clock.now_millis();
+        let evicted = self.evicted_keys().ok()?;
+        let records = self.read_records().ok()?;
+        let expiries = self.read_expiries().ok()?;
+        let keys = records
+            .keys()
+            .filter(|key| !is_expired(&expiries, key, now))
+            .chain(evicted.iter());
+        let key = if last { keys.max() } else { keys.min() }?.clone();
+        let value = records.get(&key).cloned();
+        drop(expiries);
+        drop(records);
+
+        match value {
+            Some(value) => Some((key, value)),
+            None => self.get_evicted(&key).map(|value| (key, value)),
+        }
+    }
+
+    /// Returns a view of the records as they are now, unaffected by later
+    /// writes. Records evicted from memory by `DbOptions::max_records` are
+    /// not part of it. Like `get`, it holds no records if they can't be read.
+    pub fn snapshot(&self) -> Snapshot {
+        let now = self.clock.now_millis();
+        match (self.read_records(), self.read_expiries()) {
+            (Ok(records), Ok(expiries)) => {
+                Snapshot::new(Arc::clone(&records), expiries.clone(), now)
+            }
+            _ => Snapshot::new(Arc::new(HashMap::new()), HashMap::new(), now),
+        }
     }
 
     pub fn put<S: Into<Vec<u8>>, V: Into<Vec<u8>>>(
-        &mut self,
+        &self,
         key: S,
         value: V,
     ) -> Result<(), DatabaseError> {
-        let key_as_bytes: Vec<u8> = key.into();
+        self.ensure_writable()?;
+        let key_as_bytes = self.transform_key(key.into());
         let value_as_bytes: Vec<u8> = value.into();
 
-        self.log.append_to_disk(LogOperation::Put(
-            key_as_bytes.clone(),
-            value_as_bytes.clone(),
-        ))?;
+        let mut log = self.get_log()?;
+        self.log_and_apply(&mut log, LogOperation::Put(key_as_bytes, value_as_bytes))?;
 
-        let mut records = self.get_write_records()?;
-        (*records).insert(key_as_bytes, value_as_bytes);
-        Ok(())
+        self.auto_commit_if_needed(log)
     }
 
-    pub fn delete<S: Into<Vec<u8>>>(&mut self, key: S) -> Result<(), DatabaseError> {
-        let key_as_bytes: Vec<u8> = key.into();
-        self.log
-            .append_to_disk(LogOperation::Delete(key_as_bytes.clone()))?;
-        let mut records = self.get_write_records()?;
-        (*records).remove(&key_as_bytes);
+    /// Returns the value stored under `key`, or stores and returns the value
+    /// computed by `f` if there is none. Writers are held off between the
+    /// lookup and the insert, so `f` runs at most once per missing key.
+    pub fn get_or_insert_with<S, F>(&self, key: S, f: F) -> Result<Vec<u8>, DatabaseError>
+    where
+        S: Into<Vec<u8>>,
+        F: FnOnce() -> Vec<u8>,
+    {
+        self.ensure_writable()?;
+        let key_as_bytes = self.transform_key(key.into());
 
-        Ok(())
+        let mut log = self.get_log()?;
+        if let Some(value) = self.get_transformed(&key_as_bytes) {
+            return Ok(value);
+        }
+
+        let value = f();
+        self.log_and_apply(&mut log, LogOperation::Put(key_as_bytes, value.clone()))?;
+        self.auto_commit_if_needed(log)?;
+
+        Ok(value)
+    }
+
+    /// Looks `key` up for a conditional change through the returned `Entry`,
+    /// which holds off other writers until it is dropped.
+    pub fn entry<S: Into<Vec<u8>>>(&self, key: S) -> Result<Entry<'_>, DatabaseError> {
+        self.ensure_writable()?;
+        let key = self.transform_key(key.into());
+        let log = self.get_log()?;
+        Ok(Entry::new(self, key, log))
+    }
+
+    /// Replaces the value under `key` with the result of `f`, which receives
+    /// the current value. Returning `None` deletes the key. The change is
+    /// only logged when the value actually changes, and the new value is
+    /// returned.
+    pub fn update<S, F>(&self, key: S, f: F) -> Result<Option<Vec<u8>>, DatabaseError>
+    where
+        S: Into<Vec<u8>>,
+        F: FnOnce(Option<&[u8]>) -> Option<Vec<u8>>,
+    {
+        self.ensure_writable()?;
+        let key_as_bytes = self.transform_key(key.into());
+
+        let mut log = self.get_log()?;
+        let current = self.get_transformed(&key_as_bytes);
+        let updated = f(current.as_deref());
+        if updated == current {
+            return Ok(updated);
+        }
+
+        let operation = match &updated {
+            Some(value) => LogOperation::Put(key_as_bytes, value.clone()),
+            None => LogOperation::Delete(key_as_bytes),
+        };
+        self.log_and_apply(&mut log, operation)?;
+        self.auto_commit_if_needed(log)?;
+
+        Ok(updated)
     }
 
-    pub fn commit(&mut self) -> Result<(), DatabaseError> {
-        self.commit_in_progress = true;
-        let new_version = self.version + 1;
+    /// Appends `suffix` to the value under `key`, a missing key is treated
+    /// as holding an empty value. Only the suffix is logged, the value is
+    /// rebuilt from the appends on replay. A TTL set on the key is kept.
+    pub fn append_value<S: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &self,
+        key: S,
+        suffix: V,
+    ) -> Result<(), DatabaseError> {
+        self.ensure_writable()?;
+        let key = self.transform_key(key.into());
+        let suffix: Vec<u8> = suffix.into();
+
+        let mut log = self.get_log()?;
+        if let Some(limit) = self.options.max_value_bytes {
+            let actual = self.value_len_transformed(&key).unwrap_or(0) + suffix.len();
+            if actual > limit {
+                return Err(DatabaseError::ValueTooLarge { limit, actual });
+            }
+        }
+        self.log_and_apply(&mut log, LogOperation::Append(key, suffix))?;
 
-        create_version_file(&self.path, new_version, true)?;
+        self.auto_commit_if_needed(log)
+    }
 
-        let mut checkpoint = create_db_file(&self.path, new_version, CHECKPOINT_FILE_NAME)?;
-        self.write_records_to_file(&mut checkpoint)?;
+    /// Stores a value that `get` treats as absent once `ttl` has elapsed.
+    /// Expired entries are dropped lazily on access and on `commit`.
+    pub fn put_with_ttl<S: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &self,
+        key: S,
+        value: V,
+        ttl: Duration,
+    ) -> Result<(), DatabaseError> {
+        self.ensure_writable()?;
+        let ttl_millis = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let expires_at = self.clock.now_millis().saturating_add(ttl_millis);
 
-        create_db_file(&self.path, new_version, LOG_FILE_NAME)?;
-        let log_path = get_db_file_path(&self.path, Some(new_version), LOG_FILE_NAME);
-        let log = Log::<File>::open(&log_path)?;
+        let mut log = self.get_log()?;
+        self.log_and_apply(
+            &mut log,
+            LogOperation::PutWithExpiry(self.transform_key(key.into()), value.into(), expires_at),
+        )?;
 
-        self.log = log;
+        self.auto_commit_if_needed(log)
+    }
 
-        self.commit_in_progress = false;
+    pub fn delete<S: Into<Vec<u8>>>(&self, key: S) -> Result<(), DatabaseError> {
+        self.ensure_writable()?;
+        let key_as_bytes = self.transform_key(key.into());
+        let mut log = self.get_log()?;
+        self.log_and_apply(&mut log, LogOperation::Delete(key_as_bytes))?;
 
-        self.cleanup_previous_commit_files()
-            .expect("Failed to cleanup previous commit files");
+        self.auto_commit_if_needed(log)
+    }
 
-        self.version = new_version;
+    /// Deletes every key `k` with `start <= k < end` in byte order, logging a
+    /// delete for each one, and returns how many keys were removed.
+    pub fn delete_range<S: Into<Vec<u8>>>(
+        &self,
+        start: S,
+        end: S,
+    ) -> Result<usize, DatabaseError> {
+        self.ensure_writable()?;
+        let start: Vec<u8> = start.into();
+        let end: Vec<u8> = end.into();
 
-        Ok(())
+        let mut log = self.get_log()?;
+        let evicted = self.evicted_keys()?;
+        let keys: Vec<Vec<u8>> = self
+            .read_records()?
+            .keys()
+            .chain(evicted.iter())
+            .filter(|key| **key >= start && **key < end)
+            .cloned()
+            .collect();
+
+        for key in keys.iter() {
+            self.log_and_apply(&mut log, LogOperation::Delete(key.clone()))?;
+        }
+        self.auto_commit_if_needed(log)?;
+
+        Ok(keys.len())
     }
 
-    pub fn version(&self) -> u64 {
-        self.version
+    /// Number of live keys starting with `prefix`.
+    pub fn count_prefix<S: Into<Vec<u8>>>(&self, prefix: S) -> usize {
+        self.keys_with_prefix(&prefix.into()).len()
     }
 
-    fn try_load_from_existing(path: &Path) -> Result<SimpleDB, DatabaseError> {
-        let new_version_file_path = get_db_file_path(path, None, NEW_VERSION_FILE_NAME);
-        let version;
-        if new_version_file_path.exists() {
-            version = read_string_from_file(&new_version_file_path)?.parse::<u64>().unwrap();
-            remove_file(new_version_file_path)?;
+    /// Deletes every key starting with `prefix`, logging a delete for each,
+    /// and returns how many keys were removed. With `dry_run` nothing is
+    /// logged or removed, only the count of keys that would be is returned.
+    pub fn delete_prefix<S: Into<Vec<u8>>>(
+        &self,
+        prefix: S,
+        dry_run: bool,
+    ) -> Result<usize, DatabaseError> {
+        let prefix: Vec<u8> = prefix.into();
+        if dry_run {
+            return Ok(self.count_prefix(prefix));
         }
-        else {
-            let version_file_path = get_db_file_path(path, None, VERSION_FILE_NAME);
-            version = read_string_from_file(&version_file_path)?.parse::<u64>().unwrap();
+
+        self.ensure_writable()?;
+        let mut log = self.get_log()?;
+        let keys = self.keys_with_prefix(&prefix);
+        for key in keys.iter() {
+            self.log_and_apply(&mut log, LogOperation::Delete(key.clone()))?;
         }
+        self.auto_commit_if_needed(log)?;
 
-        let mut checkpoint_file = File::open(&get_db_file_path(path, Some(version), CHECKPOINT_FILE_NAME))?;
-        let mut checkpoint: SimpleCollection = match SimpleDB::read_records_from_file(&mut checkpoint_file) {
-            Ok(records) => records,
-            Err(_) => return Err(DatabaseError::LoadCheckpoint)
-        };
+        Ok(keys.len())
+    }
+
+    /// Deletes every record by logging a single `LogOperation::Clear`,
+    /// records in the checkpoint included.
+    pub fn clear(&self) -> Result<(), DatabaseError> {
+        self.ensure_writable()?;
+        let mut log = self.get_log()?;
+        self.log_and_apply(&mut log, LogOperation::Clear)?;
+
+        self.auto_commit_if_needed(log)
+    }
+
+    /// Deletes every record for which `f` returns false, logging a delete
+    /// for each, and returns how many were removed. Other writers are held
+    /// off until the deletes are logged and synced.
+    pub fn retain<F>(&self, mut f: F) -> Result<usize, DatabaseError>
+    where
+        F: FnMut(&[u8], &[u8]) -> bool,
+    {
+        self.ensure_writable()?;
+        let mut log = self.get_log()?;
+        let removed: Vec<Vec<u8>> = self
+            .to_map()
+            .into_iter()
+            .filter(|(key, value)| !f(key, value))
+            .map(|(key, _)| key)
+            .collect();
+
+        for key in removed.iter() {
+            self.log_and_apply(&mut log, LogOperation::Delete(key.clone()))?;
+        }
+        log.sync()?;
+        self.auto_commit_if_needed(log)?;
+
+        Ok(removed.len())
+    }
+
+    /// Exchanges the values of `key_a` and `key_b`, a key whose partner is
+    /// absent is deleted. Other writers are held off until both writes are
+    /// logged and synced. Swapped values are stored without a TTL.
+    pub fn swap<S: Into<Vec<u8>>>(&self, key_a: S, key_b: S) -> Result<(), DatabaseError> {
+        self.ensure_writable()?;
+        let key_a = self.transform_key(key_a.into());
+        let key_b = self.transform_key(key_b.into());
+        if key_a == key_b {
+            return Ok(());
+        }
 
-        let mut log = Log::<File>::open(&get_db_file_path(path, Some(version), LOG_FILE_NAME))?;
-        
-        for operation in log.read_until_empty()?.into_iter() {
-            match operation {
-                LogOperation::Put(key, value) => checkpoint.insert(key.into(), value.into()),
-                LogOperation::Delete(key) => checkpoint.remove::<Vec<u8>>(&key.into())
+        let mut log = self.get_log()?;
+        let value_a = self.get_transformed(&key_a);
+        let value_b = self.get_transformed(&key_b);
+        for (key, current, swapped) in [(key_a, &value_a, &value_b), (key_b, &value_b, &value_a)] {
+            let operation = match swapped {
+                Some(value) => LogOperation::Put(key, value.clone()),
+                None if current.is_some() => LogOperation::Delete(key),
+                None => continue,
             };
+            self.log_and_apply(&mut log, operation)?;
         }
+        log.sync()?;
 
-        Ok(SimpleDB {
-            records: Arc::new(RwLock::new(checkpoint)),
-            path: PathBuf::from(path),
-            version,
-            log,
-            commit_in_progress: false
-        })
+        self.auto_commit_if_needed(log)
     }
 
-    fn write_records_to_file(&self, file: &mut File) -> Result<(), DatabaseError> {
-        let mut buffer = vec![];
-        let records = match self.records.read() {
-            Ok(ro_records) => ro_records,
-            Err(_) => {
-                return Err(DatabaseError::Lock {
-                    kind: LockKind::Read,
-                    reason: None,
-                })
+    /// Moves the value of `from` to `to`, overwriting `to`, and returns
+    /// false if `from` doesn't exist. The value keeps its TTL. Other writers
+    /// are held off until the put and the delete are logged and synced, so
+    /// a crash leaves the value under `to` if it is gone from `from`.
+    pub fn rename_key<S: Into<Vec<u8>>>(&self, from: S, to: S) -> Result<bool, DatabaseError> {
+        self.ensure_writable()?;
+        let from = self.transform_key(from.into());
+        let to = self.transform_key(to.into());
+
+        let mut log = self.get_log()?;
+        let value = match self.get_transformed(&from) {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        if from == to {
+            return Ok(true);
+        }
+
+        let expires_at = self
+            .read_expiries()
+            .ok()
+            .and_then(|expiries| expiries.get(&from).copied());
+        let put = match expires_at {
+            Some(expires_at) => LogOperation::PutWithExpiry(to, value, expires_at),
+            None => LogOperation::Put(to, value),
+        };
+        self.log_and_apply(&mut log, put)?;
+        self.log_and_apply(&mut log, LogOperation::Delete(from))?;
+        log.sync()?;
+
+        self.auto_commit_if_needed(log)?;
+        Ok(true)
+    }
+
+    /// Copies every live record of `other` into this database, logging a
+    /// put for each, and returns how many were copied. On keys present in
+    /// both, the value from `other` wins. `other` is read from a copy taken
+    /// up front, so its locks are never held together with ours.
+    pub fn merge_from(&self, other: &SimpleDB) -> Result<usize, DatabaseError> {
+        self.ensure_writable()?;
+        let imported = other.to_map();
+
+        let mut log = self.get_log()?;
+        for (key, value) in imported.iter() {
+            self.log_and_apply(&mut log, LogOperation::Put(key.clone(), value.clone()))?;
+        }
+        self.auto_commit_if_needed(log)?;
+
+        Ok(imported.len())
+    }
+
+    /// Forces any log appends not yet synced by the sync policy to disk,
+    /// without writing a new checkpoint.
+    pub fn flush(&self) -> Result<(), DatabaseError> {
+        self.group_commit()?;
+        Ok(())
+    }
+
+    /// Like `put`, but returns as soon as the write reaches the OS, without
+    /// syncing it whatever the sync policy. The returned ticket is passed
+    /// to `wait_durable` to wait until a `group_commit` has synced it.
+    pub fn put_deferred<S: Into<Vec<u8>>, V: Into<Vec<u8>>>(
+        &self,
+        key: S,
+        value: V,
+    ) -> Result<u64, DatabaseError> {
+        self.ensure_writable()?;
+        let key_as_bytes = self.transform_key(key.into());
+        let value_as_bytes: Vec<u8> = value.into();
+
+        let mut log = self.get_log()?;
+        let operation = LogOperation::Put(key_as_bytes, value_as_bytes);
+        self.log_and_apply_with(&mut log, operation, true)?;
+        let ticket = self.sequence();
+
+        self.auto_commit_if_needed(log)?;
+        Ok(ticket)
+    }
+
+    /// Syncs every write logged so far with a single `sync_data`, wakes
+    /// the threads waiting for them in `wait_durable`, and returns the
+    /// ticket of the last one.
+    pub fn group_commit(&self) -> Result<u64, DatabaseError> {
+        let mut log = self.get_log()?;
+        log.sync()?;
+        let ticket = self.sequence();
+        self.mark_durable(ticket);
+        Ok(ticket)
+    }
+
+    /// Blocks until the write `put_deferred` returned `ticket` for has been
+    /// synced, by `group_commit`, `flush` or `commit`.
+    pub fn wait_durable(&self, ticket: u64) {
+        let mut durable = self
+            .durable_sequence
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        while *durable < ticket {
+            durable = self
+                .durable
+                .wait(durable)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    fn mark_durable(&self, ticket: u64) {
+        let mut durable = self
+            .durable_sequence
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        *durable = (*durable).max(ticket);
+        self.durable.notify_all();
+    }
+
+    /// Writes the in-memory records to a new checkpoint and starts a fresh log.
+    /// The `new_version` file is only written once both are synced, so a
+    /// crash before that point leaves the previous version in place and a
+    /// crash after it is recovered to the new version on open. Failing to
+    /// remove the previous version's files is reported, but the new version
+    /// is already in use by then. Returns the new version.
+    pub fn commit(&self) -> Result<u64, DatabaseError> {
+        self.ensure_writable()?;
+        let mut log = self.get_log()?;
+        self.commit_locked(&mut log)
+    }
+
+    /// Spawns a thread that commits every `interval` if the log has grown
+    /// past `DbOptions::compaction_threshold_bytes`. Commits take the log
+    /// lock like any writer, so foreground writes wait for them instead of
+    /// failing. The thread stops on `stop_background_compaction`, when the
+    /// database is dropped, or when another one is started.
+    pub fn start_background_compaction(self: Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let (stop, stopped) = channel();
+        *self
+            .compaction_stop
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(stop);
+
+        let db = Arc::downgrade(&self);
+        std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                let db = match db.upgrade() {
+                    Some(db) => db,
+                    None => return,
+                };
+                let _ = db.compact_if_needed();
             }
+        })
+    }
+
+    /// Stops the background compaction thread after the commit it may be
+    /// running, join its handle to wait for that.
+    pub fn stop_background_compaction(&self) {
+        self.compaction_stop
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+    }
+
+    fn compact_if_needed(&self) -> Result<(), DatabaseError> {
+        self.ensure_writable()?;
+        let mut log = self.get_log()?;
+        if log.size_bytes()? > self.options.compaction_threshold_bytes {
+            self.commit_locked(&mut log)?;
+        }
+        Ok(())
+    }
+
+    /// Stores `items` straight into a new checkpoint instead of logging
+    /// them one by one, overwriting existing keys, and returns how many
+    /// were stored. Subscribers are not notified of bulk loaded items. The
+    /// records in memory are only replaced once the checkpoint is durable.
+    pub fn bulk_load<I: IntoIterator<Item = (Vec<u8>, Vec<u8>)>>(
+        &self,
+        items: I,
+    ) -> Result<usize, DatabaseError> {
+        self.ensure_writable()?;
+        let items: Vec<(Vec<u8>, Vec<u8>)> = items.into_iter().collect();
+        for (key, value) in items.iter() {
+            self.check_key(key)?;
+            self.check_value_size(value)?;
+        }
+
+        let mut log = self.get_log()?;
+        let CommitSource {
+            records,
+            mut expiries,
+            evicted,
+        } = self.commit_source()?;
+        let mut records = Arc::try_unwrap(records).unwrap_or_else(|records| (*records).clone());
+        records.extend(evicted);
+        let loaded = items.len();
+        for (key, value) in items.into_iter() {
+            expiries.remove(&key);
+            records.insert(key, value);
+        }
+        let loaded_source = CommitSource {
+            records: Arc::new(records),
+            expiries,
+            evicted: vec![],
         };
+        self.changed_since_commit.store(true, Ordering::SeqCst);
+        self.commit_locked_from(&mut log, Some(loaded_source))?;
+        drop(log);
+
+        self.init_residency(HashSet::new())?;
+        Ok(loaded)
+    }
 
-        for (key, value) in records.iter() {
-            bytes::write_encoded_bytes_to_buffer(key.to_vec(), &mut buffer);
-            bytes::write_encoded_bytes_to_buffer(value.to_vec(), &mut buffer);
+    fn commit_locked(&self, log: &mut Log<LogFile>) -> Result<u64, DatabaseError> {
+        self.commit_locked_from(log, None)
+    }
+
+    /// Commits `restored` in place of the current records if given, which
+    /// are only swapped in once the new version is durable. Used by
+    /// `rollback_to_version` and `bulk_load`.
+    fn commit_locked_from(
+        &self,
+        log: &mut Log<LogFile>,
+        restored: Option<CommitSource>,
+    ) -> Result<u64, DatabaseError> {
+        if !self.in_memory && !self.path.is_dir() {
+            return Err(DatabaseError::NotFound(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Database directory {:?} was removed", self.path),
+            )));
         }
+        log.sync()?;
+        let restored_records = restored.is_some();
+        let source = match restored {
+            Some(restored) => restored,
+            None => {
+                self.purge_expired()?;
+                self.rebuild_bloom_filter()?;
+                self.rebuild_checksums()?;
+                self.commit_source()?
+            }
+        };
+        self.commit_in_progress.store(true, Ordering::SeqCst);
+        let new_version = self.version() + 1;
+        let written = self.write_version_files(new_version, &source);
+        // Cleared on failure too, the handle stays on the current version.
+        self.commit_in_progress.store(false, Ordering::SeqCst);
+        let (new_log, committed_at, checkpoint_index) = written?;
+        // The new version is durable from here on, nothing below may fail
+        // before the handle moves to it.
+        if restored_records {
+            self.install_records(source);
+        }
+        self.changed_since_commit.store(false, Ordering::SeqCst);
+        self.ops_since_commit.store(0, Ordering::SeqCst);
+        *log = new_log;
+        self.publish_version(new_version, checkpoint_index);
+        *self.get_last_commit_millis() = Some(committed_at);
+
+        // The new version is durable at this point, if cleanup fails the
+        // leftover files are ignored and `new_version` is promoted on open,
+        // so the commit still succeeds.
+        self.mark_durable(self.sequence());
+        if let Ok(mut tombstones) = self.get_tombstones() {
+            tombstones.clear();
+        }
+        if !self.in_memory && self.cleanup_previous_commit_files(new_version - 1).is_err() {
+            self.counters.on_cleanup_failure();
+        }
+        self.counters.on_commit();
+        if let Some(metrics) = self.metrics() {
+            metrics.on_commit();
+        }
+
+        Ok(new_version)
+    }
+
+    /// Writes the checkpoint, log and `new_version` file of `version`,
+    /// returning the new log, the time of the commit and, if records are
+    /// capped by `DbOptions::max_records`, the offset of every record in the
+    /// new checkpoint. A reused checkpoint keeps the offsets of the current
+    /// one, so none are returned. In-memory databases only start a new log.
+    fn write_version_files(
+        &self,
+        version: u64,
+        source: &CommitSource,
+    ) -> Result<(Log<LogFile>, u64, Option<CheckpointIndex>), DatabaseError> {
+        if self.in_memory {
+            let mut new_log = Log::in_memory();
+            configure_log(&mut new_log, &self.options);
+            carry_expiries_forward(source, &mut new_log)?;
+            return Ok((new_log, self.clock.now_millis(), None));
+        }
+
+        let offsets = if self.reuse_checkpoint(version, source)? {
+            None
+        } else {
+            Some(self.write_checkpoint(version, source)?)
+        };
+        let checkpoint_index = offsets
+            .filter(|_| self.options.max_records.is_some())
+            .map(|offsets| offsets.into_iter().collect());
+        self.crash_point(CrashPoint::AfterCheckpointWrite);
+
+        let mut new_log = self.create_next_log(version, source)?;
+        new_log.sync()?;
+        // The checkpoint and log have to be on disk, directory entries
+        // included, before `new_version` can point at them.
+        let version_dir = self.checkpoint_path(version).parent().map(Path::to_path_buf);
+        if let Some(version_dir) = version_dir.filter(|dir| dir != &self.path) {
+            sync_dir(&version_dir)?;
+        }
+        sync_dir(&self.path)?;
+
+        let committed_at = self.clock.now_millis();
+        let layout = &self.options.layout;
+        create_version_file(&self.path, layout, version, Some(committed_at), true)?;
+        sync_dir(&self.path)?;
+        self.crash_point(CrashPoint::AfterNewVersionFile);
+
+        Ok((new_log, committed_at, checkpoint_index))
+    }
+
+    /// Returns the database to the records it held when `version` was
+    /// committed, discarding every write since. The version must still be
+    /// on disk, see `DbOptions::retain_versions`. The restored records are
+    /// committed as a new version, so versions keep increasing, and only
+    /// replace the records in memory once that commit succeeds.
+    pub fn rollback_to_version(&self, version: u64) -> Result<(), DatabaseError> {
+        self.ensure_writable()?;
+        if self.in_memory {
+            return Err(DatabaseError::MissingCheckpoint { version });
+        }
+        let mut log = self.get_log()?;
+        let restored = self.load_version_records(version)?;
+        self.changed_since_commit.store(true, Ordering::SeqCst);
+        self.commit_locked_from(&mut log, Some(restored))?;
+        drop(log);
+
+        self.init_residency(HashSet::new())
+    }
+
+    /// Reads the records `version` was committed with, along with the
+    /// expiries its commit carried into its log. Records that have expired
+    /// since are left out.
+    fn load_version_records(&self, version: u64) -> Result<CommitSource, DatabaseError> {
+        let layout = &self.options.layout;
+        let mut records = SimpleDB::load_checkpoint(
+            &self.path,
+            layout,
+            version,
+            self.options.cipher(),
+        )?;
+        let log_path = get_db_file_path(&self.path, layout, Some(version), &layout.log);
+        let mut log = Log::<LogFile>::open_read_only(&log_path)?;
+        log.set_cipher(self.options.cipher());
+        log.rewind()?;
+
+        // The carried expiries come first, the writes after the commit
+        // follow them.
+        let mut expiries = HashMap::new();
+        while let Some(record) = log.read_next()? {
+            match record.operation {
+                LogOperation::PutWithExpiry(key, value, expires_at)
+                    if records.get(&key) == Some(&value) =>
+                {
+                    expiries.insert(key, expires_at);
+                }
+                _ => break,
+            }
+        }
+        let now = self.clock.now_millis();
+        expiries.retain(|key, expires_at| {
+            let live = *expires_at > now;
+            if !live {
+                records.remove(key);
+            }
+            live
+        });
+
+        Ok(CommitSource {
+            records: Arc::new(records),
+            expiries,
+            evicted: vec![],
+        })
+    }
+
+    /// Swaps in the records `commit_locked_from` committed. What was evicted
+    /// from the records they replace is dropped with them. They are already
+    /// durable, so this can't fail: every lock taken here has its contents
+    /// replaced, and a poisoned one is recovered whatever
+    /// `DbOptions::recover_poisoned_locks` says.
+    fn install_records(&self, source: CommitSource) {
+        let bloom = build_bloom_filter(&source.records, self.options.bloom_false_positive_rate);
+        let checksums = build_checksums(&source.records, self.options.checksum_values);
+
+        let mut records = self.records.write().unwrap_or_else(PoisonError::into_inner);
+        let mut expiries = self.expiries.write().unwrap_or_else(PoisonError::into_inner);
+        *self.checksums.write().unwrap_or_else(PoisonError::into_inner) = checksums;
+        *self.residency.lock().unwrap_or_else(PoisonError::into_inner) = None;
+        *self.bloom.write().unwrap_or_else(PoisonError::into_inner) = bloom;
+        *records = source.records;
+        *expiries = source.expiries;
+        self.records.clear_poison();
+        self.expiries.clear_poison();
+        self.checksums.clear_poison();
+        self.residency.clear_poison();
+        self.bloom.clear_poison();
+    }
+
+    /// Rewrites the current log so it only holds the last operation logged
+    /// for each key, which shortens replay without writing a new checkpoint.
+    pub fn compact_log(&self) -> Result<(), DatabaseError> {
+        self.ensure_writable()?;
+        let mut log = self.get_log()?;
+        // Unlike `read_until_empty`, `read_from` fails on an entry that
+        // doesn't decode instead of ending the read there, so nothing after
+        // it is dropped from the compacted log.
+        let operations = compact_operations(log.read_from(0)?);
+        if self.in_memory {
+            let mut compacted_log = Log::in_memory();
+            configure_log(&mut compacted_log, &self.options);
+            for operation in operations.into_iter() {
+                compacted_log.append(operation)?;
+            }
+            *log = compacted_log;
+            return Ok(());
+        }
+
+        let version = self.version();
+        let layout = &self.options.layout;
+        let compacted_log_path =
+            get_db_file_path(&self.path, layout, Some(version), &layout.compacted_log);
+        let temp_path = temp_checkpoint_path(&compacted_log_path);
+        create_version_dir(&temp_path)?;
+        File::create(&temp_path)?;
+        let mut compacted_log = Log::<LogFile>::open(&temp_path)?;
+        compacted_log.set_compression(self.options.compression);
+        compacted_log.set_cipher(self.options.cipher());
+        // Folded appends can outgrow the entry limit, the log is then left
+        // as it is.
+        let written = operations
+            .into_iter()
+            .try_for_each(|operation| compacted_log.append(operation))
+            .and_then(|_| compacted_log.sync());
+        if let Err(error) = written {
+            drop(compacted_log);
+            let _ = std::fs::remove_file(&temp_path);
+            return Err(error.into());
+        }
+        drop(compacted_log);
+
+        // Once renamed, the compacted log stands in for the log and all of
+        // its segments, an open that finds it finishes the swap.
+        std::fs::rename(&temp_path, &compacted_log_path)?;
+        sync_dir(parent_dir(&compacted_log_path))?;
+        self.crash_point(CrashPoint::BeforeCompactedLogInstall);
+        let log_path = get_db_file_path(&self.path, layout, Some(version), &layout.log);
+        install_compacted_log(&compacted_log_path, &log_path)?;
+
+        let mut new_log = Log::<LogFile>::open(&log_path)?;
+        configure_log(&mut new_log, &self.options);
+        *log = new_log;
+
+        Ok(())
+    }
+
+    /// Reports how much of the current log `compact_log` would drop, without
+    /// rewriting it.
+    pub fn log_redundancy(&self) -> Result<LogRedundancy, DatabaseError> {
+        let mut log = self.get_log()?;
+        let operations = log.read_until_empty()?;
+        let total_entries = operations.len();
+
+        let mut compacted_log = Log::from_handle(std::io::Cursor::new(vec![]));
+        compacted_log.set_compression(self.options.compression);
+        compacted_log.set_cipher(self.options.cipher());
+        let mut live_keys = 0;
+        for operation in compact_operations(operations) {
+            if operation.value().is_some() {
+                live_keys += 1;
+            }
+            compacted_log.append(operation)?;
+        }
+
+        Ok(LogRedundancy {
+            total_entries,
+            live_keys,
+            reclaimable_bytes: log.size_bytes()? - compacted_log.size_bytes()?,
+        })
+    }
+
+    /// Byte offset of the end of the current log, for a later call to
+    /// `operations_since`. Every commit starts a new log, so an offset only
+    /// holds until the next commit.
+    pub fn current_log_offset(&self) -> Result<u64, DatabaseError> {
+        Ok(self.get_log()?.size_bytes()?)
+    }
+
+    /// Returns the operations logged after `byte_offset` of the current log,
+    /// oldest first, as taken by `current_log_offset`.
+    pub fn operations_since(&self, byte_offset: u64) -> Result<Vec<LogOperation>, DatabaseError> {
+        Ok(self.get_log()?.read_from(byte_offset)?)
+    }
+
+    /// Writes every live record to `writer` in the checkpoint encoding, so the
+    /// whole database fits in one portable stream. Values are written
+    /// unencrypted even with `DbOptions::encryption`, so `restore_from` can
+    /// read them.
+    pub fn backup_to<W: Write>(&self, writer: &mut W) -> Result<(), DatabaseError> {
+        self.write_records(&self.commit_source()?, writer, None)?;
+        Ok(())
+    }
+
+    /// Creates a new database at `new_path` holding the live records, as
+    /// its version 0 checkpoint, and returns it open. Records with a TTL
+    /// keep their expiry, which is carried into its log like on `commit`.
+    /// Writes to either database don't affect the other.
+    pub fn clone_to(&self, new_path: PathBuf) -> Result<SimpleDB, DatabaseError> {
+        let mut options = self.options.clone();
+        options.read_only = false;
+        let records = self.to_map();
+        let expiries: Expiries = self
+            .read_expiries()?
+            .iter()
+            .filter(|(key, _)| records.contains_key(*key))
+            .map(|(key, expires_at)| (key.clone(), *expires_at))
+            .collect();
+        SimpleDB::create(new_path, records, expiries, options)
+    }
+
+    /// Returns a channel receiving every operation applied from now on, in
+    /// the order they are applied.
+    pub fn subscribe(&self) -> Receiver<LogOperation> {
+        let (sender, receiver) = channel();
+        // The sender is dropped if the lock is poisoned, so the receiver
+        // reports the channel as disconnected.
+        if let Ok(mut subscribers) = self.get_subscribers() {
+            subscribers.push(sender);
+        }
+        receiver
+    }
+
+    /// Returns a channel receiving the current value of `key` right away,
+    /// then its new value after every write to it, `None` once it is
+    /// deleted. The channel is forgotten on the first write to `key` after
+    /// the receiver is dropped.
+    pub fn watch_key<S: Into<Vec<u8>>>(&self, key: S) -> Receiver<Option<Vec<u8>>> {
+        let key: Vec<u8> = key.into();
+        let (sender, receiver) = channel();
+        // Holding the log lock keeps writes out until the watcher is
+        // registered, so none is missed after the current value. Like in
+        // `subscribe`, a poisoned lock leaves the receiver disconnected.
+        let _log = match self.recover_poisoned(self.log.lock(), LockKind::Write, "log") {
+            Ok(log) => log,
+            Err(_) => return receiver,
+        };
+        let _ = sender.send(self.get_ref(&key));
+        if let Ok(mut key_watchers) = self.get_key_watchers() {
+            key_watchers.push((self.transform_key(key), sender));
+        }
+        receiver
+    }
+
+    /// Every operation in the current log, in the order it was applied. The
+    /// log only holds the writes made since the last commit.
+    pub fn log_operations(&self) -> Result<Vec<LogOperation>, DatabaseError> {
+        Ok(self.get_log()?.read_until_empty()?)
+    }
+
+    /// Size of the current log in bytes, including appended entries that
+    /// are still buffered.
+    pub fn log_len_bytes(&self) -> std::io::Result<u64> {
+        let mut log = self
+            .get_log()
+            .map_err(|error| std::io::Error::other(error.to_string()))?;
+        log.size_bytes()
+    }
+
+    /// Number of entries in the current log, i.e. the writes made since the
+    /// last commit.
+    pub fn log_entry_count(&self) -> Result<usize, DatabaseError> {
+        Ok(self.get_log()?.read_until_empty()?.len())
+    }
+
+    /// Reads the checkpoint of `version` one record at a time instead of
+    /// loading it whole, the iterator ends after the first error. The
+    /// database at `path` must use the default `FileLayout`.
+    pub fn checkpoint_entries(
+        path: &Path,
+        version: u64,
+    ) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>), DatabaseError>> {
+        let layout = FileLayout::default();
+        let checkpoint_path = get_db_file_path(path, &layout, Some(version), &layout.checkpoint);
+        let (entries, error) = match File::open(checkpoint_path)
+            .map_err(DatabaseError::from)
+            .and_then(|file| CheckpointEntries::new(BufReader::new(file), None))
+        {
+            Ok(entries) => (Some(entries), None),
+            Err(error) => (None, Some(Err(error))),
+        };
+
+        error.into_iter().chain(entries.into_iter().flatten())
+    }
+
+    /// Checks that the current checkpoint and every entry of the current log
+    /// decode cleanly.
+    /// In-memory databases have no checkpoint, it is reported valid and
+    /// empty.
+    pub fn verify(&self) -> Result<VerifyReport, DatabaseError> {
+        let checkpoint = if self.in_memory {
+            Ok(HashMap::new())
+        } else {
+            let checkpoint_path = self.checkpoint_path(self.version());
+            let mut checkpoint_file = File::open(checkpoint_path)?;
+            SimpleDB::read_records_from_file(&mut checkpoint_file, self.options.cipher())
+        };
+
+        let log_scan = self.get_log()?.scan()?;
+
+        Ok(VerifyReport {
+            checkpoint_valid: checkpoint.is_ok(),
+            checkpoint_records: checkpoint.map(|records| records.len()).unwrap_or(0),
+            valid_log_entries: log_scan.valid_entries,
+            invalid_log_entries: log_scan.invalid_entries,
+            partial_tail: log_scan.partial_tail,
+        })
+    }
+
+    /// Versions whose checkpoint is on disk, oldest first. Besides the
+    /// current one, these are the versions kept by
+    /// `DbOptions::retain_versions`.
+    pub fn list_versions(&self) -> std::io::Result<Vec<u64>> {
+        if self.in_memory {
+            return Ok(vec![]);
+        }
+        checkpoint_versions(&self.path, &self.options.layout)
+    }
+
+    /// Removes the checkpoint and log files of every version older than the
+    /// current one, including those kept by `DbOptions::retain_versions`,
+    /// and returns how many files were removed. The current version is
+    /// left alone. A read-only database fails with `PermissionDenied`.
+    pub fn purge_old_versions(&self) -> std::io::Result<usize> {
+        self.ensure_writable()
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::PermissionDenied, error))?;
+        // Holding the log keeps a commit from moving the current version.
+        let _log = self.get_log().map_err(std::io::Error::other)?;
+        if self.in_memory {
+            return Ok(0);
+        }
+        let version = self.version();
+        let layout = &self.options.layout;
+        let mut removed = 0;
+        for entry in std::fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = match name.to_str() {
+                Some(name) => name,
+                None => continue,
+            };
+            if layout.version_dirs {
+                match name.strip_prefix('v').and_then(|n| n.parse::<u64>().ok()) {
+                    Some(n) if n < version => {}
+                    _ => continue,
+                }
+                for file in std::fs::read_dir(entry.path())? {
+                    std::fs::remove_file(file?.path())?;
+                    removed += 1;
+                }
+                std::fs::remove_dir(entry.path())?;
+            } else if versioned_file_version(name, layout).is_some_and(|n| n < version) {
+                std::fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Total size of the files in the database directory, including the
+    /// files of retained versions.
+    pub fn disk_usage(&self) -> std::io::Result<u64> {
+        if self.in_memory {
+            return Ok(0);
+        }
+        let mut total = 0;
+        for entry in std::fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_file() {
+                total += metadata.len();
+            } else if metadata.is_dir() && self.options.layout.version_dirs {
+                for entry in std::fs::read_dir(entry.path())? {
+                    let metadata = entry?.metadata()?;
+                    if metadata.is_file() {
+                        total += metadata.len();
+                    }
+                }
+            }
+        }
+        Ok(total)
+    }
+
+    /// Number of mutations applied through this handle. It increases on every
+    /// logged write and is not persisted, so it restarts from 0 on open.
+    pub fn sequence(&self) -> u64 {
+        self.sequence.load(Ordering::SeqCst)
+    }
+
+    pub fn stats(&self) -> DatabaseStats {
+        let num_records = self
+            .read_records()
+            .map(|records| records.len())
+            .unwrap_or(0)
+            + self.evicted_keys().map(|keys| keys.len()).unwrap_or(0);
+        let log_size_bytes = self
+            .get_log()
+            .ok()
+            .and_then(|mut log| log.size_bytes().ok())
+            .unwrap_or(0);
+
+        DatabaseStats {
+            version: self.version(),
+            num_records,
+            log_size_bytes,
+            commit_in_progress: self.is_committing(),
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::SeqCst)
+    }
+
+    /// When the current version was committed, `None` if its version file
+    /// predates commit times being recorded.
+    pub fn last_commit_time(&self) -> Option<SystemTime> {
+        self.get_last_commit_millis()
+            .map(|millis| UNIX_EPOCH + Duration::from_millis(millis))
+    }
+
+    fn get_last_commit_millis(&self) -> MutexGuard<'_, Option<u64>> {
+        self.last_commit_millis
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Whether a `commit` is running right now. Reads go on meanwhile, writes
+    /// wait for the commit to finish.
+    pub fn is_committing(&self) -> bool {
+        self.commit_in_progress.load(Ordering::SeqCst)
+    }
+
+    pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+        self.options.sync_policy = policy;
+        self.log
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .set_sync_policy(policy);
+    }
+
+    /// Commits automatically once a `put` or `delete` grows the log past
+    /// `threshold` bytes, `None` disables auto-commit.
+    pub fn set_auto_commit_bytes(&mut self, threshold: Option<u64>) {
+        self.options.auto_commit_bytes = threshold;
+    }
+
+    /// Rejects values longer than `limit` bytes before they are logged,
+    /// `None` allows values of any size.
+    pub fn set_max_value_bytes(&mut self, limit: Option<usize>) {
+        self.options.max_value_bytes = limit;
+    }
+
+    fn create(
+        path: PathBuf,
+        records: SimpleCollection,
+        expiries: Expiries,
+        options: DbOptions,
+    ) -> Result<Self, DatabaseError> {
+        let version = 0;
+        create_dir(&path)?;
+        let layout = &options.layout;
+        let lock_file = lock_db_dir(&path, layout)?;
+
+        create_db_file(&path, layout, version, &layout.log)?;
+
+        let log_path = get_db_file_path(&path, layout, Some(version), &layout.log);
+        let log = Log::<LogFile>::open(&log_path)?;
+
+        let db = SimpleDB::from_parts(
+            path,
+            version,
+            records,
+            expiries,
+            log,
+            options,
+            Some(lock_file),
+        );
+        let source = db.commit_source()?;
+        db.write_checkpoint(version, &source)?;
+        carry_expiries_forward(&source, &mut *db.get_log()?)?;
+        let created_at = db.clock.now_millis();
+        create_version_file(&db.path, &db.options.layout, version, Some(created_at), false)?;
+        *db.get_last_commit_millis() = Some(created_at);
+        db.init_residency(HashSet::new())?;
+
+        Ok(db)
+    }
+
+    fn try_load_from_existing(path: &Path, options: DbOptions) -> Result<SimpleDB, DatabaseError> {
+        let read_only = options.read_only;
+        let lock_file = if read_only {
+            None
+        } else {
+            Some(lock_db_dir(path, &options.layout)?)
+        };
+        let (version_file, checkpoint) =
+            SimpleDB::load_latest_checkpoint(path, &options.layout, options.cipher(), read_only)?;
+        SimpleDB::replay_version(path, options, lock_file, version_file, checkpoint)
+    }
+
+    /// Opens the version of `version_file` by replaying its log on top of
+    /// its `checkpoint`.
+    fn replay_version(
+        path: &Path,
+        options: DbOptions,
+        lock_file: Option<File>,
+        version_file: VersionFile,
+        mut checkpoint: SimpleCollection,
+    ) -> Result<SimpleDB, DatabaseError> {
+        let read_only = options.read_only;
+        let version = version_file.version;
+        let mut expiries = HashMap::new();
+
+        let layout = &options.layout;
+        let log_path = get_db_file_path(path, layout, Some(version), &layout.log);
+        let compacted_log_path =
+            get_db_file_path(path, layout, Some(version), &layout.compacted_log);
+        let compaction_pending = compacted_log_path.exists();
+        let mut log = if read_only && compaction_pending {
+            Log::<LogFile>::open_read_only(&compacted_log_path)?
+        } else if read_only {
+            Log::<LogFile>::open_read_only(&log_path)?
+        } else {
+            if compaction_pending {
+                install_compacted_log(&compacted_log_path, &log_path)?;
+            }
+            Log::<LogFile>::open(&log_path)?
+        };
+        log.set_cipher(options.cipher());
+
+        let now = SystemClock.now_millis();
+        log.rewind()?;
+        let mut entry_index = 0;
+        let mut written_keys = HashSet::new();
+        let mut tombstones = HashSet::new();
+        let mut on_replay = options
+            .on_replay
+            .as_ref()
+            .map(|hook| hook.0.lock().unwrap_or_else(PoisonError::into_inner));
+        while let Some(record) = log
+            .read_next()
+            .map_err(|source| DatabaseError::LogReplay { entry_index, source })?
+        {
+            if let Some(limit) = options.max_replay_ops.filter(|limit| entry_index >= *limit) {
+                return Err(DatabaseError::LogReplay {
+                    entry_index,
+                    source: LogError::ReplayLimitExceeded { limit },
+                });
+            }
+            if options.max_records.is_some() {
+                written_keys.insert(record.operation.key().to_vec());
+            }
+            if let Some(on_replay) = on_replay.as_mut() {
+                (**on_replay)(&record.operation);
+            }
+            record_tombstone(&mut tombstones, &record.operation);
+            apply_operation(&mut checkpoint, &mut expiries, record.operation, now);
+            entry_index += 1;
+        }
+        drop(on_replay);
+        if !read_only {
+            log.truncate_torn_tail()?;
+        }
+
+        let db = SimpleDB::from_parts(
+            PathBuf::from(path),
+            version,
+            checkpoint,
+            expiries,
+            log,
+            options,
+            lock_file,
+        );
+        *db.get_last_commit_millis() = version_file.committed_at;
+        *db.get_tombstones()? = tombstones;
+        db.changed_since_commit.store(entry_index > 0, Ordering::SeqCst);
+        db.ops_since_commit.store(entry_index, Ordering::SeqCst);
+        db.init_residency(written_keys)?;
+        Ok(db)
+    }
+
+    /// Picks the version to open along with its checkpoint. A `new_version`
+    /// file left by an interrupted commit is only trusted when its checkpoint
+    /// loads, otherwise it is discarded in favour of `version`. If `version`
+    /// can't be read either, the newest version whose checkpoint loads and
+    /// whose log exists is opened and `version` is written again.
+    fn load_latest_checkpoint(
+        path: &Path,
+        layout: &FileLayout,
+        cipher: Option<Arc<dyn Cipher>>,
+        read_only: bool,
+    ) -> Result<(VersionFile, SimpleCollection), DatabaseError> {
+        let new_version_file_path = get_db_file_path(path, layout, None, &layout.new_version);
+        let version_file_path = get_db_file_path(path, layout, None, &layout.version);
+        if new_version_file_path.exists() {
+            let new_version = read_version_file(&new_version_file_path)?;
+            let checkpoint =
+                SimpleDB::load_checkpoint(path, layout, new_version.version, cipher.clone());
+            if let Ok(checkpoint) = checkpoint {
+                if !read_only {
+                    std::fs::rename(&new_version_file_path, &version_file_path)?;
+                }
+                return Ok((new_version, checkpoint));
+            }
+            if !read_only {
+                std::fs::remove_file(&new_version_file_path)?;
+            }
+        }
+
+        let version = match read_version_file(&version_file_path) {
+            Ok(version) => version,
+            Err(error) => {
+                for version in scan_versions(path, layout)? {
+                    let checkpoint =
+                        SimpleDB::load_checkpoint(path, layout, version, cipher.clone());
+                    if let Ok(checkpoint) = checkpoint {
+                        if !read_only {
+                            create_version_file(path, layout, version, None, false)?;
+                        }
+                        let version = VersionFile {
+                            version,
+                            committed_at: None,
+                        };
+                        return Ok((version, checkpoint));
+                    }
+                }
+                return Err(error.into());
+            }
+        };
+        let checkpoint = SimpleDB::load_checkpoint(path, layout, version.version, cipher)?;
+        Ok((version, checkpoint))
+    }
+
+    fn load_checkpoint(
+        path: &Path,
+        layout: &FileLayout,
+        version: u64,
+        cipher: Option<Arc<dyn Cipher>>,
+    ) -> Result<SimpleCollection, DatabaseError> {
+        let checkpoint_path = get_db_file_path(path, layout, Some(version), &layout.checkpoint);
+        let mut checkpoint_file = match File::open(checkpoint_path) {
+            Ok(file) => file,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                return Err(DatabaseError::MissingCheckpoint { version })
+            }
+            Err(error) => return Err(error.into()),
+        };
+        match SimpleDB::read_records_from_file(&mut checkpoint_file, cipher) {
+            Ok(records) => Ok(records),
+            Err(error @ DatabaseError::UnsupportedFormat { .. }) => Err(error),
+            Err(_) => Err(DatabaseError::LoadCheckpoint)
+        }
+    }
+
+    fn from_parts(
+        path: PathBuf,
+        version: u64,
+        records: SimpleCollection,
+        expiries: Expiries,
+        mut log: Log<LogFile>,
+        options: DbOptions,
+        lock_file: Option<File>,
+    ) -> SimpleDB {
+        configure_log(&mut log, &options);
+        let bloom = build_bloom_filter(&records, options.bloom_false_positive_rate);
+        let checksums = build_checksums(&records, options.checksum_values);
+        SimpleDB {
+            records: RwLock::new(Arc::new(records)),
+            expiries: RwLock::new(expiries),
+            clock: Arc::new(SystemClock),
+            log: Mutex::new(log),
+            path,
+            in_memory: false,
+            version: AtomicU64::new(version),
+            last_commit_millis: Mutex::new(None),
+            commit_in_progress: AtomicBool::new(false),
+            changed_since_commit: AtomicBool::new(false),
+            ops_since_commit: AtomicUsize::new(0),
+            counters: Counters::default(),
+            sequence: AtomicU64::new(0),
+            tombstones: Mutex::new(HashSet::new()),
+            durable_sequence: Mutex::new(0),
+            durable: Condvar::new(),
+            subscribers: Mutex::new(vec![]),
+            key_watchers: Mutex::new(vec![]),
+            compaction_stop: Mutex::new(None),
+            bloom: RwLock::new(bloom),
+            checksums: RwLock::new(checksums),
+            residency: Mutex::new(None),
+            options,
+            lock_file,
+            #[cfg(test)]
+            crash_point: None,
+            #[cfg(test)]
+            pause_point: None,
+        }
+    }
+
+    /// Writes the checkpoint of `version` to a temporary file first and
+    /// renames it into place once synced, so `checkpoint.N` is either
+    /// complete or missing. Its index is written the same way afterwards.
+    /// Returns the offset of every record written, see `write_records`.
+    fn write_checkpoint(
+        &self,
+        version: u64,
+        source: &CommitSource,
+    ) -> Result<Vec<(Vec<u8>, u64)>, DatabaseError> {
+        let checkpoint_path = self.checkpoint_path(version);
+        let temp_path = temp_checkpoint_path(&checkpoint_path);
+        create_version_dir(&checkpoint_path)?;
+        let mut checkpoint = File::create(&temp_path)?;
+        let mut offsets = self.write_records_to_file(source, &mut checkpoint)?;
+        self.crash_point(CrashPoint::BeforeCheckpointRename);
+
+        std::fs::rename(&temp_path, &checkpoint_path)?;
+        self.write_checkpoint_index(version, &mut offsets)?;
+        Ok(offsets)
+    }
+
+    fn write_checkpoint_index(
+        &self,
+        version: u64,
+        offsets: &mut [(Vec<u8>, u64)],
+    ) -> Result<(), DatabaseError> {
+        let index_path = self.index_path(version);
+        let temp_path = temp_checkpoint_path(&index_path);
+        let mut index_file = BufWriter::new(File::create(&temp_path)?);
+        index::write_index(&mut index_file, offsets)?;
+        index_file.into_inner().map_err(|error| error.into_error())?.sync_data()?;
+
+        std::fs::rename(&temp_path, &index_path)?;
+        Ok(())
+    }
+
+    /// Links the checkpoint and index of the previous version as those of
+    /// `version` when no record changed since, returning false if they have
+    /// to be written. Records with a TTL may expire from one commit to the
+    /// next, so their checkpoints are always written.
+    fn reuse_checkpoint(&self, version: u64, source: &CommitSource) -> Result<bool, DatabaseError> {
+        let unchanged =
+            !self.changed_since_commit.load(Ordering::SeqCst) && source.expiries.is_empty();
+        let previous = match version.checked_sub(1) {
+            Some(previous) if unchanged => previous,
+            _ => return Ok(false),
+        };
+        if !self.index_path(previous).is_file() {
+            return Ok(false);
+        }
+
+        let checkpoint_path = self.checkpoint_path(version);
+        create_version_dir(&checkpoint_path)?;
+        link_or_copy(&self.checkpoint_path(previous), &checkpoint_path)?;
+        link_or_copy(&self.index_path(previous), &self.index_path(version))?;
+        Ok(true)
+    }
+
+    fn index_path(&self, version: u64) -> PathBuf {
+        let layout = &self.options.layout;
+        get_db_file_path(&self.path, layout, Some(version), &layout.index)
+    }
+
+    /// Reads the value of `key` from the current checkpoint through its
+    /// index, seeking straight to the record instead of reading the
+    /// checkpoint from the start. Writes since the last commit are not
+    /// seen, in-memory databases have no checkpoint to read.
+    pub fn lookup_in_checkpoint<S: Into<Vec<u8>>>(
+        &self,
+        key: S,
+    ) -> Result<Option<Vec<u8>>, DatabaseError> {
+        let version = self.version();
+        if self.in_memory {
+            return Err(DatabaseError::MissingCheckpoint { version });
+        }
+        let key = self.transform_key(key.into());
+        let mut index_file = BufReader::new(File::open(self.index_path(version))?);
+        match index::lookup(&mut index_file, &key)? {
+            Some(offset) => {
+                let mut checkpoint = File::open(self.checkpoint_path(version))?;
+                Ok(Some(self.read_checkpoint_value(&mut checkpoint, &key, offset)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn checkpoint_path(&self, version: u64) -> PathBuf {
+        let layout = &self.options.layout;
+        get_db_file_path(&self.path, layout, Some(version), &layout.checkpoint)
+    }
+
+    /// Creates the log for `version`, holding only the expiries that have to
+    /// outlive the checkpoint.
+    fn create_next_log(
+        &self,
+        version: u64,
+        source: &CommitSource,
+    ) -> Result<Log<LogFile>, DatabaseError> {
+        let layout = &self.options.layout;
+        create_db_file(&self.path, layout, version, &layout.log)?;
+        let log_path = get_db_file_path(&self.path, layout, Some(version), &layout.log);
+        let mut new_log = Log::<LogFile>::open(&log_path)?;
+        configure_log(&mut new_log, &self.options);
+        carry_expiries_forward(source, &mut new_log)?;
+
+        Ok(new_log)
+    }
+
+    fn write_records_to_file(
+        &self,
+        source: &CommitSource,
+        file: &mut File,
+    ) -> Result<Vec<(Vec<u8>, u64)>, DatabaseError> {
+        let offsets = self.write_records(source, file, self.options.cipher().as_deref())?;
+        file.sync_data()?;
+
+        Ok(offsets)
+    }
+
+    /// Takes a snapshot of the live records to write out, so reads aren't
+    /// held off while a large checkpoint is written.
+    fn commit_source(&self) -> Result<CommitSource, DatabaseError> {
+        let records = Arc::clone(&*self.read_records()?);
+        let expiries = self.read_expiries()?.clone();
+
+        Ok(CommitSource {
+            records,
+            expiries,
+            evicted: self.evicted_entries()?,
+        })
+    }
+
+    /// Encodes the unexpired records of `source` into `writer` and returns
+    /// the offset each key's record was written at. Values are encrypted
+    /// with `cipher` if given.
+    fn write_records<W: Write>(
+        &self,
+        source: &CommitSource,
+        writer: &mut W,
+        cipher: Option<&dyn Cipher>,
+    ) -> Result<Vec<(Vec<u8>, u64)>, DatabaseError> {
+        let mut writer = BufWriter::new(writer);
+        let mut buffer = vec![];
+        buffer.extend(CHECKPOINT_MAGIC);
+        bytes::write_u64_to_buffer(CHECKPOINT_FORMAT_VERSION, &mut buffer);
+        writer.write_all(&buffer)?;
+        let mut offset = buffer.len() as u64;
+        let mut offsets = Vec::with_capacity(source.records.len());
+
+        let now = self.clock.now_millis();
+        let resident = source
+            .records
+            .iter()
+            .filter(|(key, _)| !is_expired(&source.expiries, key, now));
+        let evicted = source.evicted.iter().map(|(key, value)| (key, value));
+        let mut entries: Vec<(&Vec<u8>, &Vec<u8>)> = resident.chain(evicted).collect();
+        if self.options.sorted_checkpoints {
+            entries.sort_unstable_by_key(|(key, _)| *key);
+        }
+        for (key, value) in entries {
+            buffer.clear();
+            bytes::write_encoded_bytes_to_buffer(key, &mut buffer);
+            let compression = self.options.compression;
+            bytes::write_encoded_value_to_buffer(value, compression, cipher, &mut buffer);
+            writer.write_all(&buffer)?;
+            offsets.push((key.clone(), offset));
+            offset += buffer.len() as u64;
+        }
+        writer.flush()?;
+
+        Ok(offsets)
+    }
+
+    fn read_records_from_file<R: Read + Seek>(
+        file: &mut R,
+        cipher: Option<Arc<dyn Cipher>>,
+    ) -> Result<SimpleCollection, DatabaseError> {
+        CheckpointEntries::new(file, cipher)?.collect()
+    }
+
+    fn ensure_writable(&self) -> Result<(), DatabaseError> {
+        if self.options.read_only {
+            return Err(DatabaseError::ReadOnly);
+        }
+        Ok(())
+    }
+
+    /// Applies `DbOptions::key_transform` to `key`, if set.
+    fn transform_key(&self, key: Vec<u8>) -> Vec<u8> {
+        match &self.options.key_transform {
+            Some(transform) => (transform.0)(&key),
+            None => key,
+        }
+    }
+
+    fn check_key(&self, key: &[u8]) -> Result<(), DatabaseError> {
+        if self.options.reject_empty_keys && key.is_empty() {
+            return Err(DatabaseError::InvalidKey {
+                reason: String::from("key is empty"),
+            });
+        }
+        Ok(())
+    }
+
+    fn check_value_size(&self, value: &[u8]) -> Result<(), DatabaseError> {
+        match self.options.max_value_bytes {
+            Some(limit) if value.len() > limit => {
+                Err(DatabaseError::ValueTooLarge {
+                    limit,
+                    actual: value.len(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn get_log(&self) -> Result<MutexGuard<'_, Log<LogFile>>, DatabaseError> {
+        let log = match self.options.lock_timeout {
+            Some(timeout) => lock_within(timeout, LockKind::Write, || self.log.try_lock())?,
+            None => self.log.lock(),
+        };
+        self.recover_poisoned(log, LockKind::Write, "log")
+    }
+
+    pub(crate) fn log_and_apply(
+        &self,
+        log: &mut Log<LogFile>,
+        operation: LogOperation,
+    ) -> Result<(), DatabaseError> {
+        self.log_and_apply_with(log, operation, false)
+    }
+
+    /// Commits first if appending `operation` would grow the log past
+    /// `limit` bytes, so it is appended to the fresh log instead.
+    fn make_room_in_log(
+        &self,
+        log: &mut Log<LogFile>,
+        operation: LogOpRef<'_>,
+        limit: u64,
+    ) -> Result<(), DatabaseError> {
+        let entry_bytes = log.encoded_len(operation);
+        if log.size_bytes()? + entry_bytes <= limit {
+            return Ok(());
+        }
+        let log_full = DatabaseError::LogFull { limit, entry_bytes };
+        if entry_bytes > limit {
+            return Err(log_full);
+        }
+        self.commit_locked(log)?;
+        // Expiries carried forward may leave too little room in the new log.
+        if log.size_bytes()? + entry_bytes > limit {
+            return Err(log_full);
+        }
+        Ok(())
+    }
+
+    /// Like `log_and_apply`, `defer_sync` leaves the append unsynced for a
+    /// later `group_commit` instead of following the sync policy.
+    fn log_and_apply_with(
+        &self,
+        log: &mut Log<LogFile>,
+        operation: LogOperation,
+        defer_sync: bool,
+    ) -> Result<(), DatabaseError> {
+        if operation != LogOperation::Clear {
+            self.check_key(operation.key())?;
+        }
+        if let Some(value) = operation.value() {
+            self.check_value_size(value)?;
+        }
+        if let Some(limit) = self.options.max_log_bytes {
+            self.make_room_in_log(log, operation.borrowed(), limit)?;
+        }
+        // Taken before appending, a write that is logged must not fail to
+        // apply because a lock timed out or was poisoned.
+        let mut records = self.get_write_records()?;
+        let mut expiries = self.get_write_expiries()?;
+        let mut tombstones = self.get_tombstones()?;
+        let mut checksums = self.get_checksums()?;
+        let mut residency = self.get_residency()?;
+        let mut bloom = self.recover_poisoned(self.bloom.write(), LockKind::Write, "bloom")?;
+        let mut subscribers = self.get_subscribers()?;
+        let mut key_watchers = self.get_key_watchers()?;
+        let append = |log: &mut Log<LogFile>| {
+            if defer_sync {
+                log.append_deferred(operation.borrowed())
+            } else {
+                log.append_borrowed_to_disk(operation.borrowed())
+            }
+        };
+        match self.metrics() {
+            Some(metrics) => {
+                let size_before = log.size_bytes()?;
+                append(log)?;
+                metrics.on_log_append(log.size_bytes()? - size_before);
+                if let LogOperation::Put(..) | LogOperation::PutWithExpiry(..) = operation {
+                    metrics.on_put();
+                }
+            }
+            None => append(log)?,
+        }
+        match operation {
+            LogOperation::Put(..) | LogOperation::PutWithExpiry(..) => self.counters.on_put(),
+            LogOperation::Delete(..) => self.counters.on_delete(),
+            _ => {}
+        }
+        self.changed_since_commit.store(true, Ordering::SeqCst);
+        self.ops_since_commit.fetch_add(1, Ordering::SeqCst);
+
+        if let LogOperation::Append(key, _) = &operation {
+            // The value appended to has to be in memory first.
+            if let Some(value) = self.read_evicted(&residency, key) {
+                Arc::make_mut(&mut records).insert(key.clone(), value);
+            }
+        }
+        record_tombstone(&mut tombstones, &operation);
+        let cleared = operation == LogOperation::Clear;
+        let deleted = operation.value().is_none();
+        let key = operation.key().to_vec();
+        // The value moves into the records, only subscribers need a copy.
+        let notification = if subscribers.is_empty() {
+            None
+        } else {
+            Some(operation.clone())
+        };
+        apply_operation(
+            Arc::make_mut(&mut records),
+            &mut expiries,
+            operation,
+            self.clock.now_millis(),
+        );
+        if let Some(checksums) = checksums.as_mut() {
+            if cleared {
+                checksums.clear();
+            } else if let Some(value) = records.get(&key) {
+                checksums.insert(key.clone(), value_checksum(value));
+            } else {
+                checksums.remove(&key);
+            }
+        }
+        if let Some(residency) = residency.as_mut() {
+            if cleared {
+                residency.cleared();
+            } else {
+                residency.written(&key, deleted);
+            }
+            residency.evict(Arc::make_mut(&mut records), &expiries);
+        }
+        if !deleted {
+            if let Some(bloom) = bloom.as_mut() {
+                bloom.insert(&key);
+            }
+        }
+        notify_key_watchers(&mut key_watchers, &key, cleared, records.get(&key));
+        self.sequence.fetch_add(1, Ordering::SeqCst);
+        if let Some(operation) = notification {
+            notify_subscribers(&mut subscribers, operation);
+        }
+        Ok(())
+    }
+
+    fn keys_with_prefix(&self, prefix: &[u8]) -> Vec<Vec<u8>> {
+        let now = self.clock.now_millis();
+        let mut keys: Vec<Vec<u8>> = {
+            let (records, expiries) = match (self.read_records(), self.read_expiries()) {
+                (Ok(records), Ok(expiries)) => (records, expiries),
+                _ => return vec![],
+            };
+            records
+                .keys()
+                .filter(|key| key.starts_with(prefix) && !is_expired(&expiries, key, now))
+                .cloned()
+                .collect()
+        };
+        keys.extend(
+            self.evicted_keys()
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|key| key.starts_with(prefix)),
+        );
+        keys
+    }
+
+    fn metrics(&self) -> Option<&dyn Metrics> {
+        self.options.metrics.as_ref().map(|hook| &*hook.0)
+    }
+
+    fn get_residency(&self) -> Result<MutexGuard<'_, Option<Residency>>, DatabaseError> {
+        self.recover_poisoned(self.residency.lock(), LockKind::Write, "residency")
+    }
+
+    /// Starts capping the records in memory if `DbOptions::max_records` is
+    /// set, `written_keys` are the keys that differ from the checkpoint.
+    fn init_residency(&self, written_keys: HashSet<Vec<u8>>) -> Result<(), DatabaseError> {
+        let max_records = match self.options.max_records {
+            Some(max_records) => max_records,
+            None => return Ok(()),
+        };
+
+        let checkpoint_index = self.build_checkpoint_index(self.version())?;
+        let mut residency = Residency::new(max_records, checkpoint_index, written_keys);
+        let mut records = self.recover_poisoned(self.records.write(), LockKind::Write, "records")?;
+        let expiries = self.read_expiries()?;
+        residency.track(records.keys());
+        residency.evict(Arc::make_mut(&mut records), &expiries);
+        *self.get_residency()? = Some(residency);
+
+        Ok(())
+    }
+
+    /// Makes `version` the current version. Evicted records are pointed at
+    /// its checkpoint through `checkpoint_index` under the same residency
+    /// lock, so they are never read from one checkpoint with the offsets of
+    /// another, and what can now be read back from it is evicted. Nothing
+    /// is evicted if one of the locks is poisoned, reads through it fail.
+    fn publish_version(&self, version: u64, checkpoint_index: Option<CheckpointIndex>) {
+        let checkpoint_index = match checkpoint_index {
+            Some(checkpoint_index) => checkpoint_index,
+            None => {
+                self.version.store(version, Ordering::SeqCst);
+                return;
+            }
+        };
+
+        let records = self.recover_poisoned(self.records.write(), LockKind::Write, "records");
+        let expiries = self.read_expiries();
+        let residency = self.get_residency();
+        self.version.store(version, Ordering::SeqCst);
+        if let (Ok(mut records), Ok(expiries), Ok(mut residency)) = (records, expiries, residency) {
+            if let Some(residency) = residency.as_mut() {
+                residency.checkpointed(checkpoint_index);
+                residency.evict(Arc::make_mut(&mut records), &expiries);
+            }
+        }
+    }
+
+    /// Maps every key of the checkpoint of `version` to the offset of its
+    /// record.
+    fn build_checkpoint_index(
+        &self,
+        version: u64,
+    ) -> Result<HashMap<Vec<u8>, u64>, DatabaseError> {
+        let checkpoint_path = self.checkpoint_path(version);
+        let checkpoint_file = BufReader::new(File::open(checkpoint_path)?);
+        let mut entries = CheckpointEntries::new(checkpoint_file, self.options.cipher())?;
+        let mut checkpoint_index = HashMap::new();
+        loop {
+            let offset = entries.position()?;
+            match entries.next() {
+                Some(entry) => checkpoint_index.insert(entry?.0, offset),
+                None => break,
+            };
+        }
+
+        Ok(checkpoint_index)
+    }
+
+    fn touch(&self, key: &[u8]) {
+        if let Ok(Some(residency)) = self.get_residency().as_deref_mut() {
+            residency.touch(key);
+        }
+    }
+
+    /// Reads `key` back from the checkpoint if it was evicted from memory.
+    /// The residency lock is held throughout, so the checkpoint read is the
+    /// one the offset belongs to.
+    fn get_evicted(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let residency = self.get_residency().ok()?;
+        self.read_evicted(&residency, key)
+    }
+
+    /// Like `get_evicted`, under a residency lock the caller holds.
+    fn read_evicted(&self, residency: &Option<Residency>, key: &[u8]) -> Option<Vec<u8>> {
+        let offset = residency.as_ref()?.evicted_offset(key)?;
+        let mut checkpoint = File::open(self.checkpoint_path(self.version())).ok()?;
+        self.read_checkpoint_value(&mut checkpoint, key, offset).ok()
+    }
+
+    fn evicted_keys(&self) -> Result<Vec<Vec<u8>>, DatabaseError> {
+        Ok(match self.get_residency()?.as_ref() {
+            Some(residency) => residency.evicted_keys().cloned().collect(),
+            None => vec![],
+        })
+    }
+
+    /// Every evicted record, read back from the checkpoint in one pass.
+    fn evicted_entries(&self) -> Result<EvictedEntries, DatabaseError> {
+        let residency = self.get_residency()?;
+        let residency = match residency.as_ref() {
+            Some(residency) if residency.evicted_len() > 0 => residency,
+            _ => return Ok(vec![]),
+        };
+        let mut offsets: Vec<(&Vec<u8>, u64)> = residency
+            .evicted_keys()
+            .filter_map(|key| Some((key, residency.evicted_offset(key)?)))
+            .collect();
+        offsets.sort_unstable_by_key(|(_, offset)| *offset);
+
+        let checkpoint_path = self.checkpoint_path(self.version());
+        let mut checkpoint = match File::open(checkpoint_path) {
+            Ok(checkpoint) => BufReader::new(checkpoint),
+            Err(_) => return Ok(vec![]),
+        };
+        Ok(offsets
+            .into_iter()
+            .filter_map(|(key, offset)| {
+                let value = self.read_checkpoint_value(&mut checkpoint, key, offset).ok()?;
+                Some((key.clone(), value))
+            })
+            .collect())
+    }
+
+    /// Reads the value of the record at `offset` in `checkpoint`, failing if
+    /// the record there isn't the one of `key`.
+    fn read_checkpoint_value<R: Read + Seek>(
+        &self,
+        checkpoint: &mut R,
+        key: &[u8],
+        offset: u64,
+    ) -> Result<Vec<u8>, DatabaseError> {
+        checkpoint.seek(SeekFrom::Start(offset))?;
+        let key_length = bytes::read_u64_from_log(checkpoint)?;
+        let found = key_length == key.len() as u64
+            && bytes::read_bytes_from_log(checkpoint, key_length)? == key;
+        if !found {
+            return Err(DatabaseError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Checkpoint record at offset {} doesn't hold the key", offset),
+            )));
+        }
+
+        let cipher = self.options.cipher();
+        Ok(bytes::read_value_from_log(checkpoint, cipher.as_deref())?)
+    }
+
+    /// Returns false only if `key` is definitely absent, without taking the
+    /// records lock.
+    /// A poisoned filter can't rule keys out, the lookup is then left to
+    /// the records lock.
+    fn may_contain(&self, key: &[u8]) -> bool {
+        match self.recover_poisoned(self.bloom.read(), LockKind::Read, "bloom") {
+            Ok(bloom) => match bloom.as_ref() {
+                Some(bloom) => bloom.may_contain(key),
+                None => true,
+            },
+            Err(_) => true,
+        }
+    }
+
+    /// Rebuilds the bloom filter from the current keys, dropping deleted
+    /// keys from it and resizing it for the number of keys.
+    fn rebuild_bloom_filter(&self) -> Result<(), DatabaseError> {
+        let records = self.read_records()?;
+        let mut bloom = build_bloom_filter(&records, self.options.bloom_false_positive_rate);
+        if let Some(bloom) = bloom.as_mut() {
+            for key in self.evicted_keys()? {
+                bloom.insert(&key);
+            }
+        }
+        *self.recover_poisoned(self.bloom.write(), LockKind::Write, "bloom")? = bloom;
+        Ok(())
+    }
+
+    /// Recomputes the checksums from the resident records, keeping the ones
+    /// of evicted records.
+    fn rebuild_checksums(&self) -> Result<(), DatabaseError> {
+        if !self.options.checksum_values {
+            return Ok(());
+        }
+        let records = self.read_records()?;
+        let evicted_keys = self.evicted_keys()?;
+        let mut checksums = self.get_checksums()?;
+        let previous = checksums.take().unwrap_or_default();
+        let mut rebuilt = build_checksums(&records, true).unwrap_or_default();
+        for key in evicted_keys {
+            if let Some(checksum) = previous.get(&key) {
+                rebuilt.insert(key, *checksum);
+            }
+        }
+        *checksums = Some(rebuilt);
+        Ok(())
+    }
+
+    fn get_checksums(&self) -> Result<RwLockWriteGuard<'_, Option<Checksums>>, DatabaseError> {
+        self.recover_poisoned(self.checksums.write(), LockKind::Write, "checksums")
+    }
+
+    /// Fails with `DatabaseError::ChecksumMismatch` if `value` doesn't match
+    /// the checksum kept for `key`.
+    fn verify_checksum(&self, key: &[u8], value: &[u8]) -> Result<(), DatabaseError> {
+        let checksums = self.recover_poisoned(self.checksums.read(), LockKind::Read, "checksums")?;
+        match checksums.as_ref().and_then(|checksums| checksums.get(key)) {
+            Some(checksum) if *checksum != value_checksum(value) => {
+                Err(DatabaseError::ChecksumMismatch { key: key.to_vec() })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn get_tombstones(&self) -> Result<MutexGuard<'_, HashSet<Vec<u8>>>, DatabaseError> {
+        self.recover_poisoned(self.tombstones.lock(), LockKind::Write, "tombstones")
+    }
+
+    fn get_subscribers(&self) -> Result<MutexGuard<'_, Vec<Sender<LogOperation>>>, DatabaseError> {
+        self.recover_poisoned(self.subscribers.lock(), LockKind::Write, "subscribers")
+    }
+
+    fn get_key_watchers(&self) -> Result<MutexGuard<'_, KeyWatcherList>, DatabaseError> {
+        self.recover_poisoned(self.key_watchers.lock(), LockKind::Write, "key watchers")
+    }
+
+    /// Drops `key` from memory if its TTL has elapsed, returning whether it
+    /// was expired.
+    fn remove_if_expired(&self, key: &[u8]) -> bool {
+        let now = self.clock.now_millis();
+        match self.read_expiries() {
+            Ok(expiries) if is_expired(&expiries, key, now) => (),
+            _ => return false,
+        }
+
+        let records = self.recover_poisoned(self.records.write(), LockKind::Write, "records");
+        let expiries = self.recover_poisoned(self.expiries.write(), LockKind::Write, "expiries");
+        if let (Ok(mut records), Ok(mut expiries)) = (records, expiries) {
+            if is_expired(&expiries, key, now) {
+                Arc::make_mut(&mut records).remove(key);
+                expiries.remove(key);
+                self.changed_since_commit.store(true, Ordering::SeqCst);
+            }
+        }
+        true
+    }
+
+    fn purge_expired(&self) -> Result<(), DatabaseError> {
+        let now = self.clock.now_millis();
+        let mut records = self.get_write_records()?;
+        let mut expiries = self.get_write_expiries()?;
+
+        expiries.retain(|key, expires_at| {
+            let live = *expires_at > now;
+            if !live {
+                Arc::make_mut(&mut records).remove(key);
+                self.changed_since_commit.store(true, Ordering::SeqCst);
+            }
+            live
+        });
+        Ok(())
+    }
+
+    pub(crate) fn auto_commit_if_needed(
+        &self,
+        mut log: MutexGuard<'_, Log<LogFile>>,
+    ) -> Result<(), DatabaseError> {
+        let bytes_exceeded = match self.options.auto_commit_bytes {
+            Some(threshold) => log.size_bytes()? > threshold,
+            None => false,
+        };
+        let ops_reached = match self.options.auto_commit_ops {
+            Some(threshold) => self.ops_since_commit.load(Ordering::SeqCst) >= threshold,
+            None => false,
+        };
+
+        if bytes_exceeded || ops_reached {
+            drop(log);
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn get_write_records(
+        &self,
+    ) -> Result<std::sync::RwLockWriteGuard<'_, Arc<SimpleCollection>>, DatabaseError> {
+        if self.commit_in_progress.load(Ordering::SeqCst) {
+            return Err(DatabaseError::Lock {
+                kind: LockKind::Write,
+                reason: Some(String::from("Commit in progress")),
+            });
+        }
+
+        let records = match self.options.lock_timeout {
+            Some(timeout) => lock_within(timeout, LockKind::Write, || self.records.try_write())?,
+            None => self.records.write(),
+        };
+        self.recover_poisoned(records, LockKind::Write, "records")
+    }
+
+    fn read_records(
+        &self,
+    ) -> Result<RwLockReadGuard<'_, Arc<SimpleCollection>>, DatabaseError> {
+        self.recover_poisoned(self.records.read(), LockKind::Read, "records")
+    }
+
+    fn read_expiries(&self) -> Result<RwLockReadGuard<'_, Expiries>, DatabaseError> {
+        self.recover_poisoned(self.expiries.read(), LockKind::Read, "expiries")
+    }
+
+    /// Takes over a lock poisoned by a thread that panicked while holding
+    /// it if `DbOptions::recover_poisoned_locks` is set, otherwise fails
+    /// with `DatabaseError::Lock` naming the lock.
+    fn recover_poisoned<G>(
+        &self,
+        result: LockResult<G>,
+        kind: LockKind,
+        name: &str,
+    ) -> Result<G, DatabaseError> {
+        match result {
+            Ok(guard) => Ok(guard),
+            Err(poisoned) if self.options.recover_poisoned_locks => Ok(poisoned.into_inner()),
+            Err(_) => Err(DatabaseError::Lock {
+                kind,
+                reason: Some(format!("{} lock poisoned", name)),
+            }),
+        }
+    }
+
+    fn get_write_expiries(&self) -> Result<RwLockWriteGuard<'_, Expiries>, DatabaseError> {
+        let expiries = match self.options.lock_timeout {
+            Some(timeout) => lock_within(timeout, LockKind::Write, || self.expiries.try_write())?,
+            None => self.expiries.write(),
+        };
+        self.recover_poisoned(expiries, LockKind::Write, "expiries")
+    }
+
+    /// Promotes `new_version` to `version` with a single rename, then removes
+    /// the files of the version that falls out of `DbOptions::retain_versions`.
+    fn cleanup_previous_commit_files(&self, previous_version: u64) -> std::io::Result<()> {
+        self.crash_point(CrashPoint::BeforeRename);
+        let layout = &self.options.layout;
+        std::fs::rename(
+            get_db_file_path(&self.path, layout, None, &layout.new_version),
+            get_db_file_path(&self.path, layout, None, &layout.version),
+        )?;
+
+        let retained = self.options.retain_versions as u64;
+        let expired_version = match previous_version.checked_sub(retained) {
+            Some(version) => version,
+            None => return Ok(()),
+        };
+        let expired_log_path =
+            get_db_file_path(&self.path, layout, Some(expired_version), &layout.log);
+        std::fs::remove_file(&expired_log_path)?;
+        remove_log_segments(&expired_log_path)?;
+        std::fs::remove_file(self.checkpoint_path(expired_version))?;
+        // Checkpoints written before indexes were introduced have none.
+        match std::fs::remove_file(self.index_path(expired_version)) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+        if layout.version_dirs {
+            std::fs::remove_dir(get_version_dir_path(&self.path, expired_version))?;
+        }
+
+        Ok(())
+    }
+
+    /// Panics if a test injected a crash at `point`, as if the process died
+    /// there. If a test paused at `point` instead, waits on its barrier once
+    /// to signal it got there and once more to be let through.
+    #[cfg(test)]
+    fn crash_point(&self, point: CrashPoint) {
+        if self.crash_point == Some(point) {
+            panic!("Injected crash at {:?}", point);
+        }
+        if let Some((_, barrier)) = self.pause_point.as_ref().filter(|(at, _)| *at == point) {
+            barrier.wait();
+            barrier.wait();
+        }
+    }
+
+    #[cfg(not(test))]
+    fn crash_point(&self, _point: CrashPoint) {}
+
+    /// Releases the directory lock of a handle that is about to be leaked,
+    /// as the OS does when the process dies.
+    #[cfg(test)]
+    pub(crate) fn release_lock(&mut self) {
+        self.lock_file = None;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_crash_point(&mut self, point: Option<CrashPoint>) {
+        self.crash_point = point;
+    }
+
+    #[cfg(test)]
+    pub(crate) fn set_pause_point(&mut self, point: CrashPoint, barrier: Arc<std::sync::Barrier>) {
+        self.pause_point = Some((point, barrier));
+    }
+}
+
+/// Best-effort sync of log appends still pending under the sync policy.
+/// Errors can't be reported from here, so prefer calling `flush` or `commit`
+/// explicitly before dropping the database.
+impl Drop for SimpleDB {
+    fn drop(&mut self) {
+        if self.options.read_only {
+            return;
+        }
+        if let Ok(log) = self.log.get_mut() {
+            let _ = log.sync();
+        }
+        // Closing the handle releases the lock. The `LOCK` file stays, removing
+        // it would let a new handle lock a new file while another one still
+        // waits on the old one.
+        self.lock_file.take();
+    }
+}
+
+/// Streams the records of a checkpoint, from the reader's current position
+/// to its end.
+struct CheckpointEntries<R> {
+    reader: R,
+    length: u64,
+    cipher: Option<Arc<dyn Cipher>>,
+    failed: bool,
+}
+
+impl<R: Read + Seek> CheckpointEntries<R> {
+    fn new(mut reader: R, cipher: Option<Arc<dyn Cipher>>) -> Result<Self, DatabaseError> {
+        let start = reader.stream_position()?;
+        let length = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(start))?;
+        CheckpointEntries::check_header(&mut reader, length - start)?;
+
+        Ok(CheckpointEntries {
+            reader,
+            length,
+            cipher,
+            failed: false,
+        })
+    }
+
+    /// Skips over the checkpoint header, rejecting format versions newer
+    /// than this build understands. Headerless checkpoints are read from the
+    /// start, which includes empty ones as left by an interrupted create:
+    /// they hold no records.
+    fn check_header(reader: &mut R, length: u64) -> Result<(), DatabaseError> {
+        let start = reader.stream_position()?;
+        let mut magic = [0; 4];
+        if length < (magic.len() + bytes::U64_BYTES_LEN) as u64 {
+            return Ok(());
+        }
+
+        reader.read_exact(&mut magic)?;
+        if &magic != CHECKPOINT_MAGIC {
+            reader.seek(SeekFrom::Start(start))?;
+            return Ok(());
+        }
+
+        let found = bytes::read_u64_from_log(reader)?;
+        if found > CHECKPOINT_FORMAT_VERSION {
+            return Err(DatabaseError::UnsupportedFormat {
+                found,
+                supported: CHECKPOINT_FORMAT_VERSION,
+            });
+        }
+        Ok(())
+    }
+
+    fn position(&mut self) -> std::io::Result<u64> {
+        self.reader.stream_position()
+    }
+
+    fn read_entry(&mut self) -> Result<(Vec<u8>, Vec<u8>), DatabaseError> {
+        let key_length = bytes::read_u64_from_log(&mut self.reader)?;
+        let key = bytes::read_bytes_from_log(&mut self.reader, key_length)?;
+        let value = bytes::read_value_from_log(&mut self.reader, self.cipher.as_deref())?;
+
+        Ok((key, value))
+    }
+}
+
+impl<R: Read + Seek> Iterator for CheckpointEntries<R> {
+    type Item = Result<(Vec<u8>, Vec<u8>), DatabaseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let entry = match self.reader.stream_position() {
+            Ok(position) if position >= self.length => return None,
+            Ok(_) => self.read_entry(),
+            Err(error) => Err(error.into()),
+        };
+        self.failed = entry.is_err();
+        Some(entry)
+    }
+}
+
+fn apply_operation(
+    records: &mut SimpleCollection,
+    expiries: &mut Expiries,
+    operation: LogOperation,
+    now: u64,
+) {
+    match operation {
+        LogOperation::Put(key, value) => {
+            expiries.remove(&key);
+            records.insert(key, value);
+        }
+        LogOperation::PutWithExpiry(key, value, expires_at) => {
+            if expires_at > now {
+                expiries.insert(key.clone(), expires_at);
+                records.insert(key, value);
+            } else {
+                expiries.remove(&key);
+                records.remove(&key);
+            }
+        }
+        LogOperation::Delete(key) => {
+            expiries.remove(&key);
+            records.remove(&key);
+        }
+        LogOperation::Clear => {
+            expiries.clear();
+            records.clear();
+        }
+        LogOperation::Append(key, suffix) => {
+            if is_expired(expiries, &key, now) {
+                expiries.remove(&key);
+                records.remove(&key);
+            }
+            records.entry(key).or_default().extend(suffix);
+        }
+    }
+}
+
+/// Tracks the keys `operation` deletes or writes again in `tombstones`.
+fn record_tombstone(tombstones: &mut HashSet<Vec<u8>>, operation: &LogOperation) {
+    match operation {
+        LogOperation::Delete(key) => {
+            tombstones.insert(key.clone());
+        }
+        LogOperation::Clear => tombstones.clear(),
+        _ => {
+            tombstones.remove(operation.key());
+        }
+    }
+}
+
+pub(crate) fn is_expired(expiries: &Expiries, key: &[u8], now: u64) -> bool {
+    match expiries.get(key) {
+        Some(expires_at) => *expires_at <= now,
+        None => false,
+    }
+}
+
+/// Room is left for twice the current keys, so the filter stays near its
+/// false positive rate while keys are added until the next commit.
+fn build_bloom_filter(
+    records: &SimpleCollection,
+    false_positive_rate: Option<f64>,
+) -> Option<BloomFilter> {
+    let false_positive_rate = false_positive_rate?;
+    let mut bloom = BloomFilter::new((records.len() * 2).max(1024), false_positive_rate);
+    for key in records.keys() {
+        bloom.insert(key);
+    }
+    Some(bloom)
+}
+
+fn build_checksums(records: &SimpleCollection, enabled: bool) -> Option<HashMap<Vec<u8>, u64>> {
+    if !enabled {
+        return None;
+    }
+    Some(
+        records
+            .iter()
+            .map(|(key, value)| (key.clone(), value_checksum(value)))
+            .collect(),
+    )
+}
+
+fn value_checksum(value: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(value);
+    hasher.finish()
+}
+
+/// Retries `try_lock` until the lock is free, failing with a `timeout` lock
+/// error once `timeout` has elapsed. A poisoned lock is handed back to the
+/// caller like `lock` would.
+fn lock_within<G>(
+    timeout: Duration,
+    kind: LockKind,
+    mut try_lock: impl FnMut() -> TryLockResult<G>,
+) -> Result<LockResult<G>, DatabaseError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match try_lock() {
+            Ok(guard) => return Ok(Ok(guard)),
+            Err(TryLockError::Poisoned(error)) => return Ok(Err(error)),
+            Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                std::thread::sleep(LOCK_POLL_INTERVAL)
+            }
+            Err(TryLockError::WouldBlock) => {
+                return Err(DatabaseError::Lock {
+                    kind,
+                    reason: Some(String::from("timeout")),
+                })
+            }
+        }
+    }
+}
+
+/// Takes an exclusive advisory lock on the `LOCK` file of the database at
+/// `path`, it is released by the OS when the returned file is closed, even
+/// if the process crashes.
+fn lock_db_dir(path: &Path, layout: &FileLayout) -> Result<File, DatabaseError> {
+    let lock_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(get_db_file_path(path, layout, None, &layout.lock))?;
+
+    if !try_lock_exclusive(&lock_file)? {
+        return Err(DatabaseError::AlreadyOpen);
+    }
+
+    Ok(lock_file)
+}
+
+/// Takes an exclusive advisory lock on `file` without blocking, false if
+/// another handle holds it. The lock is released when `file` is closed.
+#[cfg(unix)]
+fn try_lock_exclusive(file: &File) -> std::io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(true);
+    }
+    let error = std::io::Error::last_os_error();
+    match error.kind() {
+        std::io::ErrorKind::WouldBlock => Ok(false),
+        _ => Err(error),
+    }
+}
+
+/// Other platforms have no `flock`, the directory isn't locked there and
+/// nothing stops two handles from writing to the same database.
+#[cfg(not(unix))]
+fn try_lock_exclusive(_file: &File) -> std::io::Result<bool> {
+    Ok(true)
+}
+
+/// Contents of a version file, the version followed by the time it was
+/// committed. Files written before commit times were recorded only hold
+/// the version.
+struct VersionFile {
+    version: u64,
+    committed_at: Option<u64>,
+}
+
+fn read_version_file(path: &Path) -> std::io::Result<VersionFile> {
+    let contents = read_string_from_file(path)?;
+    let mut fields = contents.split_whitespace();
+    let version = fields
+        .next()
+        .and_then(|version| version.parse::<u64>().ok())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid version file: {:?}", contents),
+            )
+        })?;
+    let committed_at = fields.next().and_then(|millis| millis.parse::<u64>().ok());
+
+    Ok(VersionFile {
+        version,
+        committed_at,
+    })
+}
+
+/// Checks the database at `path` without opening it: the version file
+/// must hold a version whose checkpoint decodes and whose log replays
+/// cleanly. Nothing is written and the directory isn't locked, so this is
+/// safe to run against a database open elsewhere. The database must use
+/// the default `FileLayout` and no `DbOptions::encryption`. A version left
+/// by an interrupted commit isn't considered.
+pub fn check_integrity(path: &Path) -> Result<IntegrityReport, DatabaseError> {
+    if !path.is_dir() {
+        return Err(DatabaseError::NotADatabase {
+            path: path.to_path_buf(),
+        });
+    }
+
+    let layout = FileLayout::default();
+    let version = match read_version_file(&get_db_file_path(path, &layout, None, &layout.version)) {
+        Ok(version_file) => version_file.version,
+        Err(_) => {
+            return Ok(IntegrityReport {
+                version: None,
+                anomalies: vec![Anomaly::BadVersionFile],
+            })
+        }
+    };
+
+    let mut anomalies = vec![];
+    let checkpoint_path = get_db_file_path(path, &layout, Some(version), &layout.checkpoint);
+    match File::open(checkpoint_path) {
+        Ok(mut checkpoint_file) => {
+            if SimpleDB::read_records_from_file(&mut checkpoint_file, None).is_err() {
+                anomalies.push(Anomaly::CorruptCheckpoint { version });
+            }
+        }
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            anomalies.push(Anomaly::MissingCheckpoint { version });
+        }
+        Err(error) => return Err(error.into()),
+    }
+
+    let log_path = get_db_file_path(path, &layout, Some(version), &layout.log);
+    match Log::<LogFile>::open_read_only(&log_path) {
+        Ok(mut log) => {
+            let scan = log.scan()?;
+            if scan.partial_tail {
+                anomalies.push(Anomaly::PartialLogTail {
+                    valid_entries: scan.valid_entries,
+                });
+            } else if scan.invalid_entries > 0 {
+                anomalies.push(Anomaly::CorruptLog {
+                    valid_entries: scan.valid_entries,
+                });
+            }
+        }
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+            anomalies.push(Anomaly::MissingLog { version });
+        }
+        Err(error) => return Err(error.into()),
+    }
+
+    Ok(IntegrityReport {
+        version: Some(version),
+        anomalies,
+    })
+}
+
+fn create_version_file(
+    path: &Path,
+    layout: &FileLayout,
+    version: u64,
+    committed_at: Option<u64>,
+    new: bool,
+) -> std::io::Result<()> {
+    let file_name = if new {
+        &layout.new_version
+    } else {
+        &layout.version
+    };
+    let file_path = format!("{}/{}", path.to_str().unwrap(), file_name);
+    let mut file = File::create(file_path)?;
+    let version_string = match committed_at {
+        Some(committed_at) => format!("{} {}", version, committed_at),
+        None => format!("{}", version),
+    };
+    file.write_all(version_string.as_bytes())?;
+    file.sync_data()?;
+
+    Ok(())
+}
+
+/// Makes the files created in or renamed into `dir` durable.
+fn sync_dir(dir: &Path) -> std::io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Versions that have a checkpoint in the database at `path`, oldest first.
+fn checkpoint_versions(path: &Path, layout: &FileLayout) -> std::io::Result<Vec<u64>> {
+    let mut versions = vec![];
+    for entry in std::fs::read_dir(path)? {
+        let name = entry?.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        let version = if layout.version_dirs {
+            name.strip_prefix('v')
+        } else {
+            name.strip_prefix(layout.checkpoint.as_str())
+                .and_then(|suffix| suffix.strip_prefix('.'))
+        };
+        let version = match version.and_then(|version| version.parse::<u64>().ok()) {
+            Some(version) => version,
+            None => continue,
+        };
+        if get_db_file_path(path, layout, Some(version), &layout.checkpoint).is_file() {
+            versions.push(version);
+        }
+    }
+    versions.sort_unstable();
+    Ok(versions)
+}
+
+/// Version of a checkpoint or log file named `name`, including log
+/// segments and temporary checkpoints, in the flat `FileLayout`.
+fn versioned_file_version(name: &str, layout: &FileLayout) -> Option<u64> {
+    let suffix = [&layout.checkpoint, &layout.index, &layout.log, &layout.compacted_log]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix.as_str())?.strip_prefix('.'))?;
+    suffix.split('.').next()?.parse().ok()
+}
+
+/// Versions that have both a checkpoint and a log in the database at
+/// `path`, newest first.
+fn scan_versions(path: &Path, layout: &FileLayout) -> std::io::Result<Vec<u64>> {
+    let mut versions = checkpoint_versions(path, layout)?;
+    versions.retain(|version| {
+        get_db_file_path(path, layout, Some(*version), &layout.log).is_file()
+    });
+    versions.reverse();
+    Ok(versions)
+}
+
+fn create_db_file(
+    path: &Path,
+    layout: &FileLayout,
+    version: u64,
+    file_name: &str,
+) -> std::io::Result<File> {
+    let file_path = get_db_file_path(path, layout, Some(version), file_name);
+    create_version_dir(&file_path)?;
+    File::create(file_path)
+}
+
+/// Creates the directory `file_path` goes in, which only needs creating
+/// with `FileLayout::version_dirs`.
+fn create_version_dir(file_path: &Path) -> std::io::Result<()> {
+    match file_path.parent() {
+        Some(dir) => std::fs::create_dir_all(dir),
+        None => Ok(()),
+    }
+}
+
+/// Hard links `from` as `to`, copying it where links aren't supported. A
+/// file already at `to`, left by an interrupted commit, is replaced.
+fn link_or_copy(from: &Path, to: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(to) {
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => return Err(error),
+        _ => {}
+    }
+    if std::fs::hard_link(from, to).is_err() {
+        std::fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+fn temp_checkpoint_path(checkpoint_path: &Path) -> PathBuf {
+    let mut temp_path = checkpoint_path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    PathBuf::from(temp_path)
+}
+
+fn configure_log(log: &mut Log<LogFile>, options: &DbOptions) {
+    log.set_sync_policy(options.sync_policy);
+    log.set_compression(options.compression);
+    log.set_cipher(options.cipher());
+    log.set_max_segment_bytes(options.log_segment_bytes);
+}
+
+/// Re-logs the expiry of every live TTL entry of `source` into a freshly
+/// created log, since checkpoints only hold plain key/value pairs.
+fn carry_expiries_forward(
+    source: &CommitSource,
+    log: &mut Log<LogFile>,
+) -> Result<(), DatabaseError> {
+    for (key, expires_at) in source.expiries.iter() {
+        if let Some(value) = source.records.get(key) {
+            log.append(LogOperation::PutWithExpiry(
+                key.clone(),
+                value.clone(),
+                *expires_at,
+            ))?;
+        }
+    }
+    log.sync()?;
+    Ok(())
+}
+
+/// Sends `operation` to every live subscriber, forgetting the ones whose
+/// receiver was dropped.
+fn notify_subscribers(subscribers: &mut Vec<Sender<LogOperation>>, operation: LogOperation) {
+    subscribers.retain(|subscriber| subscriber.send(operation.clone()).is_ok());
+}
+
+/// Sends `value` to the watchers of `key`, or `None` to every watcher if the
+/// records were cleared, forgetting the ones whose receiver was dropped.
+fn notify_key_watchers(
+    watchers: &mut KeyWatcherList,
+    key: &[u8],
+    cleared: bool,
+    value: Option<&Vec<u8>>,
+) {
+    watchers.retain(|(watched, watcher)| {
+        if cleared || watched.as_slice() == key {
+            watcher.send(value.cloned()).is_ok()
+        } else {
+            true
+        }
+    });
+}
+
+/// Removes the segments after the first of the log at `log_path`, newest
+/// first so a crash part way never leaves a gap before the ones left.
+fn remove_log_segments(log_path: &Path) -> std::io::Result<()> {
+    let mut segments = 1;
+    while log::segment_path(log_path, segments).exists() {
+        segments += 1;
+    }
+    for index in (1..segments).rev() {
+        std::fs::remove_file(log::segment_path(log_path, index))?;
+    }
+    Ok(())
+}
+
+/// Replaces the log at `log_path` and its segments with the compacted log
+/// `compact_log` finished writing. Segments left behind would replay after
+/// the compacted log and apply their appends twice, so they go first.
+fn install_compacted_log(compacted_log_path: &Path, log_path: &Path) -> std::io::Result<()> {
+    remove_log_segments(log_path)?;
+    std::fs::rename(compacted_log_path, log_path)?;
+    sync_dir(parent_dir(log_path))
+}
+
+fn parent_dir(file_path: &Path) -> &Path {
+    file_path.parent().expect("database files live in a directory")
+}
+
+fn get_db_file_path(
+    path: &Path,
+    layout: &FileLayout,
+    version: Option<u64>,
+    file_name: &str,
+) -> PathBuf {
+    match version {
+        Some(n) if layout.version_dirs => get_version_dir_path(path, n).join(file_name),
+        Some(n) => PathBuf::from(format!("{}/{}.{}", path.to_str().unwrap(), file_name, n)),
+        None => PathBuf::from(format!("{}/{}", path.to_str().unwrap(), file_name,)),
+    }
+}
+
+fn get_version_dir_path(path: &Path, version: u64) -> PathBuf {
+    PathBuf::from(format!("{}/v{}", path.to_str().unwrap(), version))
+}
+
+fn read_string_from_file(path: &Path) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
+    let mut string = String::new();
+    file.read_to_string(&mut string)?;
+
+    Ok(string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::Compression;
+    use crate::error::LogError;
+    use crate::test_utils::{
+        crash_after_checkpoint_write, crash_after_new_version_file, crash_before_checkpoint_rename,
+        crash_before_rename, delete_all_db_files, delete_db_files, get_other_temp_test_path,
+        get_temp_test_current_path, json_value, run_test,
+    };
+    use serial_test::serial;
+    use std::env;
+    use std::sync::Mutex;
+
+    fn _check_file_exists_in_path(base_path: PathBuf, file_name: &str) {
+        let mut path = base_path.clone();
+        path.push(file_name);
+        assert!(path.exists());
+    }
+
+    fn check_file_exists_in_temp_test_folder(file_name: &str) {
+        let temp_test_path = get_temp_test_current_path();
+        _check_file_exists_in_path(temp_test_path, file_name);
+    }
+
+    fn get_db_file_path(path: &Path, version: Option<u64>, file_name: &str) -> PathBuf {
+        super::get_db_file_path(path, &FileLayout::default(), version, file_name)
+    }
+
+    fn get_version_from_file() -> u64 {
+        let mut current_path = get_temp_test_current_path();
+        current_path.push("version");
+
+        read_version_file(&current_path).unwrap().version
+    }
+
+    #[test]
+    #[serial]
+    fn test_create_db() {
+        run_test(
+            || {
+                SimpleDB::open(get_temp_test_current_path()).unwrap();
+
+                check_file_exists_in_temp_test_folder("checkpoint.0");
+                check_file_exists_in_temp_test_folder("logfile.0");
+                check_file_exists_in_temp_test_folder("version");
+
+                assert_eq!(get_version_from_file(), 0);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_basic_db_operations() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "ran").unwrap();
+                let name = db.get("name").unwrap();
+
+                assert_eq!(String::from_utf8(name.to_vec()).unwrap(), "ran");
+
+                db.put("name", "bob").unwrap();
+                let name = db.get("name").unwrap();
+                assert_eq!(String::from_utf8(name.to_vec()).unwrap(), "bob");
+                assert_eq!(db.version(), 0);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_concurrent_write() {
+        run_test(
+            || {
+                let db = Arc::new(Mutex::new(
+                    SimpleDB::open(get_temp_test_current_path()).unwrap(),
+                ));
+                let mut handles = vec![];
+                for _ in 0..2 {
+                    let t_db = Arc::clone(&db);
+                    let handle = std::thread::spawn(move || {
+                        let db = t_db.lock().unwrap();
+                        if db.get("name").is_none() {
+                            db.put("name", "bob").unwrap();
+                        } else {
+                            db.put("age", "54").unwrap();
+                        }
+                    });
+
+                    handles.push(handle);
+                }
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+                let db: &SimpleDB = &db.lock().unwrap();
+                let name = db.get("name").unwrap();
+                let age = db.get("age").unwrap();
+
+                assert_eq!(String::from_utf8(name.to_vec()).unwrap(), "bob");
+                assert_eq!(String::from_utf8(age.to_vec()).unwrap(), "54");
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_commit_changes() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.put("age", "54").unwrap();
+
+                db.commit().unwrap();
+
+                let name = db.get("name").unwrap();
+                let age = db.get("age").unwrap();
+
+                assert_eq!(String::from_utf8(name.to_vec()).unwrap(), "bob");
+                assert_eq!(String::from_utf8(age.to_vec()).unwrap(), "54");
+
+                check_file_exists_in_temp_test_folder("checkpoint.1");
+                check_file_exists_in_temp_test_folder("logfile.1");
+                check_file_exists_in_temp_test_folder("version");
+
+                assert_eq!(get_version_from_file(), 1);
+                assert_eq!(db.version(), 1);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_load_from_checkpoint_after_commit() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.put("age", "54").unwrap();
+                db.delete("age").unwrap();
+
+                db.commit().unwrap();
+                
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+
+                assert_eq!(String::from_utf8(db.get("name").unwrap().to_vec()).unwrap(), "bob");
+                assert_eq!(db.version(), 1);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_load_from_checkpoint_before_commit() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.put("age", "54").unwrap();
+                db.delete("age").unwrap();
+                
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+
+                assert_eq!(String::from_utf8(db.get("name").unwrap().to_vec()).unwrap(), "bob");
+                assert_eq!(db.version(), 0);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_sync_policy_survives_commit() {
+        run_test(
+            || {
+                let mut db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.set_sync_policy(SyncPolicy::Never);
+                db.put("name", "bob").unwrap();
+
+                db.commit().unwrap();
+                db.put("age", "54").unwrap();
+
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+
+                assert_eq!(db.get("name").unwrap(), b"bob");
+                assert_eq!(db.get("age").unwrap(), b"54");
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_flush_without_commit() {
+        run_test(
+            || {
+                let mut db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.set_sync_policy(SyncPolicy::Never);
+                db.put("name", "bob").unwrap();
+                db.put("age", "54").unwrap();
+
+                db.flush().unwrap();
+
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+
+                assert_eq!(db.get("name").unwrap(), b"bob");
+                assert_eq!(db.get("age").unwrap(), b"54");
+                assert_eq!(db.version(), 0);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_open_read_only() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+
+                drop(db);
+
+                let db = SimpleDB::open_read_only(get_temp_test_current_path()).unwrap();
+
+                assert_eq!(db.get("name").unwrap(), b"bob");
+                assert!(matches!(db.put("age", "54"), Err(DatabaseError::ReadOnly)));
+                assert!(matches!(db.delete("name"), Err(DatabaseError::ReadOnly)));
+                assert!(matches!(db.commit(), Err(DatabaseError::ReadOnly)));
+                assert_eq!(db.get("name").unwrap(), b"bob");
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_concurrent_write_without_external_lock() {
+        run_test(
+            || {
+                let db = Arc::new(SimpleDB::open(get_temp_test_current_path()).unwrap());
+                let mut handles = vec![];
+                for i in 0..8 {
+                    let t_db = Arc::clone(&db);
+                    let handle = std::thread::spawn(move || {
+                        for j in 0..10 {
+                            let key = format!("key-{}-{}", i, j);
+                            t_db.put(key.clone(), key).unwrap();
+                        }
+                    });
+
+                    handles.push(handle);
+                }
+                for handle in handles {
+                    handle.join().unwrap();
+                }
+
+                for i in 0..8 {
+                    for j in 0..10 {
+                        let key = format!("key-{}-{}", i, j);
+                        assert_eq!(db.get(key.clone()).unwrap(), key.as_bytes());
+                    }
+                }
+
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("key-7-9").unwrap(), b"key-7-9");
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_auto_commit_bytes() {
+        run_test(
+            || {
+                let mut db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.set_auto_commit_bytes(Some(64));
+
+                db.put("name", "bob").unwrap();
+                assert_eq!(db.version(), 0);
+
+                for i in 0..5 {
+                    db.put("name", format!("bob-{}", i)).unwrap();
+                }
+                assert!(db.version() > 0);
+
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert!(db.version() > 0);
+                assert_eq!(db.get("name").unwrap(), b"bob-4");
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_compact_log() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                for i in 0..100 {
+                    db.put("name", format!("bob-{}", i)).unwrap();
+                }
+                db.put("age", "54").unwrap();
+                db.delete("age").unwrap();
+
+                db.compact_log().unwrap();
+
+                let operations = db.get_log().unwrap().read_until_empty().unwrap();
+                assert_eq!(
+                    operations,
+                    vec![
+                        LogOperation::Put("name".into(), "bob-99".into()),
+                        LogOperation::Delete("age".into()),
+                    ]
+                );
+
+                db.put("city", "paris").unwrap();
+
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("name").unwrap(), b"bob-99");
+                assert_eq!(db.get("city").unwrap(), b"paris");
+                assert_eq!(db.get("age"), None);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_scan_prefix() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("user:1:name", "bob").unwrap();
+                db.put("user:1:age", "54").unwrap();
+                db.put("user:2:name", "alice").unwrap();
+                db.put("group:1", "admins").unwrap();
+
+                let mut user_1 = db.scan_prefix("user:1:");
+                user_1.sort();
+                assert_eq!(
+                    user_1,
+                    vec![
+                        (b"user:1:age".to_vec(), b"54".to_vec()),
+                        (b"user:1:name".to_vec(), b"bob".to_vec()),
+                    ]
+                );
+
+                assert!(db.scan_prefix("user:3:").is_empty());
+                assert_eq!(db.scan_prefix("").len(), 4);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    struct ManualClock(AtomicU64);
+
+    impl Clock for ManualClock {
+        fn now_millis(&self) -> u64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_put_with_ttl() {
+        run_test(
+            || {
+                let clock = Arc::new(ManualClock(AtomicU64::new(1_000)));
+                let mut db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.clock = clock.clone();
+
+                db.put_with_ttl("session", "abc", Duration::from_millis(500))
+                    .unwrap();
+                assert_eq!(db.get("session").unwrap(), b"abc");
+                assert!(db.contains_key("session"));
+
+                clock.0.store(1_500, Ordering::SeqCst);
+                assert_eq!(db.get("session"), None);
+                assert!(!db.contains_key("session"));
+
+                db.put_with_ttl("session", "def", Duration::from_millis(500))
+                    .unwrap();
+                db.put("session", "forever").unwrap();
+                clock.0.store(10_000, Ordering::SeqCst);
+                assert_eq!(db.get("session").unwrap(), b"forever");
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_put_with_ttl_survives_reload() {
+        run_test(
+            || {
+                let mut db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.clock = Arc::new(ManualClock(AtomicU64::new(1_000)));
+                db.put_with_ttl("expired", "abc", Duration::from_millis(500))
+                    .unwrap();
+                db.clock = Arc::new(SystemClock);
+                db.put_with_ttl("live", "def", Duration::from_secs(3600))
+                    .unwrap();
+
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("expired"), None);
+                assert_eq!(db.get("live").unwrap(), b"def");
+
+                db.commit().unwrap();
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("live").unwrap(), b"def");
+                assert!(db.expiries.read().unwrap().contains_key(b"live".as_ref()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_verify() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.commit().unwrap();
+                db.put("age", "54").unwrap();
+                db.delete("name").unwrap();
+
+                let report = db.verify().unwrap();
+                assert!(report.is_healthy());
+                assert_eq!(
+                    report,
+                    VerifyReport {
+                        checkpoint_valid: true,
+                        checkpoint_records: 1,
+                        valid_log_entries: 2,
+                        invalid_log_entries: 0,
+                        partial_tail: false,
+                    }
+                );
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_verify_corrupted_log_tail() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.put("age", "54").unwrap();
+
+                let mut log_path = get_temp_test_current_path();
+                log_path.push("logfile.0");
+                let mut log_file = std::fs::OpenOptions::new()
+                    .append(true)
+                    .open(log_path)
+                    .unwrap();
+                let mut truncated = vec![];
+                bytes::write_encoded_char_to_buffer('p', &mut truncated);
+                bytes::write_u64_to_buffer(100, &mut truncated);
+                truncated.extend(b"abc");
+                log_file.write_all(&truncated).unwrap();
+
+                let report = db.verify().unwrap();
+                assert!(!report.is_healthy());
+                assert_eq!(report.valid_log_entries, 2);
+                assert_eq!(report.invalid_log_entries, 1);
+                assert!(report.partial_tail);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_backup_to() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.commit().unwrap();
+                db.put("age", "54").unwrap();
+                db.put("city", "paris").unwrap();
+                db.delete("city").unwrap();
+
+                let mut backup = vec![];
+                db.backup_to(&mut backup).unwrap();
+
+                let records =
+                    SimpleDB::read_records_from_file(&mut std::io::Cursor::new(backup), None)
+                        .unwrap();
+                let mut expected = HashMap::new();
+                expected.insert(b"name".to_vec(), b"bob".to_vec());
+                expected.insert(b"age".to_vec(), b"54".to_vec());
+                assert_eq!(records, expected);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_restore_from_backup() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.commit().unwrap();
+                db.put("age", "54").unwrap();
+
+                let mut backup = vec![];
+                db.backup_to(&mut backup).unwrap();
+
+                let restored = SimpleDB::restore_from(
+                    get_other_temp_test_path(),
+                    &mut std::io::Cursor::new(backup),
+                )
+                .unwrap();
+                assert_eq!(restored.version(), 0);
+                assert_eq!(restored.get("name").unwrap(), b"bob");
+                assert_eq!(restored.get("age").unwrap(), b"54");
+
+                drop(restored);
+
+                let restored = SimpleDB::open(get_other_temp_test_path()).unwrap();
+                assert_eq!(restored.version(), 0);
+                assert_eq!(restored.get("name").unwrap(), b"bob");
+                assert_eq!(restored.get("age").unwrap(), b"54");
+                assert!(restored.get_log().unwrap().read_until_empty().unwrap().is_empty());
+            },
+            None,
+            Some(Box::new(delete_all_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_stats() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                let stats = db.stats();
+                assert_eq!(stats.version, 0);
+                assert_eq!(stats.num_records, 0);
+                assert_eq!(stats.log_size_bytes, 0);
+                assert!(!stats.commit_in_progress);
+
+                db.put("name", "bob").unwrap();
+                db.put("age", "54").unwrap();
+                let stats = db.stats();
+                assert_eq!(stats.num_records, 2);
+                assert!(stats.log_size_bytes > 0);
+
+                db.commit().unwrap();
+                let stats = db.stats();
+                assert_eq!(stats.version, 1);
+                assert_eq!(stats.num_records, 2);
+                assert_eq!(stats.log_size_bytes, 0);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_open_error_kinds() {
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                create_dir(&path).unwrap();
+                assert!(matches!(
+                    SimpleDB::open(path.clone()),
+                    Err(DatabaseError::NotFound(_))
+                ));
+
+                create_dir(get_db_file_path(&path, None, VERSION_FILE_NAME)).unwrap();
+                assert!(matches!(
+                    SimpleDB::open(path),
+                    Err(DatabaseError::Io(_))
+                ));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_open_with_options() {
+        run_test(
+            || {
+                let db = DbOptions::new()
+                    .sync_policy(SyncPolicy::Never)
+                    .auto_commit_bytes(Some(64))
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                assert_eq!(db.options.sync_policy, SyncPolicy::Never);
+
+                for i in 0..5 {
+                    db.put("name", format!("bob-{}", i)).unwrap();
+                }
+                assert!(db.version() > 0);
+
+                drop(db);
+
+                let db = DbOptions::new()
+                    .read_only(true)
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                assert_eq!(db.get("name").unwrap(), b"bob-4");
+                assert!(matches!(db.put("age", "54"), Err(DatabaseError::ReadOnly)));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_collections() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                let users = db.collection("users");
+                let groups = db.collection("groups");
+
+                users.put("x", "bob").unwrap();
+                groups.put("x", "admins").unwrap();
+                groups.put("y", "guests").unwrap();
+
+                assert_eq!(users.get("x").unwrap(), b"bob");
+                assert_eq!(groups.get("x").unwrap(), b"admins");
+                assert_eq!(users.get("y"), None);
+                assert_eq!(db.get("x"), None);
+                assert_eq!(users.scan_prefix(""), vec![(b"x".to_vec(), b"bob".to_vec())]);
+                assert_eq!(groups.scan_prefix("").len(), 2);
+
+                groups.delete("x").unwrap();
+                assert_eq!(users.get("x").unwrap(), b"bob");
+
+                db.commit().unwrap();
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.collection("users").get("x").unwrap(), b"bob");
+                assert_eq!(db.collection("groups").get("x"), None);
+                assert_eq!(db.collection("groups").get("y").unwrap(), b"guests");
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_get_or_insert_with() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+
+                let name = db
+                    .get_or_insert_with("name", || panic!("closure called on hit"))
+                    .unwrap();
+                assert_eq!(name, b"bob");
+
+                let mut calls = 0;
+                let age = db
+                    .get_or_insert_with("age", || {
+                        calls += 1;
+                        b"54".to_vec()
+                    })
+                    .unwrap();
+                assert_eq!(age, b"54");
+                assert_eq!(calls, 1);
+
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("age").unwrap(), b"54");
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_update() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+
+                let created = db
+                    .update("counter", |current| {
+                        assert_eq!(current, None);
+                        Some(b"1".to_vec())
+                    })
+                    .unwrap();
+                assert_eq!(created.unwrap(), b"1");
+
+                let modified = db
+                    .update("counter", |current| {
+                        let mut value = current.unwrap().to_vec();
+                        value.push(b'0');
+                        Some(value)
+                    })
+                    .unwrap();
+                assert_eq!(modified.unwrap(), b"10");
+
+                let entries_before = db.get_log().unwrap().read_until_empty().unwrap().len();
+                let unchanged = db
+                    .update("counter", |current| current.map(|value| value.to_vec()))
+                    .unwrap();
+                assert_eq!(unchanged.unwrap(), b"10");
+                assert_eq!(
+                    db.get_log().unwrap().read_until_empty().unwrap().len(),
+                    entries_before
+                );
+
+                db.put("name", "bob").unwrap();
+                assert_eq!(db.update("name", |_| None).unwrap(), None);
+                assert_eq!(db.get("name"), None);
+
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("counter").unwrap(), b"10");
+                assert_eq!(db.get("name"), None);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_delete_range() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                for i in 0..10 {
+                    db.put(format!("key-{}", i), format!("value-{}", i)).unwrap();
+                }
+
+                assert_eq!(db.delete_range("key-3", "key-7").unwrap(), 4);
+
+                let check = |db: &SimpleDB| {
+                    for i in 0..10 {
+                        let present = db.get(format!("key-{}", i)).is_some();
+                        assert_eq!(present, !(3..7).contains(&i));
+                    }
+                };
+                check(&db);
+
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                check(&db);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_scan_prefix_limited() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                for i in 0..50 {
+                    db.put(format!("user:{}", i), "bob").unwrap();
+                }
+                db.put("group:1", "admins").unwrap();
+
+                assert_eq!(db.scan_prefix_limited("user:", 10).len(), 10);
+                assert_eq!(db.scan_prefix_limited("user:", 100).len(), 50);
+                assert!(db.scan_prefix_limited("user:", 0).is_empty());
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_many_buffered_puts_reload() {
+        run_test(
+            || {
+                let db = DbOptions::new()
+                    .sync_policy(SyncPolicy::Never)
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                for i in 0..10_000 {
+                    db.put(format!("key-{}", i), format!("value-{}", i)).unwrap();
+                }
+                db.flush().unwrap();
+
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.stats().num_records, 10_000);
+                for i in 0..10_000 {
+                    assert_eq!(
+                        db.get(format!("key-{}", i)).unwrap(),
+                        format!("value-{}", i).as_bytes()
+                    );
+                }
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_drop_syncs_pending_writes() {
+        run_test(
+            || {
+                let db = DbOptions::new()
+                    .sync_policy(SyncPolicy::Never)
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                db.put("name", "bob").unwrap();
+                db.put("age", "54").unwrap();
+
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("name").unwrap(), b"bob");
+                assert_eq!(db.get("age").unwrap(), b"54");
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_sequence() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.sequence(), 0);
+
+                db.put("name", "bob").unwrap();
+                assert_eq!(db.sequence(), 1);
+
+                db.get("name");
+                assert_eq!(db.sequence(), 1);
+
+                db.delete("name").unwrap();
+                assert_eq!(db.sequence(), 2);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_log_operations() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.put("age", "42").unwrap();
+                db.delete("name").unwrap();
+
+                assert_eq!(
+                    db.log_operations().unwrap(),
+                    vec![
+                        LogOperation::Put("name".into(), "bob".into()),
+                        LogOperation::Put("age".into(), "42".into()),
+                        LogOperation::Delete("name".into()),
+                    ]
+                );
+
+                db.put("city", "paris").unwrap();
+                assert_eq!(db.log_operations().unwrap().len(), 4);
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("city"), Some("paris".into()));
+                assert_eq!(db.get("age"), Some("42".into()));
+                assert_eq!(db.get("name"), None);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_max_value_bytes() {
+        run_test(
+            || {
+                let db = DbOptions::new()
+                    .max_value_bytes(Some(5))
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+
+                db.put("name", "bob").unwrap();
+                assert!(matches!(
+                    db.put("name", "robert"),
+                    Err(DatabaseError::ValueTooLarge { limit: 5, actual: 6 })
+                ));
+                assert!(matches!(
+                    db.put_with_ttl("name", "robert", Duration::from_secs(60)),
+                    Err(DatabaseError::ValueTooLarge { .. })
+                ));
+
+                assert_eq!(db.get("name"), Some("bob".into()));
+                assert_eq!(
+                    db.log_operations().unwrap(),
+                    vec![LogOperation::Put("name".into(), "bob".into())]
+                );
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_compression() {
+        run_test(
+            || {
+                let value = json_value();
+                let db = DbOptions::new()
+                    .compression(Compression::Deflate)
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                db.put("name", value.as_slice()).unwrap();
+                db.put("raw", "abc").unwrap();
+                assert_eq!(db.get("name"), Some(value.clone()));
+                assert!(db.stats().log_size_bytes < value.len() as u64);
+
+                db.commit().unwrap();
+                let checkpoint_path = get_db_file_path(
+                    &get_temp_test_current_path(),
+                    Some(db.version()),
+                    CHECKPOINT_FILE_NAME,
+                );
+                let checkpoint_size = std::fs::metadata(checkpoint_path).unwrap().len();
+                assert!(checkpoint_size < value.len() as u64);
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("name"), Some(value));
+                assert_eq!(db.get("raw"), Some("abc".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    /// Commits version 1 holding "name", puts "age", crashes the next commit
+    /// with `crash` and checks the reopened database is at `expected_version`
+    /// with both keys and can still commit.
+    fn assert_recovers_from_crash(crash: fn(SimpleDB), expected_version: u64) {
+        let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+        db.put("name", "bob").unwrap();
+        db.commit().unwrap();
+        db.put("age", "42").unwrap();
+        crash(db);
+
+        let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+        assert_eq!(db.version(), expected_version);
+        assert_eq!(get_version_from_file(), expected_version);
+        assert_eq!(db.get("name"), Some("bob".into()));
+        assert_eq!(db.get("age"), Some("42".into()));
+
+        db.put("city", "paris").unwrap();
+        db.commit().unwrap();
+        drop(db);
+
+        let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+        assert_eq!(db.version(), expected_version + 1);
+        assert_eq!(db.get("age"), Some("42".into()));
+        assert_eq!(db.get("city"), Some("paris".into()));
+    }
+
+    #[test]
+    #[serial]
+    fn test_crash_before_checkpoint_rename() {
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                let checkpoint_path = get_db_file_path(&path, Some(2), CHECKPOINT_FILE_NAME);
+                let db = SimpleDB::open(path.clone()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.commit().unwrap();
+                db.put("age", "42").unwrap();
+                crash_before_checkpoint_rename(db);
+                assert!(temp_checkpoint_path(&checkpoint_path).exists());
+                assert!(!checkpoint_path.exists());
+                assert_eq!(get_version_from_file(), 1);
+
+                let db = SimpleDB::open(path).unwrap();
+                assert_eq!(db.version(), 1);
+                assert_eq!(db.get("name"), Some("bob".into()));
+                assert_eq!(db.get("age"), Some("42".into()));
+                db.commit().unwrap();
+                assert!(!temp_checkpoint_path(&checkpoint_path).exists());
+                assert!(checkpoint_path.exists());
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    /// A `new_version` file written ahead of its checkpoint, as commits used
+    /// to, must not be trusted when the checkpoint never made it to disk.
+    #[test]
+    #[serial]
+    fn test_crash_after_new_version_file_before_checkpoint_write() {
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                let db = SimpleDB::open(path.clone()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.commit().unwrap();
+                db.put("age", "42").unwrap();
+                crash_before_checkpoint_rename(db);
+                create_version_file(&path, &FileLayout::default(), 2, None, true).unwrap();
+
+                let db = SimpleDB::open(path.clone()).unwrap();
+                assert_eq!(db.version(), 1);
+                assert_eq!(get_version_from_file(), 1);
+                assert!(!path.join(NEW_VERSION_FILE_NAME).exists());
+                assert_eq!(db.get("name"), Some("bob".into()));
+                assert_eq!(db.get("age"), Some("42".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_crash_after_checkpoint_write() {
+        run_test(
+            || assert_recovers_from_crash(crash_after_checkpoint_write, 1),
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_crash_after_new_version_file() {
+        run_test(
+            || assert_recovers_from_crash(crash_after_new_version_file, 2),
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_crash_before_rename() {
+        run_test(
+            || assert_recovers_from_crash(crash_before_rename, 2),
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_commit_with_failed_cleanup() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+                std::fs::remove_file(get_db_file_path(
+                    &get_temp_test_current_path(),
+                    Some(0),
+                    CHECKPOINT_FILE_NAME,
+                ))
+                .unwrap();
+
+                assert_eq!(db.commit().unwrap(), 1);
+                assert_eq!(db.version(), 1);
+                assert_eq!(db.op_counters().cleanup_failures, 1);
+                db.put("age", "42").unwrap();
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.version(), 1);
+                assert_eq!(get_version_from_file(), 1);
+                assert_eq!(db.get("name"), Some("bob".into()));
+                assert_eq!(db.get("age"), Some("42".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_commit_promotes_new_version_file() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.commit().unwrap();
+                db.commit().unwrap();
+
+                check_file_exists_in_temp_test_folder(VERSION_FILE_NAME);
+                assert!(!get_temp_test_current_path().join(NEW_VERSION_FILE_NAME).exists());
+                assert_eq!(get_version_from_file(), 2);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_recover_valid_new_version() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+                crash_after_new_version_file(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.version(), 1);
+                assert_eq!(get_version_from_file(), 1);
+                assert_eq!(db.get("name"), Some("bob".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_recover_invalid_new_version() {
+        let corruptions: [fn(PathBuf); 2] = [
+            |checkpoint_path| std::fs::remove_file(checkpoint_path).unwrap(),
+            |checkpoint_path| std::fs::write(checkpoint_path, [0, 0, 0, 0, 0, 0, 0, 9, 1]).unwrap(),
+        ];
+        for corrupt in corruptions.iter() {
+            run_test(
+                || {
+                    let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                    db.put("name", "bob").unwrap();
+                    crash_after_new_version_file(db);
+                    corrupt(get_db_file_path(
+                        &get_temp_test_current_path(),
+                        Some(1),
+                        CHECKPOINT_FILE_NAME,
+                    ));
+
+                    let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                    assert_eq!(db.version(), 0);
+                    assert_eq!(get_version_from_file(), 0);
+                    assert!(!get_temp_test_current_path().join(NEW_VERSION_FILE_NAME).exists());
+                    assert_eq!(db.get("name"), Some("bob".into()));
+                },
+                None,
+                Some(Box::new(delete_db_files)),
+            )
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_checkpoint_entries() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                for i in 0..100 {
+                    db.put(format!("key{}", i), format!("value{}", i)).unwrap();
+                }
+                db.commit().unwrap();
+
+                let streamed: HashMap<Vec<u8>, Vec<u8>> =
+                    SimpleDB::checkpoint_entries(&get_temp_test_current_path(), db.version())
+                        .collect::<Result<_, _>>()
+                        .unwrap();
+                let mut checkpoint_file = File::open(get_db_file_path(
+                    &get_temp_test_current_path(),
+                    Some(db.version()),
+                    CHECKPOINT_FILE_NAME,
+                ))
+                .unwrap();
+                let loaded = SimpleDB::read_records_from_file(&mut checkpoint_file, None).unwrap();
+                assert_eq!(streamed.len(), 100);
+                assert_eq!(streamed, loaded);
+
+                let mut missing =
+                    SimpleDB::checkpoint_entries(&get_temp_test_current_path(), db.version() + 1);
+                assert!(matches!(missing.next(), Some(Err(DatabaseError::NotFound(_)))));
+                assert!(missing.next().is_none());
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_subscribe() {
+        run_test(
+            || {
+                let db = Arc::new(SimpleDB::open(get_temp_test_current_path()).unwrap());
+                let receiver = db.subscribe();
+                let dropped = db.subscribe();
+                drop(dropped);
+
+                let writer = Arc::clone(&db);
+                std::thread::spawn(move || {
+                    writer.put("name", "bob").unwrap();
+                    writer.delete("name").unwrap();
+                })
+                .join()
+                .unwrap();
+
+                assert_eq!(
+                    receiver.recv().unwrap(),
+                    LogOperation::Put("name".into(), "bob".into())
+                );
+                assert_eq!(receiver.recv().unwrap(), LogOperation::Delete("name".into()));
+                assert!(receiver.try_recv().is_err());
+                assert_eq!(db.subscribers.lock().unwrap().len(), 1);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_bulk_load() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("key0", "old").unwrap();
+                let items = (0..1000).map(|i| {
+                    (
+                        format!("key{}", i).into_bytes(),
+                        format!("value{}", i).into_bytes(),
+                    )
+                });
+
+                assert_eq!(db.bulk_load(items).unwrap(), 1000);
+                assert_eq!(db.version(), 1);
+                assert!(db.log_operations().unwrap().is_empty());
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.stats().num_records, 1000);
+                for i in 0..1000 {
+                    assert_eq!(db.get(format!("key{}", i)), Some(format!("value{}", i).into()));
+                }
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_already_open() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                check_file_exists_in_temp_test_folder(LOCK_FILE_NAME);
+                assert!(matches!(
+                    SimpleDB::open(get_temp_test_current_path()),
+                    Err(DatabaseError::AlreadyOpen)
+                ));
+                assert!(SimpleDB::open_read_only(get_temp_test_current_path()).is_ok());
+
+                drop(db);
+                check_file_exists_in_temp_test_folder(LOCK_FILE_NAME);
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert!(matches!(
+                    SimpleDB::open(get_temp_test_current_path()),
+                    Err(DatabaseError::AlreadyOpen)
+                ));
+                drop(db);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_snapshot() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.put("age", "42").unwrap();
+
+                let snapshot = db.snapshot();
+                db.put("name", "alice").unwrap();
+                db.delete("age").unwrap();
+                db.put("city", "paris").unwrap();
+
+                assert_eq!(snapshot.get("name"), Some("bob".into()));
+                assert_eq!(snapshot.get("age"), Some("42".into()));
+                assert!(!snapshot.contains_key("city"));
+
+                assert_eq!(db.get("name"), Some("alice".into()));
+                assert_eq!(db.get("age"), None);
+                assert_eq!(db.get("city"), Some("paris".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_to_map() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.put("age", "42").unwrap();
+                db.put("city", "paris").unwrap();
+                db.delete("age").unwrap();
+                db.put("name", "alice").unwrap();
+
+                let mut expected = HashMap::new();
+                expected.insert(b"name".to_vec(), b"alice".to_vec());
+                expected.insert(b"city".to_vec(), b"paris".to_vec());
+                let map = db.to_map();
+                assert_eq!(map, expected);
+
+                db.put("age", "43").unwrap();
+                assert_eq!(map, expected);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_checkpoint_format_version() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.commit().unwrap();
+                drop(db);
+
+                let checkpoint_path =
+                    get_db_file_path(&get_temp_test_current_path(), Some(1), CHECKPOINT_FILE_NAME);
+                let checkpoint = std::fs::read(&checkpoint_path).unwrap();
+                assert_eq!(&checkpoint[..4], CHECKPOINT_MAGIC);
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("name"), Some("bob".into()));
+                drop(db);
+
+                let mut headerless = vec![];
+                bytes::write_encoded_bytes_to_buffer(b"name", &mut headerless);
+                bytes::write_encoded_bytes_to_buffer(b"alice", &mut headerless);
+                std::fs::write(&checkpoint_path, &headerless).unwrap();
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("name"), Some("alice".into()));
+                drop(db);
+
+                let mut bumped = CHECKPOINT_MAGIC.to_vec();
+                bytes::write_u64_to_buffer(CHECKPOINT_FORMAT_VERSION + 1, &mut bumped);
+                bumped.extend(headerless);
+                std::fs::write(&checkpoint_path, &bumped).unwrap();
+                assert!(matches!(
+                    SimpleDB::open(get_temp_test_current_path()),
+                    Err(DatabaseError::UnsupportedFormat { found: 2, supported: 1 })
+                ));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_missing_checkpoint() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.commit().unwrap();
+                drop(db);
+
+                std::fs::remove_file(get_db_file_path(
+                    &get_temp_test_current_path(),
+                    Some(1),
+                    CHECKPOINT_FILE_NAME,
+                ))
+                .unwrap();
+                assert!(matches!(
+                    SimpleDB::open(get_temp_test_current_path()),
+                    Err(DatabaseError::MissingCheckpoint { version: 1 })
+                ));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_custom_file_layout() {
+        run_test(
+            || {
+                let layout = FileLayout::new()
+                    .checkpoint("data")
+                    .log("wal")
+                    .version("CURRENT");
+                let db = DbOptions::new()
+                    .file_layout(layout.clone())
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                check_file_exists_in_temp_test_folder("data.0");
+                check_file_exists_in_temp_test_folder("wal.0");
+                check_file_exists_in_temp_test_folder("CURRENT");
+                assert!(!get_temp_test_current_path().join("checkpoint.0").exists());
+
+                db.put("name", "bob").unwrap();
+                db.commit().unwrap();
+                check_file_exists_in_temp_test_folder("data.1");
+                assert!(!get_temp_test_current_path().join("data.0").exists());
+                drop(db);
+
+                let db = DbOptions::new()
+                    .file_layout(layout)
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                assert_eq!(db.get("name"), Some("bob".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_log_replay_error() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+                drop(db);
+
+                let mut invalid_entry = vec![];
+                bytes::write_encoded_char_to_buffer('x', &mut invalid_entry);
+                let mut log_file = std::fs::OpenOptions::new()
+                    .append(true)
+                    .open(get_db_file_path(&get_temp_test_current_path(), Some(0), LOG_FILE_NAME))
+                    .unwrap();
+                log_file.write_all(&invalid_entry).unwrap();
+                drop(log_file);
+
+                assert!(matches!(
+                    SimpleDB::open(get_temp_test_current_path()),
+                    Err(DatabaseError::LogReplay {
+                        entry_index: 1,
+                        source: LogError::InvalidOperation('x'),
+                    })
+                ));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_bloom_filter() {
+        run_test(
+            || {
+                let db = DbOptions::new()
+                    .bloom_false_positive_rate(Some(0.01))
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                for i in 0..500 {
+                    db.put(format!("key{}", i), "value").unwrap();
+                }
+                db.commit().unwrap();
+                for i in 500..1000 {
+                    db.put(format!("key{}", i), "value").unwrap();
+                }
+
+                for i in 0..1000 {
+                    assert!(db.may_contain(format!("key{}", i).as_bytes()));
+                    assert_eq!(db.get(format!("key{}", i)), Some("value".into()));
+                }
+                let false_positives = (0..10_000)
+                    .filter(|i| db.may_contain(format!("absent{}", i).as_bytes()))
+                    .count();
+                assert!(false_positives < 500, "{} false positives", false_positives);
+                assert_eq!(db.get("absent"), None);
+                drop(db);
+
+                let db = DbOptions::new()
+                    .bloom_false_positive_rate(Some(0.01))
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                assert!((0..1000).all(|i| db.contains_key(format!("key{}", i))));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_max_records() {
+        run_test(
+            || {
+                let open = || {
+                    DbOptions::new()
+                        .max_records(Some(10))
+                        .open(get_temp_test_current_path())
+                        .unwrap()
+                };
+                let db = open();
+                for i in 0..100 {
+                    db.put(format!("key{}", i), format!("value{}", i)).unwrap();
+                }
+                assert_eq!(db.records.read().unwrap().len(), 100);
+                db.commit().unwrap();
+                assert_eq!(db.records.read().unwrap().len(), 10);
+
+                for i in 0..100 {
+                    assert_eq!(db.get(format!("key{}", i)), Some(format!("value{}", i).into()));
+                }
+                assert_eq!(db.stats().num_records, 100);
+                assert_eq!(db.scan_prefix("key").len(), 100);
+                let mut keys: Vec<Vec<u8>> =
+                    (0..100).map(|i| format!("key{}", i).into_bytes()).collect();
+                keys.sort();
+                assert_eq!(db.keys_sorted(), keys);
+
+                db.put("key0", "updated").unwrap();
+                db.delete("key1").unwrap();
+                assert!(db.records.read().unwrap().len() <= 11);
+                db.commit().unwrap();
+                drop(db);
+
+                let db = open();
+                assert!(db.records.read().unwrap().len() <= 10);
+                assert_eq!(db.get("key0"), Some("updated".into()));
+                assert_eq!(db.get("key1"), None);
+                assert_eq!(db.to_map().len(), 99);
+                for i in 2..100 {
+                    assert_eq!(db.get(format!("key{}", i)), Some(format!("value{}", i).into()));
+                }
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_log_len_and_entry_count() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.log_len_bytes().unwrap(), 0);
+                assert_eq!(db.log_entry_count().unwrap(), 0);
+
+                db.put("a", "1").unwrap();
+                let len_after_one = db.log_len_bytes().unwrap();
+                assert!(len_after_one > 0);
+                assert_eq!(db.log_entry_count().unwrap(), 1);
+
+                db.put("b", "2").unwrap();
+                db.delete("a").unwrap();
+                assert!(db.log_len_bytes().unwrap() > len_after_one);
+                assert_eq!(db.log_entry_count().unwrap(), 3);
+
+                // Counting must leave the cursor at the end for later appends.
+                db.put("c", "3").unwrap();
+                assert_eq!(db.log_entry_count().unwrap(), 4);
+
+                db.commit().unwrap();
+                assert_eq!(db.log_len_bytes().unwrap(), 0);
+                assert_eq!(db.log_entry_count().unwrap(), 0);
+                assert_eq!(db.get("b"), Some("2".into()));
+                assert_eq!(db.get("c"), Some("3".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_swap() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("a", "1").unwrap();
+                db.put("b", "2").unwrap();
+                db.swap("a", "b").unwrap();
+                assert_eq!(db.get("a"), Some("2".into()));
+                assert_eq!(db.get("b"), Some("1".into()));
+
+                db.swap("a", "missing").unwrap();
+                assert_eq!(db.get("a"), None);
+                assert_eq!(db.get("missing"), Some("2".into()));
+
+                let entries = db.log_entry_count().unwrap();
+                db.swap("b", "b").unwrap();
+                db.swap("none1", "none2").unwrap();
+                assert_eq!(db.get("b"), Some("1".into()));
+                assert_eq!(db.log_entry_count().unwrap(), entries);
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("a"), None);
+                assert_eq!(db.get("b"), Some("1".into()));
+                assert_eq!(db.get("missing"), Some("2".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_clone_to() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("a", "1").unwrap();
+                db.commit().unwrap();
+                db.put("b", "2").unwrap();
+
+                let clone = db.clone_to(get_other_temp_test_path()).unwrap();
+                assert_eq!(clone.version(), 0);
+                assert_eq!(clone.log_entry_count().unwrap(), 0);
+                assert_eq!(clone.to_map(), db.to_map());
+                assert!(matches!(
+                    db.clone_to(get_other_temp_test_path()),
+                    Err(DatabaseError::Io(_))
+                ));
+
+                clone.put("a", "changed").unwrap();
+                clone.delete("b").unwrap();
+                clone.put("c", "3").unwrap();
+                clone.commit().unwrap();
+                assert_eq!(db.get("a"), Some("1".into()));
+                assert_eq!(db.get("b"), Some("2".into()));
+                assert_eq!(db.get("c"), None);
+                drop(clone);
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("a"), Some("1".into()));
+                assert_eq!(db.get("b"), Some("2".into()));
+                assert_eq!(db.get("c"), None);
+                let clone = SimpleDB::open(get_other_temp_test_path()).unwrap();
+                assert_eq!(clone.get("a"), Some("changed".into()));
+                assert_eq!(clone.get("b"), None);
+                assert_eq!(clone.get("c"), Some("3".into()));
+            },
+            None,
+            Some(Box::new(delete_all_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_sorted_listing() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                for key in ["pear", "apple", "b", "zebra", "a", "apples", "ab"] {
+                    db.put(key, key.to_uppercase()).unwrap();
+                }
+                db.put(vec![0xff], "high").unwrap();
+                db.put(vec![0x00], "low").unwrap();
+
+                let keys = db.keys_sorted();
+                let expected: Vec<Vec<u8>> = vec![
+                    vec![0x00],
+                    "a".into(),
+                    "ab".into(),
+                    "apple".into(),
+                    "apples".into(),
+                    "b".into(),
+                    "pear".into(),
+                    "zebra".into(),
+                    vec![0xff],
+                ];
+                assert_eq!(keys, expected);
+
+                let entries = db.entries_sorted();
+                assert_eq!(entries.len(), expected.len());
+                assert!(entries.windows(2).all(|pair| pair[0].0 < pair[1].0));
+                assert_eq!(entries[1], ("a".into(), "A".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_open_with_empty_files() {
+        run_test(
+            || {
+                let empty =
+                    SimpleDB::read_records_from_file(&mut std::io::Cursor::new(vec![]), None)
+                        .unwrap();
+                assert!(empty.is_empty());
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("a", "1").unwrap();
+                drop(db);
+
+                let path = get_temp_test_current_path();
+                for file_name in [CHECKPOINT_FILE_NAME, LOG_FILE_NAME] {
+                    File::create(get_db_file_path(&path, Some(0), file_name)).unwrap();
+                }
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.version(), 0);
+                assert!(db.to_map().is_empty());
+                assert_eq!(db.log_entry_count().unwrap(), 0);
+
+                db.put("b", "2").unwrap();
+                db.commit().unwrap();
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("a"), None);
+                assert_eq!(db.get("b"), Some("2".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_get_ref() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("key", "value").unwrap();
+                db.put(vec![0, 1, 2], vec![3]).unwrap();
+
+                assert_eq!(db.get_ref("key".as_bytes()), Some("value".into()));
+                assert_eq!(db.get_ref(&[0, 1, 2]), Some(vec![3]));
+                assert_eq!(db.get_ref(b"missing"), None);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_is_committing() {
+        run_test(
+            || {
+                let mut db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert!(!db.is_committing());
+                db.put("a", "1").unwrap();
+                db.commit().unwrap();
+                assert!(!db.is_committing());
+
+                let barrier = Arc::new(std::sync::Barrier::new(2));
+                db.set_pause_point(CrashPoint::AfterCheckpointWrite, Arc::clone(&barrier));
+                std::thread::scope(|scope| {
+                    let commit = scope.spawn(|| db.commit());
+                    barrier.wait();
+                    assert!(db.is_committing());
+                    let write = scope.spawn(|| db.put("b", "2"));
+                    barrier.wait();
+                    commit.join().unwrap().unwrap();
+                    write.join().unwrap().unwrap();
+                });
+                assert!(!db.is_committing());
+                assert_eq!(db.get("b"), Some("2".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_rollback_to_version() {
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                let db = DbOptions::new().retain_versions(2).open(path.clone()).unwrap();
+                for i in 1..=3 {
+                    db.put("a", i.to_string()).unwrap();
+                    db.put(format!("key{}", i), "x").unwrap();
+                    db.commit().unwrap();
+                }
+                assert_eq!(db.version(), 3);
+                assert!(!get_db_file_path(&path, Some(0), CHECKPOINT_FILE_NAME).exists());
+                assert!(get_db_file_path(&path, Some(1), CHECKPOINT_FILE_NAME).exists());
+                assert!(get_db_file_path(&path, Some(2), CHECKPOINT_FILE_NAME).exists());
+
+                db.put("uncommitted", "x").unwrap();
+                db.rollback_to_version(1).unwrap();
+                assert_eq!(db.version(), 4);
+                assert_eq!(db.get("a"), Some("1".into()));
+                assert_eq!(db.get("key1"), Some("x".into()));
+                assert_eq!(db.get("key2"), None);
+                assert_eq!(db.get("uncommitted"), None);
+                assert!(matches!(
+                    db.rollback_to_version(0),
+                    Err(DatabaseError::MissingCheckpoint { version: 0 })
+                ));
+                drop(db);
+
+                let db = SimpleDB::open(path).unwrap();
+                assert_eq!(db.to_map().len(), 2);
+                assert_eq!(db.get("a"), Some("1".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_reads_during_commit() {
+        run_test(
+            || {
+                let mut db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                let value = vec![b'v'; 100];
+                let items = (0..1000).map(|i| (format!("key{}", i).into_bytes(), value.clone()));
+                db.bulk_load(items).unwrap();
+                db.put("probe", "value").unwrap();
+                let barrier = Arc::new(std::sync::Barrier::new(2));
+                db.set_pause_point(CrashPoint::AfterCheckpointWrite, Arc::clone(&barrier));
+
+                std::thread::scope(|scope| {
+                    let commit = scope.spawn(|| db.commit());
+                    barrier.wait();
+                    assert!(db.is_committing());
+                    assert_eq!(db.get("probe"), Some("value".into()));
+                    assert_eq!(db.get("key999"), Some(value.clone()));
+                    barrier.wait();
+                    commit.join().unwrap().unwrap();
+                });
+
+                assert_eq!(db.version(), 2);
+                assert_eq!(db.stats().num_records, 1001);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_disk_usage() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("a", "1").unwrap();
+                db.commit().unwrap();
+                let usage = db.disk_usage().unwrap();
+                assert!(usage > 0);
+
+                db.put("b", vec![b'x'; 1000]).unwrap();
+                db.flush().unwrap();
+                assert!(db.disk_usage().unwrap() > usage + 1000);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_merge_from() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("shared", "ours").unwrap();
+                db.put("only_ours", "1").unwrap();
+                let other = SimpleDB::open(get_other_temp_test_path()).unwrap();
+                other.put("shared", "theirs").unwrap();
+                other.put("only_theirs", "2").unwrap();
+                other.put("deleted", "3").unwrap();
+                other.delete("deleted").unwrap();
+
+                assert_eq!(db.merge_from(&other).unwrap(), 2);
+                assert_eq!(
+                    db.keys_sorted(),
+                    vec![
+                        b"only_ours".to_vec(),
+                        b"only_theirs".to_vec(),
+                        b"shared".to_vec(),
+                    ]
+                );
+                assert_eq!(db.get("shared"), Some("theirs".into()));
+                assert_eq!(other.to_map().len(), 2);
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("shared"), Some("theirs".into()));
+                assert_eq!(db.get("only_theirs"), Some("2".into()));
+            },
+            None,
+            Some(Box::new(delete_all_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_log_segments() {
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                let open = || {
+                    DbOptions::new()
+                        .log_segment_bytes(Some(256))
+                        .open(path.clone())
+                        .unwrap()
+                };
+                let db = open();
+                let mut expected = vec![];
+                for i in 0..50 {
+                    let key = format!("key{}", i % 20).into_bytes();
+                    if i % 7 == 6 {
+                        db.delete(key.clone()).unwrap();
+                        expected.push(LogOperation::Delete(key));
+                    } else {
+                        let value = format!("value{}", i).into_bytes();
+                        db.put(key.clone(), value.clone()).unwrap();
+                        expected.push(LogOperation::Put(key, value));
+                    }
+                }
+
+                let log_path = get_db_file_path(&path, Some(0), LOG_FILE_NAME);
+                assert!(log::segment_path(&log_path, 1).exists());
+                assert!(log::segment_path(&log_path, 2).exists());
+                assert_eq!(db.log_operations().unwrap(), expected);
+                assert_eq!(db.log_entry_count().unwrap(), 50);
+                let state = db.to_map();
+                drop(db);
+
+                let db = open();
+                assert_eq!(db.to_map(), state);
+                assert_eq!(db.log_operations().unwrap(), expected);
+                db.compact_log().unwrap();
+                assert!(!log::segment_path(&log_path, 1).exists());
+                assert_eq!(db.to_map(), state);
+                db.put("after_reopen", "x").unwrap();
+                db.commit().unwrap();
+                assert!(!log_path.exists());
+                assert!(!log::segment_path(&log_path, 1).exists());
+                drop(db);
+
+                let db = open();
+                assert_eq!(db.to_map().len(), state.len() + 1);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_value_len() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("big", vec![7; 10_000]).unwrap();
+                db.put("empty", "").unwrap();
+
+                assert_eq!(db.value_len("big"), Some(10_000));
+                assert_eq!(db.value_len("empty"), Some(0));
+                assert_eq!(db.value_len("missing"), None);
+                db.delete("big").unwrap();
+                assert_eq!(db.value_len("big"), None);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_get_map() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("a", "1").unwrap();
+                db.put("b", "2").unwrap();
+                db.put("c", "3").unwrap();
+                db.delete("c").unwrap();
+
+                let map = db.get_map(vec!["a", "b", "c", "missing", "a"]);
+                let mut expected = HashMap::new();
+                expected.insert(b"a".to_vec(), b"1".to_vec());
+                expected.insert(b"b".to_vec(), b"2".to_vec());
+                assert_eq!(map, expected);
+                assert!(db.get_map(Vec::<&str>::new()).is_empty());
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_open_file_is_not_a_database() {
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                File::create(&path).unwrap();
+                assert!(matches!(
+                    SimpleDB::open(path.clone()),
+                    Err(DatabaseError::NotADatabase { path: error_path }) if error_path == path
+                ));
+                assert!(matches!(
+                    SimpleDB::open_read_only(path.clone()),
+                    Err(DatabaseError::NotADatabase { .. })
+                ));
+            },
+            None,
+            Some(Box::new(|| std::fs::remove_file(get_temp_test_current_path()).unwrap())),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_compact_on_open() {
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                let db = SimpleDB::open(path.clone()).unwrap();
+                for i in 0..1000 {
+                    db.put(format!("key{}", i % 100), format!("value{}", i)).unwrap();
+                }
+                drop(db);
+
+                let db = SimpleDB::open(path.clone()).unwrap();
+                assert_eq!(db.version(), 0);
+                assert_eq!(db.log_entry_count().unwrap(), 1000);
+                drop(db);
+
+                let open = || DbOptions::new().compact_on_open(true).open(path.clone()).unwrap();
+                let db = open();
+                assert_eq!(db.version(), 1);
+                assert_eq!(db.log_len_bytes().unwrap(), 0);
+                assert_eq!(db.get("key99"), Some("value999".into()));
+                drop(db);
+
+                let db = open();
+                assert_eq!(db.version(), 1);
+                assert_eq!(db.to_map().len(), 100);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_delete_prefix_dry_run() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                for i in 0..10 {
+                    db.put(format!("user:{}", i), "x").unwrap();
+                }
+                db.put("users", "x").unwrap();
+                db.put("other", "x").unwrap();
+                assert_eq!(db.count_prefix("user:"), 10);
+                assert_eq!(db.count_prefix("nothing"), 0);
+
+                let entries = db.log_entry_count().unwrap();
+                let would_delete = db.delete_prefix("user:", true).unwrap();
+                assert_eq!(would_delete, 10);
+                assert_eq!(db.log_entry_count().unwrap(), entries);
+                assert_eq!(db.count_prefix("user:"), 10);
+                assert_eq!(db.to_map().len(), 12);
+
+                assert_eq!(db.delete_prefix("user:", false).unwrap(), would_delete);
+                assert_eq!(db.count_prefix("user:"), 0);
+                assert_eq!(db.log_entry_count().unwrap(), entries + 10);
+                assert_eq!(db.get("users"), Some("x".into()));
+                assert_eq!(db.get("other"), Some("x".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_append_value() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("existing", "hello").unwrap();
+                db.append_value("existing", ", world").unwrap();
+                assert_eq!(db.get("existing"), Some("hello, world".into()));
+
+                db.append_value("missing", "first").unwrap();
+                assert_eq!(db.get("missing"), Some("first".into()));
+
+                for i in 0..5 {
+                    db.append_value("events", format!("{};", i)).unwrap();
+                }
+                db.delete("existing").unwrap();
+                db.append_value("existing", "again").unwrap();
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("events"), Some("0;1;2;3;4;".into()));
+                assert_eq!(db.get("existing"), Some("again".into()));
+                assert_eq!(db.get("missing"), Some("first".into()));
+
+                db.compact_log().unwrap();
+                db.append_value("events", "5;").unwrap();
+                db.commit().unwrap();
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("events"), Some("0;1;2;3;4;5;".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_metrics() {
+        #[derive(Default)]
+        struct CountingMetrics {
+            puts: AtomicU64,
+            hits: AtomicU64,
+            misses: AtomicU64,
+            commits: AtomicU64,
+            appended_bytes: AtomicU64,
+        }
+
+        impl Metrics for CountingMetrics {
+            fn on_put(&self) {
+                self.puts.fetch_add(1, Ordering::SeqCst);
+            }
+
+            fn on_get_hit(&self) {
+                self.hits.fetch_add(1, Ordering::SeqCst);
+            }
+
+            fn on_get_miss(&self) {
+                self.misses.fetch_add(1, Ordering::SeqCst);
+            }
+
+            fn on_commit(&self) {
+                self.commits.fetch_add(1, Ordering::SeqCst);
+            }
+
+            fn on_log_append(&self, bytes: u64) {
+                self.appended_bytes.fetch_add(bytes, Ordering::SeqCst);
+            }
+        }
+
+        run_test(
+            || {
+                let metrics = Arc::new(CountingMetrics::default());
+                let db = DbOptions::new()
+                    .metrics(metrics.clone())
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                db.put("a", "1").unwrap();
+                db.put("b", "2").unwrap();
+                db.put_with_ttl("c", "3", Duration::from_secs(60)).unwrap();
+                db.delete("b").unwrap();
+                assert_eq!(db.get("a"), Some("1".into()));
+                assert_eq!(db.get("c"), Some("3".into()));
+                assert_eq!(db.get("b"), None);
+                assert_eq!(db.get("missing"), None);
+                assert_eq!(
+                    metrics.appended_bytes.load(Ordering::SeqCst),
+                    db.log_len_bytes().unwrap()
+                );
+                db.commit().unwrap();
+                db.commit().unwrap();
+
+                assert_eq!(metrics.puts.load(Ordering::SeqCst), 3);
+                assert_eq!(metrics.hits.load(Ordering::SeqCst), 2);
+                assert_eq!(metrics.misses.load(Ordering::SeqCst), 2);
+                assert_eq!(metrics.commits.load(Ordering::SeqCst), 2);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_commit_returns_version() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                for expected in 1..=3 {
+                    db.put("key", expected.to_string()).unwrap();
+                    let version = db.commit().unwrap();
+                    assert_eq!(version, expected);
+                    assert_eq!(version, db.version());
+                }
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_empty_keys() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("", "allowed").unwrap();
+                assert_eq!(db.get(""), Some("allowed".into()));
+                drop(db);
+
+                let db = DbOptions::new()
+                    .reject_empty_keys(true)
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                let entries = db.log_entry_count().unwrap();
+                assert!(matches!(db.put("", "rejected"), Err(DatabaseError::InvalidKey { .. })));
+                assert!(matches!(
+                    db.bulk_load(vec![(vec![], b"rejected".to_vec())]),
+                    Err(DatabaseError::InvalidKey { .. })
+                ));
+                assert_eq!(db.log_entry_count().unwrap(), entries);
+                assert_eq!(db.get(""), Some("allowed".into()));
+                db.put("key", "value").unwrap();
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_retain() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                for i in 0..10 {
+                    db.put(format!("key{}", i), vec![b'x'; i]).unwrap();
+                }
+                db.commit().unwrap();
+
+                let removed = db.retain(|_, value| value.len() % 2 == 0).unwrap();
+                assert_eq!(removed, 5);
+                let expected: Vec<Vec<u8>> = (0..10)
+                    .step_by(2)
+                    .map(|i| format!("key{}", i).into_bytes())
+                    .collect();
+                assert_eq!(db.keys_sorted(), expected);
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.keys_sorted(), expected);
+                assert_eq!(db.get("key4"), Some(vec![b'x'; 4]));
+                assert_eq!(db.retain(|_, _| true).unwrap(), 0);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_clear() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("committed", "1").unwrap();
+                db.commit().unwrap();
+                db.put("before", "2").unwrap();
+                db.put_with_ttl("expiring", "3", Duration::from_secs(60)).unwrap();
+
+                db.clear().unwrap();
+                assert!(db.to_map().is_empty());
+                db.put("after", "4").unwrap();
+                db.append_value("before", "5").unwrap();
+                assert_eq!(db.to_map().len(), 2);
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("committed"), None);
+                assert_eq!(db.get("expiring"), None);
+                assert_eq!(db.get("after"), Some("4".into()));
+                assert_eq!(db.get("before"), Some("5".into()));
+                db.commit().unwrap();
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.to_map().len(), 2);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_background_compaction() {
+        run_test(
+            || {
+                let db = DbOptions::new()
+                    .compaction_threshold_bytes(64)
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                let db = Arc::new(db);
+                let handle = Arc::clone(&db).start_background_compaction(Duration::from_millis(5));
+
+                db.put("small", "1").unwrap();
+                std::thread::sleep(Duration::from_millis(50));
+                assert_eq!(db.version(), 0);
+
+                for i in 0..20 {
+                    db.put(format!("key{}", i), "value").unwrap();
+                }
+                let deadline = std::time::Instant::now() + Duration::from_secs(5);
+                while db.version() == 0 && std::time::Instant::now() < deadline {
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+                assert!(db.version() > 0);
+
+                db.stop_background_compaction();
+                handle.join().unwrap();
+                let version = db.version();
+                for i in 0..20 {
+                    db.put(format!("other{}", i), "value").unwrap();
+                }
+                std::thread::sleep(Duration::from_millis(50));
+                assert_eq!(db.version(), version);
+                assert_eq!(db.get("key19"), Some("value".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_on_replay() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("committed", "1").unwrap();
+                db.commit().unwrap();
+                db.put("a", "1").unwrap();
+                db.delete("a").unwrap();
+                db.append_value("b", "2").unwrap();
+                drop(db);
+
+                let replayed = Arc::new(Mutex::new(vec![]));
+                let hook_replayed = Arc::clone(&replayed);
+                let db = DbOptions::new()
+                    .on_replay(move |operation| {
+                        hook_replayed.lock().unwrap().push(operation.clone())
+                    })
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+
+                assert_eq!(
+                    *replayed.lock().unwrap(),
+                    vec![
+                        LogOperation::Put("a".into(), "1".into()),
+                        LogOperation::Delete("a".into()),
+                        LogOperation::Append("b".into(), "2".into()),
+                    ]
+                );
+                assert_eq!(db.get("committed"), Some("1".into()));
+                assert_eq!(db.get("b"), Some("2".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_log_redundancy() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                for i in 0..100 {
+                    db.put("key", format!("value{}", i)).unwrap();
+                }
+                db.put("other", "value").unwrap();
+                db.put("deleted", "value").unwrap();
+                db.delete("deleted").unwrap();
+
+                let redundancy = db.log_redundancy().unwrap();
+                assert_eq!(redundancy.total_entries, 103);
+                assert_eq!(redundancy.live_keys, 2);
+                let log_len_bytes = db.log_len_bytes().unwrap();
+                assert!(redundancy.reclaimable_bytes > log_len_bytes * 9 / 10);
+
+                db.compact_log().unwrap();
+                assert_eq!(
+                    db.log_len_bytes().unwrap(),
+                    log_len_bytes - redundancy.reclaimable_bytes
+                );
+                assert_eq!(db.log_redundancy().unwrap().reclaimable_bytes, 0);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_key_transform() {
+        run_test(
+            || {
+                let open = || {
+                    DbOptions::new()
+                        .key_transform(|key| key.to_ascii_lowercase())
+                        .open(get_temp_test_current_path())
+                        .unwrap()
+                };
+                let db = open();
+                db.put("Key", "value").unwrap();
+                assert_eq!(db.get("KEY"), Some("value".into()));
+                assert_eq!(db.get("key"), Some("value".into()));
+                assert!(db.contains_key("kEy"));
+                assert_eq!(db.keys_sorted(), vec![b"key".to_vec()]);
+
+                db.put("OTHER", "1").unwrap();
+                db.delete("Other").unwrap();
+                assert_eq!(db.get("other"), None);
+                drop(db);
+
+                let db = open();
+                assert_eq!(db.get("KEY"), Some("value".into()));
+                assert_eq!(db.to_map().len(), 1);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_last_commit_time() {
+        run_test(
+            || {
+                let before = SystemTime::now() - Duration::from_secs(1);
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("key", "value").unwrap();
+                db.commit().unwrap();
+                let committed_at = db.last_commit_time().unwrap();
+                assert!(committed_at >= before);
+                assert!(committed_at <= SystemTime::now());
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                let reopened_at = db.last_commit_time().unwrap();
+                let drift = committed_at
+                    .duration_since(reopened_at)
+                    .unwrap_or_else(|error| error.duration());
+                assert!(drift < Duration::from_millis(1));
+                drop(db);
+
+                let version_path = get_db_file_path(&get_temp_test_current_path(), None, "version");
+                std::fs::write(&version_path, "1").unwrap();
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.version(), 1);
+                assert_eq!(db.last_commit_time(), None);
+                assert_eq!(db.get("key"), Some("value".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_get_checked() {
+        run_test(
+            || {
+                let db = DbOptions::new()
+                    .checksum_values(true)
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                db.put("key", "value").unwrap();
+                db.put("other", "value").unwrap();
+                db.commit().unwrap();
+                db.append_value("other", "!").unwrap();
+                assert_eq!(db.get_checked("key").unwrap(), Some("value".into()));
+                assert_eq!(db.get_checked("other").unwrap(), Some("value!".into()));
+                assert_eq!(db.get_checked("missing").unwrap(), None);
+
+                {
+                    let mut records = db.records.write().unwrap();
+                    Arc::make_mut(&mut records).get_mut(b"key".as_ref()).unwrap()[0] ^= 1;
+                }
+                assert!(matches!(
+                    db.get_checked("key"),
+                    Err(DatabaseError::ChecksumMismatch { key }) if key == b"key"
+                ));
+                assert_eq!(db.get_checked("other").unwrap(), Some("value!".into()));
+
+                db.put("key", "fixed").unwrap();
+                assert_eq!(db.get_checked("key").unwrap(), Some("fixed".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_version_dirs() {
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                let open = || {
+                    DbOptions::new()
+                        .file_layout(FileLayout::new().version_dirs(true))
+                        .open(get_temp_test_current_path())
+                        .unwrap()
+                };
+                let db = open();
+                assert!(path.join("v0").join(CHECKPOINT_FILE_NAME).exists());
+                assert!(path.join("v0").join(LOG_FILE_NAME).exists());
+
+                db.put("key", "value").unwrap();
+                db.commit().unwrap();
+                assert!(path.join("v1").join(CHECKPOINT_FILE_NAME).exists());
+                assert!(path.join("v1").join(LOG_FILE_NAME).exists());
+                assert!(!path.join("v0").exists());
+                assert!(path.join("version").exists());
+                assert!(!get_db_file_path(&path, Some(1), CHECKPOINT_FILE_NAME).exists());
+                assert!(db.disk_usage().unwrap() > 0);
+
+                db.put("other", "value").unwrap();
+                drop(db);
+                let db = open();
+                assert_eq!(db.version(), 1);
+                assert_eq!(db.get("key"), Some("value".into()));
+                assert_eq!(db.get("other"), Some("value".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_recover_without_version_file() {
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                let db = SimpleDB::open(path.clone()).unwrap();
+                db.put("a", "1").unwrap();
+                db.commit().unwrap();
+                db.put("b", "2").unwrap();
+                db.commit().unwrap();
+                db.put("c", "3").unwrap();
+                drop(db);
+
+                std::fs::remove_file(path.join(VERSION_FILE_NAME)).unwrap();
+                // A stray checkpoint without a log is not a usable version.
+                File::create(get_db_file_path(&path, Some(7), CHECKPOINT_FILE_NAME)).unwrap();
+
+                let db = SimpleDB::open(path.clone()).unwrap();
+                assert_eq!(db.version(), 2);
+                assert_eq!(db.get("a"), Some("1".into()));
+                assert_eq!(db.get("b"), Some("2".into()));
+                assert_eq!(db.get("c"), Some("3".into()));
+                assert_eq!(db.last_commit_time(), None);
+                assert_eq!(get_version_from_file(), 2);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_lock_timeout() {
+        run_test(
+            || {
+                let db = DbOptions::new()
+                    .lock_timeout(Some(Duration::from_millis(20)))
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                let log = db.log.lock().unwrap();
+                std::thread::scope(|scope| {
+                    let result = scope.spawn(|| db.put("key", "value")).join().unwrap();
+                    assert!(matches!(
+                        result,
+                        Err(DatabaseError::Lock {
+                            kind: LockKind::Write,
+                            reason: Some(reason),
+                        }) if reason == "timeout"
+                    ));
+                });
+                drop(log);
+
+                db.put("key", "value").unwrap();
+                assert_eq!(db.get("key"), Some("value".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_recover_poisoned_locks() {
+        run_test(
+            || {
+                let poison = |db: &SimpleDB| {
+                    std::thread::scope(|scope| {
+                        let writer = scope.spawn(|| {
+                            let _records = db.records.write().unwrap();
+                            panic!("writer panicked holding the records lock");
+                        });
+                        assert!(writer.join().is_err());
+                    });
+                    assert!(db.records.is_poisoned());
+                };
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("key", "value").unwrap();
+                poison(&db);
+                assert!(matches!(db.put("other", "value"), Err(DatabaseError::Lock { .. })));
+                drop(db);
+
+                let db = DbOptions::new()
+                    .recover_poisoned_locks(true)
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                poison(&db);
+                assert_eq!(db.get("key"), Some("value".into()));
+                db.put("other", "value").unwrap();
+                db.delete("key").unwrap();
+                assert_eq!(db.get("other"), Some("value".into()));
+                assert_eq!(db.stats().num_records, 1);
+                db.commit().unwrap();
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_group_commit() {
+        run_test(
+            || {
+                let db = DbOptions::new()
+                    .sync_policy(SyncPolicy::EveryWrite)
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                let mut tickets = vec![];
+                for i in 0..100 {
+                    tickets.push(db.put_deferred(format!("key{}", i), "value").unwrap());
+                }
+                assert!(tickets.windows(2).all(|pair| pair[0] < pair[1]));
+
+                std::thread::scope(|scope| {
+                    let waiter = scope.spawn(|| db.wait_durable(*tickets.last().unwrap()));
+                    assert_eq!(db.group_commit().unwrap(), *tickets.last().unwrap());
+                    waiter.join().unwrap();
+                });
+                db.wait_durable(tickets[0]);
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                for i in 0..100 {
+                    assert_eq!(db.get(format!("key{}", i)), Some("value".into()));
+                }
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_list_versions() {
+        run_test(
+            || {
+                let db = DbOptions::new()
+                    .retain_versions(2)
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                assert_eq!(db.list_versions().unwrap(), vec![0]);
+
+                for i in 0..5 {
+                    db.put("key", format!("value{}", i)).unwrap();
+                    db.commit().unwrap();
+                }
+                assert_eq!(db.list_versions().unwrap(), vec![3, 4, 5]);
+                for version in 0..=5 {
+                    let on_disk = get_db_file_path(
+                        &get_temp_test_current_path(),
+                        Some(version),
+                        CHECKPOINT_FILE_NAME,
+                    )
+                    .exists();
+                    assert_eq!(on_disk, version >= 3);
+                }
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_put_moves_value_into_records() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                let value = vec![b'x'; 1 << 20];
+                let allocation = value.as_ptr();
+                db.put("large", value).unwrap();
+                assert_eq!(db.records.read().unwrap()[b"large".as_ref()].as_ptr(), allocation);
+
+                let receiver = db.subscribe();
+                db.put("other", vec![b'y'; 1 << 20]).unwrap();
+                assert_eq!(receiver.try_recv().unwrap().value(), Some(&[b'y'; 1 << 20][..]));
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("large"), Some(vec![b'x'; 1 << 20]));
+                assert_eq!(db.get("other"), Some(vec![b'y'; 1 << 20]));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_status() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("deleted", "1").unwrap();
+                db.put("present", "2").unwrap();
+                db.delete("deleted").unwrap();
+                assert_eq!(db.status("present"), KeyStatus::Present("2".into()));
+                assert_eq!(db.status("deleted"), KeyStatus::Deleted);
+                assert_eq!(db.status("never"), KeyStatus::Unknown);
+
+                db.put("deleted", "3").unwrap();
+                assert_eq!(db.status("deleted"), KeyStatus::Present("3".into()));
+                db.delete("deleted").unwrap();
+                db.delete("never_written").unwrap();
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.status("deleted"), KeyStatus::Deleted);
+                assert_eq!(db.status("never_written"), KeyStatus::Deleted);
+
+                db.commit().unwrap();
+                assert_eq!(db.status("deleted"), KeyStatus::Unknown);
+                assert_eq!(db.status("present"), KeyStatus::Present("2".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_first_and_last_key_value() {
+        run_test(
+            || {
+                let db = DbOptions::new()
+                    .max_records(Some(2))
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                assert_eq!(db.first_key_value(), None);
+                assert_eq!(db.last_key_value(), None);
+
+                for key in ["m", "c", "x", "a", "q"] {
+                    db.put(key, key.to_uppercase()).unwrap();
+                }
+                db.put_with_ttl("z", "Z", Duration::from_millis(1)).unwrap();
+                std::thread::sleep(Duration::from_millis(5));
+                assert_eq!(db.first_key_value(), Some(("a".into(), "A".into())));
+                assert_eq!(db.last_key_value(), Some(("x".into(), "X".into())));
+
+                // Once committed, the least recently written "x" is evicted
+                // from memory and read back.
+                db.commit().unwrap();
+                assert!(db.evicted_keys().unwrap().contains(&b"x".to_vec()));
+                assert_eq!(db.first_key_value(), Some(("a".into(), "A".into())));
+                assert_eq!(db.last_key_value(), Some(("x".into(), "X".into())));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_failed_commit_clears_commit_in_progress() {
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                let db = SimpleDB::open(path.clone()).unwrap();
+                db.put("key", "value").unwrap();
+
+                create_dir(get_db_file_path(&path, Some(1), CHECKPOINT_FILE_NAME)).unwrap();
+                assert!(db.commit().is_err());
+                assert!(!db.is_committing());
+                assert_eq!(db.version(), 0);
+                db.put("other", "value").unwrap();
+
+                std::fs::remove_dir_all(&path).unwrap();
+                assert!(matches!(db.commit(), Err(DatabaseError::NotFound(_))));
+                assert!(!db.is_committing());
+                assert_eq!(db.version(), 0);
+                assert_eq!(db.get("key"), Some("value".into()));
+            },
+            None,
+            Some(Box::new(|| {
+                let _ = std::fs::remove_dir_all(get_temp_test_current_path());
+            })),
+        )
+    }
+
+    /// XORs every byte with the key and the nonce, enough to hide the
+    /// plaintext from a search of the files.
+    struct XorCipher(u8);
+
+    impl Cipher for XorCipher {
+        fn encrypt(&self, nonce: u64, plaintext: &[u8]) -> Vec<u8> {
+            plaintext.iter().map(|byte| byte ^ self.0 ^ nonce as u8).collect()
+        }
+
+        fn decrypt(&self, nonce: u64, ciphertext: &[u8]) -> std::io::Result<Vec<u8>> {
+            Ok(self.encrypt(nonce, ciphertext))
+        }
+    }
+
+    fn files_contain(path: &Path, needle: &[u8]) -> bool {
+        std::fs::read_dir(path).unwrap().any(|entry| {
+            let contents = std::fs::read(entry.unwrap().path()).unwrap_or_default();
+            contents.windows(needle.len()).any(|window| window == needle)
+        })
+    }
+
+    #[test]
+    #[serial]
+    fn test_encryption() {
+        run_test(
+            || {
+                let open = || {
+                    DbOptions::new()
+                        .encryption(Arc::new(XorCipher(0x5a)))
+                        .open(get_temp_test_current_path())
+                        .unwrap()
+                };
+                let db = open();
+                db.put("name", "top secret").unwrap();
+                db.put("logged", "also secret").unwrap();
+                db.commit().unwrap();
+                db.put("pending", "still secret").unwrap();
+                assert_eq!(db.get("name"), Some("top secret".into()));
+                drop(db);
+
+                let path = get_temp_test_current_path();
+                assert!(files_contain(&path, b"pending"));
+                assert!(!files_contain(&path, b"secret"));
+
+                let db = open();
+                assert_eq!(db.get("name"), Some("top secret".into()));
+                assert_eq!(db.get("logged"), Some("also secret".into()));
+                assert_eq!(db.get("pending"), Some("still secret".into()));
+                drop(db);
+
+                assert!(SimpleDB::open(get_temp_test_current_path()).is_err());
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_operations_since() {
+        run_test(
+            || {
+                let db = DbOptions::new()
+                    .log_segment_bytes(Some(64))
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                db.put("name", "bob").unwrap();
+                db.put("age", "42").unwrap();
+                let offset = db.current_log_offset().unwrap();
+
+                db.put("city", "paris").unwrap();
+                db.delete("name").unwrap();
+                assert_eq!(
+                    db.operations_since(offset).unwrap(),
+                    vec![
+                        LogOperation::Put("city".into(), "paris".into()),
+                        LogOperation::Delete("name".into()),
+                    ]
+                );
+                assert_eq!(db.operations_since(0).unwrap().len(), 4);
+
+                let end = db.current_log_offset().unwrap();
+                assert!(db.operations_since(end).unwrap().is_empty());
+                db.put("next", "1").unwrap();
+                assert_eq!(db.operations_since(end).unwrap().len(), 1);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_purge_old_versions() {
+        run_test(
+            || {
+                let db = DbOptions::new()
+                    .retain_versions(3)
+                    .log_segment_bytes(Some(64))
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                for i in 0..4 {
+                    db.put("key", format!("value{}", i)).unwrap();
+                    db.put("other", format!("value{}", i)).unwrap();
+                    db.commit().unwrap();
+                }
+                assert_eq!(db.list_versions().unwrap(), vec![1, 2, 3, 4]);
+
+                assert!(db.purge_old_versions().unwrap() >= 6);
+                assert_eq!(db.list_versions().unwrap(), vec![4]);
+                let mut files: Vec<String> = std::fs::read_dir(get_temp_test_current_path())
+                    .unwrap()
+                    .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                    .collect();
+                files.sort();
+                assert_eq!(
+                    files,
+                    vec!["LOCK", "checkpoint.4", "index.4", "logfile.4", "version"]
+                );
+                assert_eq!(db.purge_old_versions().unwrap(), 0);
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("key"), Some("value3".into()));
+                drop(db);
+
+                let db = SimpleDB::open_read_only(get_temp_test_current_path()).unwrap();
+                assert_eq!(
+                    db.purge_old_versions().unwrap_err().kind(),
+                    std::io::ErrorKind::PermissionDenied
+                );
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_check_integrity() {
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                let db = SimpleDB::open(path.clone()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.commit().unwrap();
+                db.put("age", "42").unwrap();
+
+                let report = check_integrity(&path).unwrap();
+                assert!(report.is_healthy());
+                assert_eq!(report.version, Some(1));
+                drop(db);
+                assert!(check_integrity(&path.join("missing")).is_err());
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_check_integrity_anomalies() {
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                let db = SimpleDB::open(path.clone()).unwrap();
+                db.put("name", "bob").unwrap();
+                drop(db);
+
+                let log_path = get_db_file_path(&path, Some(0), LOG_FILE_NAME);
+                let mut log_file = OpenOptions::new().append(true).open(&log_path).unwrap();
+                log_file.write_all(&[0, 0, 0]).unwrap();
+                assert_eq!(
+                    check_integrity(&path).unwrap().anomalies,
+                    vec![Anomaly::PartialLogTail { valid_entries: 1 }]
+                );
+
+                std::fs::remove_file(get_db_file_path(&path, Some(0), CHECKPOINT_FILE_NAME))
+                    .unwrap();
+                std::fs::remove_file(&log_path).unwrap();
+                assert_eq!(
+                    check_integrity(&path).unwrap().anomalies,
+                    vec![
+                        Anomaly::MissingCheckpoint { version: 0 },
+                        Anomaly::MissingLog { version: 0 },
+                    ]
+                );
+
+                std::fs::write(get_db_file_path(&path, None, VERSION_FILE_NAME), "garbage")
+                    .unwrap();
+                let report = check_integrity(&path).unwrap();
+                assert_eq!(report.version, None);
+                assert_eq!(report.anomalies, vec![Anomaly::BadVersionFile]);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_open_in_memory() {
+        let current_dir = env::current_dir().unwrap();
+        let files_before = std::fs::read_dir(&current_dir).unwrap().count();
+
+        let db = SimpleDB::open_in_memory();
+        db.put("name", "bob").unwrap();
+        db.put_with_ttl("session", "abc", Duration::from_secs(60)).unwrap();
+        db.put("age", "42").unwrap();
+        db.delete("age").unwrap();
+        assert_eq!(db.get("name"), Some("bob".into()));
+        assert_eq!(db.get("age"), None);
+
+        assert_eq!(db.commit().unwrap(), 1);
+        assert_eq!(db.get("name"), Some("bob".into()));
+        assert_eq!(db.get("session"), Some("abc".into()));
+        assert_eq!(db.log_entry_count().unwrap(), 1);
+        db.put("name", "alice").unwrap();
+        db.compact_log().unwrap();
+        assert_eq!(db.get("name"), Some("alice".into()));
+        assert!(db.verify().unwrap().is_healthy());
+        assert_eq!(db.list_versions().unwrap(), Vec::<u64>::new());
+        assert_eq!(db.disk_usage().unwrap(), 0);
+        drop(db);
+
+        assert!(!get_temp_test_current_path().exists());
+        assert_eq!(std::fs::read_dir(&current_dir).unwrap().count(), files_before);
+    }
+
+    #[test]
+    #[serial]
+    fn test_contains_prefix() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert!(!db.contains_prefix(""));
+
+                db.put("user:1", "bob").unwrap();
+                db.put("order:1", "book").unwrap();
+                assert!(db.contains_prefix(""));
+                assert!(db.contains_prefix("user:"));
+                assert!(db.contains_prefix("order:1"));
+                assert!(!db.contains_prefix("item:"));
+                assert!(!db.contains_prefix("user:10"));
+
+                db.delete("user:1").unwrap();
+                assert!(!db.contains_prefix("user:"));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_max_replay_ops() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                for i in 0..5 {
+                    db.put(format!("key{}", i), "value").unwrap();
+                }
+                drop(db);
+
+                let result = DbOptions::new()
+                    .max_replay_ops(Some(4))
+                    .open(get_temp_test_current_path());
+                assert!(matches!(
+                    result,
+                    Err(DatabaseError::LogReplay {
+                        entry_index: 4,
+                        source: LogError::ReplayLimitExceeded { limit: 4 },
+                    })
+                ));
+
+                let db = DbOptions::new()
+                    .max_replay_ops(Some(5))
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                assert_eq!(db.get("key4"), Some("value".into()));
+                db.commit().unwrap();
+                drop(db);
+
+                let db = DbOptions::new()
+                    .max_replay_ops(Some(0))
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                assert_eq!(db.stats().num_records, 5);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_lookup_in_checkpoint() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                for i in 0..50 {
+                    db.put(format!("key{}", i), format!("value{}", i)).unwrap();
+                }
+                db.commit().unwrap();
+                db.put("pending", "value").unwrap();
+                assert_eq!(db.lookup_in_checkpoint("pending").unwrap(), None);
+
+                // With its header garbled the checkpoint no longer reads from
+                // the start, a lookup through the index never reads it.
+                let checkpoint_path =
+                    get_db_file_path(&get_temp_test_current_path(), Some(1), CHECKPOINT_FILE_NAME);
+                let mut checkpoint = std::fs::read(&checkpoint_path).unwrap();
+                checkpoint[..CHECKPOINT_MAGIC.len()].copy_from_slice(b"\xff\xff\xff\xff");
+                std::fs::write(&checkpoint_path, checkpoint).unwrap();
+
+                for i in 0..50 {
+                    assert_eq!(
+                        db.lookup_in_checkpoint(format!("key{}", i)).unwrap(),
+                        Some(format!("value{}", i).into_bytes())
+                    );
+                }
+                assert_eq!(db.lookup_in_checkpoint("missing").unwrap(), None);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_rename_key() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("old", "value").unwrap();
+                db.put("taken", "overwritten").unwrap();
+                db.put_with_ttl("session", "abc", Duration::from_secs(60)).unwrap();
+
+                assert!(db.rename_key("old", "new").unwrap());
+                assert_eq!(db.get("old"), None);
+                assert_eq!(db.get("new"), Some("value".into()));
+
+                assert!(!db.rename_key("missing", "other").unwrap());
+                assert_eq!(db.get("other"), None);
+
+                assert!(db.rename_key("new", "new").unwrap());
+                assert_eq!(db.get("new"), Some("value".into()));
+                assert!(!db.rename_key("missing", "missing").unwrap());
+
+                assert!(db.rename_key("new", "taken").unwrap());
+                assert!(db.rename_key("session", "renamed_session").unwrap());
+                assert!(db.expiries.read().unwrap().contains_key(b"renamed_session".as_ref()));
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("new"), None);
+                assert_eq!(db.get("taken"), Some("value".into()));
+                assert_eq!(db.get("session"), None);
+                assert_eq!(db.get("renamed_session"), Some("abc".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[serial]
+    fn test_commit_reuses_unchanged_checkpoint() {
+        use std::os::unix::fs::MetadataExt;
+
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                let inode = |version| {
+                    let checkpoint = get_db_file_path(&path, Some(version), CHECKPOINT_FILE_NAME);
+                    std::fs::metadata(checkpoint).unwrap().ino()
+                };
+                let db = DbOptions::new().retain_versions(3).open(path.clone()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.commit().unwrap();
+
+                db.commit().unwrap();
+                assert_eq!(inode(2), inode(1));
+                assert_eq!(db.lookup_in_checkpoint("name").unwrap(), Some("bob".into()));
+
+                db.put("age", "42").unwrap();
+                db.commit().unwrap();
+                assert_ne!(inode(3), inode(2));
+                drop(db);
+
+                let db = DbOptions::new().retain_versions(3).open(path.clone()).unwrap();
+                db.commit().unwrap();
+                assert_eq!(inode(4), inode(3));
+                assert_eq!(db.get("age"), Some("42".into()));
+                drop(db);
+
+                let db = SimpleDB::open(path.clone()).unwrap();
+                assert_eq!(db.version(), 4);
+                assert_eq!(db.get("name"), Some("bob".into()));
+                assert_eq!(db.get("age"), Some("42".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_entry() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.entry("count").unwrap().or_insert("1").unwrap(), b"1".to_vec());
+                assert_eq!(db.entry("count").unwrap().or_insert("5").unwrap(), b"1".to_vec());
+
+                let value = db
+                    .entry("count")
+                    .unwrap()
+                    .and_modify(|value| value.push(b'0'))
+                    .unwrap()
+                    .or_insert("0")
+                    .unwrap();
+                assert_eq!(value, b"10".to_vec());
+
+                let entry = db.entry("missing").unwrap().and_modify(|value| value.clear()).unwrap();
+                assert_eq!(entry.get(), None);
+                drop(entry);
+                assert_eq!(db.get("missing"), None);
+
+                db.put("temp", "x").unwrap();
+                assert_eq!(db.entry("temp").unwrap().remove().unwrap(), Some("x".into()));
+                assert_eq!(db.entry("temp").unwrap().remove().unwrap(), None);
+                assert_eq!(db.log_entry_count().unwrap(), 4);
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("count"), Some("10".into()));
+                assert_eq!(db.get("temp"), None);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_auto_commit_ops() {
+        run_test(
+            || {
+                let db = DbOptions::new()
+                    .auto_commit_ops(Some(5))
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                for i in 0..4 {
+                    db.put(format!("key{}", i), "value").unwrap();
+                    assert_eq!(db.version(), 0);
+                }
+                db.delete("key0").unwrap();
+                assert_eq!(db.version(), 1);
+
+                for i in 0..4 {
+                    db.put(format!("key{}", i), "value").unwrap();
+                }
+                drop(db);
+
+                let db = DbOptions::new()
+                    .auto_commit_ops(Some(5))
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                assert_eq!(db.version(), 1);
+                db.put("key4", "value").unwrap();
+                assert_eq!(db.version(), 2);
+                assert_eq!(db.log_entry_count().unwrap(), 0);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_sorted_checkpoints() {
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                let keys: Vec<String> = (0..100).map(|i| format!("key{}", i)).collect();
+                let reversed: Vec<String> = keys.iter().rev().cloned().collect();
+                let mut checkpoints = vec![];
+                for (name, keys) in [("a", keys), ("b", reversed)] {
+                    let db_path = path.join(name);
+                    let db = DbOptions::new()
+                        .sorted_checkpoints(true)
+                        .open(db_path.clone())
+                        .unwrap();
+                    for key in keys {
+                        db.put(key.as_str(), format!("value of {}", key)).unwrap();
+                    }
+                    db.commit().unwrap();
+                    let checkpoint = get_db_file_path(&db_path, Some(1), CHECKPOINT_FILE_NAME);
+                    checkpoints.push(std::fs::read(checkpoint).unwrap());
+                }
+                assert_eq!(checkpoints[0], checkpoints[1]);
+
+                let keys: Vec<Vec<u8>> = SimpleDB::checkpoint_entries(&path.join("a"), 1)
+                    .map(|entry| entry.unwrap().0)
+                    .collect();
+                let mut sorted = keys.clone();
+                sorted.sort();
+                assert_eq!(keys.len(), 100);
+                assert_eq!(keys, sorted);
+            },
+            Some(Box::new(|| std::fs::create_dir_all(get_temp_test_current_path()).unwrap())),
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_get_into() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("a", "first").unwrap();
+                db.put("b", "second value").unwrap();
+                db.put("empty", "").unwrap();
+
+                let mut buf = Vec::with_capacity(64);
+                let allocation = buf.as_ptr();
+                assert!(db.get_into("a", &mut buf));
+                assert_eq!(buf, b"first");
+                assert!(db.get_into("b", &mut buf));
+                assert_eq!(buf, b"second value");
+                assert!(db.get_into("empty", &mut buf));
+                assert!(buf.is_empty());
+                assert!(db.get_into("a", &mut buf));
+                assert!(!db.get_into("missing", &mut buf));
+                assert!(buf.is_empty());
+                assert_eq!(buf.as_ptr(), allocation);
+                assert_eq!(buf.capacity(), 64);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_watch_key() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+                let receiver = db.watch_key("name");
+                let dropped = db.watch_key("name");
+                drop(dropped);
+                assert_eq!(receiver.recv().unwrap(), Some("bob".into()));
+
+                db.put("other", "value").unwrap();
+                db.put("name", "alice").unwrap();
+                db.append_value("name", "!").unwrap();
+                db.delete("name").unwrap();
+                assert_eq!(receiver.recv().unwrap(), Some("alice".into()));
+                assert_eq!(receiver.recv().unwrap(), Some("alice!".into()));
+                assert_eq!(receiver.recv().unwrap(), None);
+                assert!(receiver.try_recv().is_err());
+                assert_eq!(db.key_watchers.lock().unwrap().len(), 1);
+
+                let missing = db.watch_key("missing");
+                assert_eq!(missing.recv().unwrap(), None);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_op_counters() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.put("age", "42").unwrap();
+                db.put_with_ttl("session", "abc", Duration::from_secs(60)).unwrap();
+                assert_eq!(db.get("name"), Some("bob".into()));
+                assert_eq!(db.get("age"), Some("42".into()));
+                assert_eq!(db.get("missing"), None);
+                db.delete("age").unwrap();
+                assert_eq!(db.get("age"), None);
+                db.commit().unwrap();
+
+                assert_eq!(
+                    db.op_counters(),
+                    OpCounters {
+                        get_hits: 2,
+                        get_misses: 2,
+                        puts: 3,
+                        deletes: 1,
+                        commits: 1,
+                        cleanup_failures: 0,
+                    }
+                );
+
+                db.reset_counters();
+                assert_eq!(db.op_counters(), OpCounters::default());
+                db.put("city", "paris").unwrap();
+                assert_eq!(db.op_counters().puts, 1);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_max_log_bytes() {
+        run_test(
+            || {
+                let options = DbOptions::new().max_log_bytes(Some(256));
+                let db = SimpleDB::open_with_options(get_temp_test_current_path(), options)
+                    .unwrap();
+                db.put("name", "bob").unwrap();
+                let log_bytes = db.log.lock().unwrap().size_bytes().unwrap();
+                assert_eq!(db.version(), 0);
+
+                let large = vec![7; 256 - log_bytes as usize - 32];
+                db.put("large", large.clone()).unwrap();
+                assert_eq!(db.version(), 1);
+                assert!(db.log.lock().unwrap().size_bytes().unwrap() <= 256);
+                assert_eq!(db.get("large"), Some(large.clone()));
+                db.put("small", "value").unwrap();
+                assert!(db.log.lock().unwrap().size_bytes().unwrap() <= 256);
+
+                assert!(matches!(
+                    db.put("huge", vec![0; 512]),
+                    Err(DatabaseError::LogFull { limit: 256, .. })
+                ));
+                assert_eq!(db.get("huge"), None);
+                drop(db);
+
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("name"), Some("bob".into()));
+                assert_eq!(db.get("large"), Some(large));
+                assert_eq!(db.get("small"), Some("value".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+
+    #[test]
+    #[serial]
+    fn test_open_version() {
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                let options = DbOptions::new().retain_versions(2);
+                let db = SimpleDB::open_with_options(path.clone(), options).unwrap();
+                db.put("name", "bob").unwrap();
+                db.commit().unwrap();
+                db.put("age", "42").unwrap();
+                db.commit().unwrap();
+                db.put("name", "alice").unwrap();
+                db.delete("age").unwrap();
+                db.commit().unwrap();
+
+                let first = SimpleDB::open_version(path.clone(), 1).unwrap();
+                assert_eq!(first.version(), 1);
+                assert_eq!(first.get("name"), Some("bob".into()));
+                assert_eq!(first.get("age"), Some("42".into()));
+                assert!(matches!(first.put("age", "43"), Err(DatabaseError::ReadOnly)));
+                let second = SimpleDB::open_version(path.clone(), 2).unwrap();
+                assert_eq!(second.get("name"), Some("alice".into()));
+                assert_eq!(second.get("age"), None);
+                assert_eq!(db.get("name"), Some("alice".into()));
+
+                db.commit().unwrap();
+                assert!(matches!(
+                    SimpleDB::open_version(path, 1),
+                    Err(DatabaseError::MissingCheckpoint { version: 1 })
+                ));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
 
-        file.write_all(&buffer)?;
-        file.sync_data()?;
+    #[test]
+    #[serial]
+    fn test_try_get() {
+        run_test(
+            || {
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put("key", "value").unwrap();
+                assert_eq!(db.try_get("key").unwrap(), Some("value".into()));
+                assert_eq!(db.try_get("missing").unwrap(), None);
 
-        Ok(())
+                std::thread::scope(|scope| {
+                    let writer = scope.spawn(|| {
+                        let _records = db.records.write().unwrap();
+                        panic!("writer panicked holding the records lock");
+                    });
+                    assert!(writer.join().is_err());
+                });
+                assert!(matches!(
+                    db.try_get("key"),
+                    Err(DatabaseError::Lock { kind: LockKind::Read, .. })
+                ));
+                assert_eq!(db.get("key"), None);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
     }
 
-    fn read_records_from_file(file: &mut File) -> Result<SimpleCollection, DatabaseError> {
-        let mut records: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+    #[test]
+    #[serial]
+    fn test_evicted_reads_check_key() {
+        run_test(
+            || {
+                let db = DbOptions::new()
+                    .max_records(Some(2))
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                for i in 0..10 {
+                    db.put(format!("key{}", i), format!("value{}", i)).unwrap();
+                }
+                db.commit().unwrap();
+                db.put("key9", "updated").unwrap();
+                db.commit().unwrap();
+                assert_eq!(db.records.read().unwrap().len(), 2);
+                assert_eq!(db.to_map().len(), 10);
+                assert_eq!(db.get("key0"), Some("value0".into()));
 
-        let file_length = file.metadata()?.len();
-        while file.stream_position()? < file_length {
-            let key_length = bytes::read_u64_from_log(file);
-            let key = bytes::read_bytes_from_log(file, key_length)?;
+                let offset = db
+                    .get_residency()
+                    .unwrap()
+                    .as_ref()
+                    .unwrap()
+                    .evicted_offset(b"key1")
+                    .unwrap();
+                let mut checkpoint = File::open(db.checkpoint_path(db.version())).unwrap();
+                let value = db.read_checkpoint_value(&mut checkpoint, b"key1", offset);
+                assert_eq!(value.unwrap(), b"value1");
+                let value = db.read_checkpoint_value(&mut checkpoint, b"key2", offset);
+                assert!(matches!(value, Err(DatabaseError::Io(_))));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
 
-            let value_length = bytes::read_u64_from_log(file);
-            let value = bytes::read_bytes_from_log(file, value_length)?;
+    #[test]
+    #[serial]
+    fn test_lock_timeout_before_logging() {
+        run_test(
+            || {
+                let db = DbOptions::new()
+                    .lock_timeout(Some(Duration::from_millis(10)))
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                {
+                    let _reader = db.records.read().unwrap();
+                    assert!(matches!(db.put("key", "value"), Err(DatabaseError::Lock { .. })));
+                }
+                assert_eq!(db.log_entry_count().unwrap(), 0);
+                assert_eq!(db.get("key"), None);
+                drop(db);
 
-            records.insert(key, value);
-        }
-        Ok(records)
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("key"), None);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
     }
 
-    fn get_write_records(
-        &self,
-    ) -> Result<std::sync::RwLockWriteGuard<SimpleCollection>, DatabaseError> {
-        if self.commit_in_progress {
-            return Err(DatabaseError::Lock {
-                kind: LockKind::Write,
-                reason: Some(String::from("Commit in progress")),
-            });
-        }
+    #[test]
+    #[serial]
+    fn test_open_truncates_torn_log_tail() {
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                let db = SimpleDB::open(path.clone()).unwrap();
+                db.put("name", "bob").unwrap();
+                drop(db);
 
-        match self.records.write() {
-            Ok(records) => Ok(records),
-            Err(_) => {
-                return Err(DatabaseError::Lock {
-                    kind: LockKind::Write,
-                    reason: None,
-                })
-            }
-        }
-    }
+                let log_path = get_db_file_path(&path, Some(0), LOG_FILE_NAME);
+                let mut log_file = OpenOptions::new().append(true).open(&log_path).unwrap();
+                log_file.write_all(&[0, 0, 0, 0, 0, 0, 0, 9, b'p', 0]).unwrap();
+                drop(log_file);
 
-    fn cleanup_previous_commit_files(&self) -> std::io::Result<()> {
-        std::fs::remove_file(get_db_file_path(
-            &self.path,
-            Some(self.version),
-            LOG_FILE_NAME,
-        ))?;
-        std::fs::remove_file(get_db_file_path(
-            &self.path,
-            Some(self.version),
-            CHECKPOINT_FILE_NAME,
-        ))?;
-        std::fs::remove_file(get_db_file_path(&self.path, None, VERSION_FILE_NAME))?;
+                let db = SimpleDB::open_read_only(path.clone()).unwrap();
+                assert_eq!(db.get("name"), Some("bob".into()));
+                drop(db);
+                assert!(!check_integrity(&path).unwrap().is_healthy());
 
-        let old_version_file_path = get_db_file_path(&self.path, None, VERSION_FILE_NAME);
-        let new_version_file_path = get_db_file_path(&self.path, None, NEW_VERSION_FILE_NAME);
-        std::fs::rename(new_version_file_path, old_version_file_path)?;
+                let db = SimpleDB::open(path.clone()).unwrap();
+                db.put("age", "42").unwrap();
+                drop(db);
+                assert!(check_integrity(&path).unwrap().is_healthy());
 
-        Ok(())
+                let db = SimpleDB::open(path).unwrap();
+                assert_eq!(db.get("name"), Some("bob".into()));
+                assert_eq!(db.get("age"), Some("42".into()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
     }
-}
 
-fn create_version_file(path: &Path, version: u64, new: bool) -> std::io::Result<()> {
-    let file_name = if new {
-        NEW_VERSION_FILE_NAME
-    } else {
-        VERSION_FILE_NAME
-    };
-    let file_path = format!("{}/{}", path.to_str().unwrap(), file_name);
-    let mut file = File::create(file_path)?;
-    let version_string = format!("{}", version);
-    file.write_all(version_string.as_bytes())?;
+    #[test]
+    #[serial]
+    fn test_crash_before_compacted_log_install() {
+        run_test(
+            || {
+                let options = DbOptions::new().log_segment_bytes(Some(64));
+                let mut db = options.clone().open(get_temp_test_current_path()).unwrap();
+                for _ in 0..8 {
+                    db.append_value("name", "bob-").unwrap();
+                }
+                let log_path =
+                    get_db_file_path(&get_temp_test_current_path(), Some(0), LOG_FILE_NAME);
+                assert!(log::segment_path(&log_path, 1).exists());
 
-    Ok(())
-}
+                db.set_crash_point(Some(CrashPoint::BeforeCompactedLogInstall));
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    db.compact_log()
+                }));
+                assert!(result.is_err());
+                db.release_lock();
+                std::mem::forget(db);
 
-fn create_db_file(path: &Path, version: u64, file_name: &str) -> std::io::Result<File> {
-    let file_path = get_db_file_path(&path, Some(version), file_name);
-    File::create(file_path)
-}
+                let expected: Vec<u8> = b"bob-".repeat(8);
+                let db = options.clone().read_only(true).open(get_temp_test_current_path());
+                assert_eq!(db.unwrap().get("name"), Some(expected.clone()));
 
-fn get_db_file_path(path: &Path, version: Option<u64>, file_name: &str) -> PathBuf {
-    match version {
-        Some(n) => PathBuf::from(format!("{}/{}.{}", path.to_str().unwrap(), file_name, n)),
-        None => PathBuf::from(format!("{}/{}", path.to_str().unwrap(), file_name,)),
+                let db = options.open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("name"), Some(expected.clone()));
+                assert!(!log::segment_path(&log_path, 1).exists());
+                drop(db);
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.get("name"), Some(expected));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
     }
-}
-
-fn read_string_from_file(path: &Path) -> std::io::Result<String> {
-    let mut file = File::open(path)?;
-    let mut string = String::new();
-    file.read_to_string(&mut string)?;
-
-    Ok(string)
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::test_utils::run_test;
-    use serial_test::serial;
-    use std::env;
-    use std::fs::remove_dir_all;
-    use std::io::Read;
-    use std::sync::Mutex;
+    #[test]
+    #[serial]
+    fn test_rollback_to_version_keeps_ttls() {
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                let db = DbOptions::new().retain_versions(2).open(path.clone()).unwrap();
+                db.put_with_ttl("session", "abc", Duration::from_secs(3600)).unwrap();
+                db.put("a", "1").unwrap();
+                db.commit().unwrap();
+                db.put("a", "2").unwrap();
+                db.put("session", "def").unwrap();
+                db.commit().unwrap();
 
-    fn get_temp_test_current_path() -> PathBuf {
-        env::current_dir()
-            .and_then(|mut p| {
-                p.push("_temp_test");
-                Ok(p)
-            })
-            .unwrap()
-    }
+                db.rollback_to_version(1).unwrap();
+                assert_eq!(db.get("a"), Some("1".into()));
+                assert_eq!(db.get("session"), Some("abc".into()));
+                assert!(db.expiries.read().unwrap().contains_key(b"session".as_ref()));
+                drop(db);
 
-    fn delete_db_files() {
-        let temp_test_path = get_temp_test_current_path();
-        remove_dir_all(temp_test_path).unwrap();
+                let db = SimpleDB::open(path).unwrap();
+                assert_eq!(db.get("session"), Some("abc".into()));
+                assert!(db.expiries.read().unwrap().contains_key(b"session".as_ref()));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
     }
 
-    fn _check_file_exists_in_path(base_path: PathBuf, file_name: &str) {
-        let mut path = base_path.clone();
-        path.push(file_name);
-        assert!(path.exists());
-    }
+    #[test]
+    #[serial]
+    fn test_failed_rollback_keeps_records() {
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                let mut db = DbOptions::new().retain_versions(2).open(path).unwrap();
+                db.put("a", "1").unwrap();
+                db.commit().unwrap();
+                db.put("a", "2").unwrap();
 
-    fn check_file_exists_in_temp_test_folder(file_name: &str) {
-        let temp_test_path = get_temp_test_current_path();
-        _check_file_exists_in_path(temp_test_path, file_name);
+                db.set_crash_point(Some(CrashPoint::AfterCheckpointWrite));
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    db.rollback_to_version(0)
+                }));
+                assert!(result.is_err());
+                assert_eq!(db.version(), 1);
+                assert_eq!(db.get("a"), Some("2".into()));
+                db.release_lock();
+                std::mem::forget(db);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
     }
 
-    fn get_version_from_file() -> u64 {
-        let mut current_path = get_temp_test_current_path();
-        current_path.push("version");
-        let mut version_file = File::open(current_path).unwrap();
+    #[test]
+    #[serial]
+    fn test_key_transform_applied_once() {
+        run_test(
+            || {
+                let db = DbOptions::new()
+                    .key_transform(|key| [b"ns:".as_ref(), key].concat())
+                    .max_value_bytes(Some(4))
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                db.put("a", "1").unwrap();
+                assert_eq!(db.status("a"), KeyStatus::Present("1".into()));
+                assert_eq!(db.get_checked("a").unwrap(), Some("1".into()));
+                assert_eq!(db.get_or_insert_with("a", || "2".into()).unwrap(), b"1");
+                let updated = db.update("a", |value| value.map(|value| [value, b"2"].concat()));
+                assert_eq!(updated.unwrap(), Some("12".into()));
+                db.append_value("a", "34").unwrap();
+                assert!(matches!(
+                    db.append_value("a", "5"),
+                    Err(DatabaseError::ValueTooLarge { limit: 4, actual: 5 })
+                ));
 
-        let mut version = String::new();
-        version_file.read_to_string(&mut version).unwrap();
+                db.swap("a", "b").unwrap();
+                assert_eq!(db.get("b"), Some("1234".into()));
+                assert!(db.rename_key("b", "c").unwrap());
+                assert_eq!(db.get("c"), Some("1234".into()));
+                assert_eq!(db.keys_sorted(), vec![b"ns:c".to_vec()]);
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
 
-        version.parse::<u64>().unwrap()
+    #[test]
+    #[serial]
+    fn test_invalid_bloom_false_positive_rate() {
+        run_test(
+            || {
+                for rate in [0.0, 1.0, 2.0, -0.5, f64::NAN] {
+                    let opened = DbOptions::new()
+                        .bloom_false_positive_rate(Some(rate))
+                        .open(get_temp_test_current_path());
+                    assert!(matches!(opened, Err(DatabaseError::InvalidOption { .. })));
+                }
+                assert!(!get_temp_test_current_path().exists());
+            },
+            None,
+            None,
+        )
     }
 
     #[test]
     #[serial]
-    fn test_create_db() {
+    fn test_poisoned_side_locks() {
         run_test(
             || {
-                SimpleDB::open(get_temp_test_current_path()).unwrap();
+                let poison = |db: &SimpleDB| {
+                    std::thread::scope(|scope| {
+                        let writer = scope.spawn(|| {
+                            let _bloom = db.bloom.write().unwrap();
+                            panic!("writer panicked holding the bloom lock");
+                        });
+                        assert!(writer.join().is_err());
+                    });
+                };
+                let options = DbOptions::new().bloom_false_positive_rate(Some(0.01));
 
-                check_file_exists_in_temp_test_folder("checkpoint.0");
-                check_file_exists_in_temp_test_folder("logfile.0");
-                check_file_exists_in_temp_test_folder("version");
+                let db = options.clone().open(get_temp_test_current_path()).unwrap();
+                db.put("key", "value").unwrap();
+                poison(&db);
+                let put = db.put("other", "value");
+                assert!(matches!(
+                    put,
+                    Err(DatabaseError::Lock { reason: Some(reason), .. })
+                        if reason.contains("bloom")
+                ));
+                assert!(matches!(db.commit(), Err(DatabaseError::Lock { .. })));
+                assert_eq!(db.get("key"), Some("value".into()));
+                assert_eq!(db.log_entry_count().unwrap(), 1);
 
-                assert_eq!(get_version_from_file(), 0);
+                std::thread::scope(|scope| {
+                    let writer = scope.spawn(|| {
+                        let _records = db.records.write().unwrap();
+                        panic!("writer panicked holding the records lock");
+                    });
+                    assert!(writer.join().is_err());
+                });
+                assert!(db.to_map().is_empty());
+                assert!(matches!(db.get_checked("key"), Err(DatabaseError::Lock { .. })));
+                drop(db);
+
+                let db = options
+                    .recover_poisoned_locks(true)
+                    .open(get_temp_test_current_path())
+                    .unwrap();
+                poison(&db);
+                db.put("other", "value").unwrap();
+                db.commit().unwrap();
+                assert_eq!(db.to_map().len(), 2);
             },
             None,
             Some(Box::new(delete_db_files)),
@@ -337,19 +7187,16 @@ mod tests {
 
     #[test]
     #[serial]
-    fn test_basic_db_operations() {
+    fn test_put_with_huge_ttl() {
         run_test(
             || {
+                let clock = Arc::new(ManualClock(AtomicU64::new(1_000)));
                 let mut db = SimpleDB::open(get_temp_test_current_path()).unwrap();
-                db.put("name", "ran").unwrap();
-                let name = db.get("name").unwrap();
-
-                assert_eq!(String::from_utf8(name.to_vec()).unwrap(), "ran");
+                db.clock = clock.clone();
 
-                db.put("name", "bob").unwrap();
-                let name = db.get("name").unwrap();
-                assert_eq!(String::from_utf8(name.to_vec()).unwrap(), "bob");
-                assert_eq!(db.version(), 0);
+                db.put_with_ttl("session", "abc", Duration::MAX).unwrap();
+                clock.0.store(u64::MAX - 1, Ordering::SeqCst);
+                assert_eq!(db.get("session").unwrap(), b"abc");
             },
             None,
             Some(Box::new(delete_db_files)),
@@ -358,35 +7205,29 @@ mod tests {
 
     #[test]
     #[serial]
-    fn test_concurrent_write() {
+    fn test_failed_bulk_load_keeps_records() {
         run_test(
             || {
-                let db = Arc::new(Mutex::new(
-                    SimpleDB::open(get_temp_test_current_path()).unwrap(),
-                ));
-                let mut handles = vec![];
-                for _ in 0..2 {
-                    let t_db = Arc::clone(&db);
-                    let handle = std::thread::spawn(move || {
-                        let mut db = t_db.lock().unwrap();
-                        if let None = db.get("name") {
-                            db.put("name", "bob").unwrap();
-                        } else {
-                            db.put("age", "54").unwrap();
-                        }
-                    });
+                let mut db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                db.put_with_ttl("a", "1", Duration::from_secs(3600)).unwrap();
+                db.put("b", "1").unwrap();
 
-                    handles.push(handle);
-                }
-                for handle in handles {
-                    handle.join().unwrap();
-                }
-                let db: &SimpleDB = &*db.lock().unwrap();
-                let name = db.get("name").unwrap();
-                let age = db.get("age").unwrap();
+                db.set_crash_point(Some(CrashPoint::AfterCheckpointWrite));
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    db.bulk_load(vec![(b"a".to_vec(), b"2".to_vec())])
+                }));
+                assert!(result.is_err());
+                assert_eq!(db.version(), 0);
+                assert_eq!(db.get("a"), Some("1".into()));
+                assert!(db.read_expiries().unwrap().contains_key(&b"a".to_vec()));
+                db.release_lock();
+                std::mem::forget(db);
 
-                assert_eq!(String::from_utf8(name.to_vec()).unwrap(), "bob");
-                assert_eq!(String::from_utf8(age.to_vec()).unwrap(), "54");
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(db.bulk_load(vec![(b"a".to_vec(), b"2".to_vec())]).unwrap(), 1);
+                assert_eq!(db.get("a"), Some("2".into()));
+                assert_eq!(db.get("b"), Some("1".into()));
+                assert!(!db.read_expiries().unwrap().contains_key(&b"a".to_vec()));
             },
             None,
             Some(Box::new(delete_db_files)),
@@ -395,27 +7236,62 @@ mod tests {
 
     #[test]
     #[serial]
-    fn test_commit_changes() {
+    fn test_clone_to_keeps_ttls() {
         run_test(
             || {
+                let now = SystemClock.now_millis();
+                let clock = Arc::new(ManualClock(AtomicU64::new(now)));
                 let mut db = SimpleDB::open(get_temp_test_current_path()).unwrap();
-                db.put("name", "bob").unwrap();
-                db.put("age", "54").unwrap();
-
-                db.commit().unwrap();
+                db.clock = clock.clone();
+                db.put_with_ttl("session", "abc", Duration::from_secs(3600)).unwrap();
+                db.put("a", "1").unwrap();
 
-                let name = db.get("name").unwrap();
-                let age = db.get("age").unwrap();
+                let mut clone = db.clone_to(get_other_temp_test_path()).unwrap();
+                clone.clock = clock.clone();
+                assert_eq!(clone.get("session"), Some("abc".into()));
+                drop(clone);
 
-                assert_eq!(String::from_utf8(name.to_vec()).unwrap(), "bob");
-                assert_eq!(String::from_utf8(age.to_vec()).unwrap(), "54");
+                let mut clone = SimpleDB::open(get_other_temp_test_path()).unwrap();
+                let expiries = clone.read_expiries().unwrap().clone();
+                assert_eq!(expiries.get(b"session".as_ref()), Some(&(now + 3_600_000)));
+                clone.clock = clock.clone();
+                clock.0.store(now + 3_600_000, Ordering::SeqCst);
+                assert_eq!(clone.get("session"), None);
+                assert_eq!(clone.get("a"), Some("1".into()));
+            },
+            None,
+            Some(Box::new(delete_all_db_files)),
+        )
+    }
 
-                check_file_exists_in_temp_test_folder("checkpoint.1");
-                check_file_exists_in_temp_test_folder("logfile.1");
-                check_file_exists_in_temp_test_folder("version");
+    #[test]
+    #[serial]
+    fn test_open_version_with_options() {
+        run_test(
+            || {
+                let path = get_temp_test_current_path();
+                let options = || {
+                    DbOptions::new()
+                        .retain_versions(2)
+                        .encryption(Arc::new(XorCipher(0x5a)))
+                        .file_layout(FileLayout::new().version_dirs(true))
+                        .key_transform(|key| [b"ns:".as_ref(), key].concat())
+                };
+                let db = options().open(path.clone()).unwrap();
+                db.put("name", "bob").unwrap();
+                db.commit().unwrap();
+                db.commit().unwrap();
+                db.put("name", "alice").unwrap();
 
-                assert_eq!(get_version_from_file(), 1);
-                assert_eq!(db.version(), 1);
+                let first = options().open_version(path.clone(), 1).unwrap();
+                assert_eq!(first.get("name"), Some("bob".into()));
+                assert_eq!(db.get("name"), Some("alice".into()));
+                assert!(matches!(first.put("name", "eve"), Err(DatabaseError::ReadOnly)));
+                assert!(SimpleDB::open_version(path.clone(), 1).is_err());
+                assert!(matches!(
+                    options().bloom_false_positive_rate(Some(2.0)).open_version(path, 1),
+                    Err(DatabaseError::InvalidOption { .. })
+                ));
             },
             None,
             Some(Box::new(delete_db_files)),
@@ -424,22 +7300,35 @@ mod tests {
 
     #[test]
     #[serial]
-    fn test_load_from_checkpoint_after_commit() {
+    fn test_rollback_with_poisoned_lock_moves_to_new_version() {
         run_test(
             || {
-                let mut db = SimpleDB::open(get_temp_test_current_path()).unwrap();
-                db.put("name", "bob").unwrap();
-                db.put("age", "54").unwrap();
-                db.delete("age").unwrap();
-
+                let path = get_temp_test_current_path();
+                let options = DbOptions::new()
+                    .retain_versions(2)
+                    .bloom_false_positive_rate(Some(0.01));
+                let db = options.clone().open(path.clone()).unwrap();
+                db.put("a", "1").unwrap();
+                db.commit().unwrap();
+                db.put("a", "2").unwrap();
                 db.commit().unwrap();
-                
-                drop(db);
 
-                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                std::thread::scope(|scope| {
+                    let writer = scope.spawn(|| {
+                        let _bloom = db.bloom.write().unwrap();
+                        panic!("writer panicked holding the bloom lock");
+                    });
+                    assert!(writer.join().is_err());
+                });
+                db.rollback_to_version(1).unwrap();
+                assert_eq!(db.version(), 3);
+                db.put("after", "x").unwrap();
+                drop(db);
 
-                assert_eq!(String::from_utf8(db.get("name").unwrap().to_vec()).unwrap(), "bob");
-                assert_eq!(db.version(), 1);
+                let db = options.open(path).unwrap();
+                assert_eq!(db.version(), 3);
+                assert_eq!(db.get("a"), Some("1".into()));
+                assert_eq!(db.get("after"), Some("x".into()));
             },
             None,
             Some(Box::new(delete_db_files)),
@@ -448,20 +7337,32 @@ mod tests {
 
     #[test]
     #[serial]
-    fn test_load_from_checkpoint_before_commit() {
+    fn test_compact_log_with_corrupted_entry() {
         run_test(
             || {
-                let mut db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
                 db.put("name", "bob").unwrap();
+                let log_path =
+                    get_db_file_path(&get_temp_test_current_path(), Some(0), LOG_FILE_NAME);
+                let first_entry_len = std::fs::metadata(&log_path).unwrap().len();
                 db.put("age", "54").unwrap();
-                db.delete("age").unwrap();
-                
-                drop(db);
+                db.put("city", "paris").unwrap();
 
-                let db = SimpleDB::open(get_temp_test_current_path()).unwrap();
+                // Replaces the operation of the second entry, after its
+                // header length.
+                let mut log_file =
+                    std::fs::OpenOptions::new().write(true).open(&log_path).unwrap();
+                log_file.seek(SeekFrom::Start(first_entry_len + 8)).unwrap();
+                log_file.write_all(b"x").unwrap();
+                drop(log_file);
+                let corrupted = std::fs::read(&log_path).unwrap();
 
-                assert_eq!(String::from_utf8(db.get("name").unwrap().to_vec()).unwrap(), "bob");
-                assert_eq!(db.version(), 0);
+                assert!(matches!(
+                    db.compact_log(),
+                    Err(DatabaseError::Other(error))
+                        if matches!(error.downcast_ref(), Some(LogError::InvalidOperation('x')))
+                ));
+                assert_eq!(std::fs::read(&log_path).unwrap(), corrupted);
             },
             None,
             Some(Box::new(delete_db_files)),
@@ -0,0 +1,96 @@
+use crate::db::SimpleDB;
+use crate::error::DatabaseError;
+use crate::log::{Log, LogFile, LogOperation};
+
+use std::sync::MutexGuard;
+
+/// A key looked up for a conditional change, see `SimpleDB::entry`.
+///
+/// The entry holds the log lock until it is consumed or dropped, so no other
+/// write lands between looking the key up and changing it. Writing to the
+/// same database through anything but the entry meanwhile deadlocks. Every
+/// change is logged before the method making it returns.
+pub struct Entry<'a> {
+    db: &'a SimpleDB,
+    key: Vec<u8>,
+    value: Option<Vec<u8>>,
+    log: MutexGuard<'a, Log<LogFile>>,
+}
+
+impl<'a> Entry<'a> {
+    pub(crate) fn new(db: &'a SimpleDB, key: Vec<u8>, log: MutexGuard<'a, Log<LogFile>>) -> Self {
+        let value = db.get(key.as_slice());
+        Entry {
+            db,
+            key,
+            value,
+            log,
+        }
+    }
+
+    pub fn key(&self) -> &[u8] {
+        &self.key
+    }
+
+    pub fn get(&self) -> Option<&[u8]> {
+        self.value.as_deref()
+    }
+
+    /// Returns the value of the key, storing `default` first if there is
+    /// none.
+    pub fn or_insert<V: Into<Vec<u8>>>(self, default: V) -> Result<Vec<u8>, DatabaseError> {
+        self.or_insert_with(|| default.into())
+    }
+
+    /// Like `or_insert`, but only computes the value when it is stored.
+    pub fn or_insert_with<F>(mut self, f: F) -> Result<Vec<u8>, DatabaseError>
+    where
+        F: FnOnce() -> Vec<u8>,
+    {
+        if let Some(value) = self.value.take() {
+            return Ok(value);
+        }
+
+        let value = f();
+        self.write(LogOperation::Put(self.key.clone(), value.clone()))?;
+        self.finish()?;
+        Ok(value)
+    }
+
+    /// Changes the value of the key in place with `f` if there is one. The
+    /// change is only logged when the value actually changes.
+    pub fn and_modify<F>(mut self, f: F) -> Result<Self, DatabaseError>
+    where
+        F: FnOnce(&mut Vec<u8>),
+    {
+        if let Some(current) = self.value.as_ref() {
+            let mut modified = current.clone();
+            f(&mut modified);
+            if &modified != current {
+                self.write(LogOperation::Put(self.key.clone(), modified.clone()))?;
+                self.value = Some(modified);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Deletes the key, returning the value it held.
+    pub fn remove(mut self) -> Result<Option<Vec<u8>>, DatabaseError> {
+        let value = self.value.take();
+        if value.is_some() {
+            self.write(LogOperation::Delete(self.key.clone()))?;
+        }
+        self.finish()?;
+        Ok(value)
+    }
+
+    fn write(&mut self, operation: LogOperation) -> Result<(), DatabaseError> {
+        self.db.log_and_apply(&mut self.log, operation)
+    }
+
+    /// Releases the log lock, committing if the writes grew the log past
+    /// `DbOptions::auto_commit_bytes`.
+    fn finish(self) -> Result<(), DatabaseError> {
+        self.db.auto_commit_if_needed(self.log)
+    }
+}
@@ -3,50 +3,116 @@ use thiserror::Error;
 #[derive(Debug)]
 pub enum LockKind {
     Read,
-    Write
+    Write,
 }
 
 #[derive(Error, Debug)]
 pub enum DatabaseError {
-    
     #[error("Database creation failed")]
     Initialization,
 
     #[error("Unable to acquire lock: {kind:?}, reason: {reason:?}")]
     Lock {
         kind: LockKind,
-        reason: Option<String>
+        reason: Option<String>,
     },
-    
 
     #[error("Database files not found on disk")]
-    NotFound(#[from] std::io::Error),
+    NotFound(#[source] std::io::Error),
+
+    #[error("IO error while accessing database files")]
+    Io(#[source] std::io::Error),
 
     #[error("Key: {0} doesn't exist in the Databse")]
     KeyNotFound(String),
 
-
     #[error("Failed to load records from checkpoint")]
     LoadCheckpoint,
 
+    #[error("Failed to replay log entry {entry_index}")]
+    LogReplay {
+        entry_index: usize,
+        source: LogError,
+    },
+
+    #[error("Checkpoint file for version {version} is missing")]
+    MissingCheckpoint { version: u64 },
+
+    #[error("Unsupported checkpoint format version {found}, supported up to {supported}")]
+    UnsupportedFormat { found: u64, supported: u64 },
+
+    #[error("Database was opened in read-only mode")]
+    ReadOnly,
+
+    #[error("{path:?} is not a database directory")]
+    NotADatabase { path: std::path::PathBuf },
+
+    #[error("Database is already open by another handle")]
+    AlreadyOpen,
+
+    #[error("Invalid key: {reason}")]
+    InvalidKey { reason: String },
+
+    #[error("Invalid option: {reason}")]
+    InvalidOption { reason: String },
+
+    #[error("Value of key {key:?} doesn't match its checksum")]
+    ChecksumMismatch { key: Vec<u8> },
+
+    #[error("Value of {actual} bytes exceeds the limit of {limit} bytes")]
+    ValueTooLarge { limit: usize, actual: usize },
+
+    #[error("Log entry of {entry_bytes} bytes doesn't fit in the log cap of {limit} bytes")]
+    LogFull { limit: u64, entry_bytes: u64 },
+
+    #[cfg(feature = "serde")]
+    #[error("Failed to encode or decode a typed value")]
+    Encoding(#[from] bincode::Error),
+
     #[error(transparent)]
-    Other(#[from] anyhow::Error)
+    Other(#[from] anyhow::Error),
 }
 
 #[derive(Error, Debug)]
 pub enum LogError {
-
     #[error("End of log reached")]
     EndReached,
 
     #[error("Invalid log operation: {0}")]
     InvalidOperation(char),
 
-    #[error("Failed to perform IO operations on the log")]
-    Io(#[from] std::io::Error)
+    #[error("Unsupported log entry format, header length: {0}")]
+    UnsupportedEntryFormat(u64),
+
+    #[error("Log entry declares {length} bytes, over the limit of {limit} bytes")]
+    EntryTooLarge { length: u64, limit: u64 },
+
+    #[error("Log entry declares {expected} bytes but only {available} remain in the log")]
+    TruncatedEntry { expected: u64, available: u64 },
+
+    #[error("Log holds more than {limit} entries, commit or compact it to replay it")]
+    ReplayLimitExceeded { limit: usize },
+
+    #[error("Failed to perform IO operations on the log ({kind:?}): {0}", kind = .0.kind())]
+    Io(#[from] std::io::Error),
+}
+
+impl From<std::io::Error> for DatabaseError {
+    fn from(error: std::io::Error) -> Self {
+        match error.kind() {
+            std::io::ErrorKind::NotFound => DatabaseError::NotFound(error),
+            _ => DatabaseError::Io(error),
+        }
+    }
 }
 
 impl From<LogError> for DatabaseError {
     fn from(error: LogError) -> Self {
-        DatabaseError::Other(anyhow::Error::new(error))
-    }}
\ No newline at end of file
+        match error {
+            // Keeps the kind of the IO error visible to callers matching on
+            // `DatabaseError`.
+            LogError::Io(error) => DatabaseError::from(error),
+            error => DatabaseError::Other(anyhow::Error::new(error)),
+        }
+    }
+}
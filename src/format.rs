@@ -0,0 +1,158 @@
+//! The on-disk encoding of simpledb files, for tools that read or write
+//! them directly.
+//!
+//! Every number is a big-endian `u64`. Byte strings are written as their
+//! length followed by the bytes. A checkpoint is the `CHECKPOINT_MAGIC`
+//! header followed by records, each one the key and then the value as byte
+//! strings. A value whose length has the top bit set was compressed by
+//! simpledb, `read_record` decompresses it.
+
+use crate::bytes;
+
+use std::io::{Read, Write};
+
+pub use crate::db::{CHECKPOINT_FORMAT_VERSION, CHECKPOINT_MAGIC};
+
+/// The longest byte string the readers accept. A longer length prefix is an
+/// `InvalidData` error rather than an allocation of that size.
+pub const MAX_LENGTH: u64 = bytes::DEFAULT_MAX_ENTRY_BYTES;
+
+fn check_length(length: u64) -> std::io::Result<()> {
+    if length > MAX_LENGTH {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Length of {} bytes is over the limit of {}", length, MAX_LENGTH),
+        ));
+    }
+    Ok(())
+}
+
+pub fn write_u64<W: Write>(writer: &mut W, n: u64) -> std::io::Result<()> {
+    writer.write_all(&n.to_be_bytes())
+}
+
+pub fn read_u64<R: Read>(reader: &mut R) -> std::io::Result<u64> {
+    bytes::read_u64_from_log(reader)
+}
+
+/// Writes `bytes` prefixed with their length.
+pub fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> std::io::Result<()> {
+    write_u64(writer, bytes.len() as u64)?;
+    writer.write_all(bytes)
+}
+
+/// Reads a byte string written by `write_bytes`. A length over
+/// `MAX_LENGTH` is an `InvalidData` error.
+pub fn read_bytes<R: Read>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let length = read_u64(reader)?;
+    check_length(length)?;
+    bytes::read_bytes_from_log(reader, length)
+}
+
+/// Writes the checkpoint header, which goes before the first record.
+pub fn write_checkpoint_header<W: Write>(writer: &mut W) -> std::io::Result<()> {
+    writer.write_all(CHECKPOINT_MAGIC)?;
+    write_u64(writer, CHECKPOINT_FORMAT_VERSION)
+}
+
+/// Writes a checkpoint record, the value is stored uncompressed.
+pub fn write_record<W: Write>(writer: &mut W, key: &[u8], value: &[u8]) -> std::io::Result<()> {
+    write_bytes(writer, key)?;
+    write_bytes(writer, value)
+}
+
+/// Reads a record written by `write_record`, or by simpledb itself.
+/// Returns `None` at the end of the input, and an `UnexpectedEof` error if
+/// the input ends partway through a record. Keys or values longer than
+/// `MAX_LENGTH` are an `InvalidData` error.
+pub fn read_record<R: Read>(reader: &mut R) -> std::io::Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let mut key_length = [0; bytes::U64_BYTES_LEN];
+    let mut filled = 0;
+    while filled < key_length.len() {
+        match reader.read(&mut key_length[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            Ok(read) => filled += read,
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+
+    let key_length = u64::from_be_bytes(key_length);
+    check_length(key_length)?;
+    let key = bytes::read_bytes_from_log(reader, key_length)?;
+    let value_length = read_u64(reader)?;
+    check_length(bytes::value_len(value_length))?;
+    let value = bytes::read_value_with_length(reader, value_length, None)?;
+    Ok(Some((key, value)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_record_round_trip() {
+        let records: Vec<(Vec<u8>, Vec<u8>)> = vec![
+            (b"key".to_vec(), b"value".to_vec()),
+            (vec![], b"empty key".to_vec()),
+            (b"empty value".to_vec(), vec![]),
+            (vec![], vec![]),
+        ];
+
+        let mut buffer = vec![];
+        for (key, value) in records.iter() {
+            write_record(&mut buffer, key, value).unwrap();
+        }
+
+        let mut reader = Cursor::new(buffer);
+        for record in records.into_iter() {
+            assert_eq!(read_record(&mut reader).unwrap(), Some(record));
+        }
+        assert_eq!(read_record(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn test_truncated_record() {
+        let mut buffer = vec![];
+        write_record(&mut buffer, b"key", b"value").unwrap();
+        for length in [3, 8, 12, buffer.len() - 1] {
+            let error = read_record(&mut Cursor::new(&buffer[..length])).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[test]
+    fn test_oversized_lengths() {
+        let mut oversized_bytes = vec![];
+        write_u64(&mut oversized_bytes, MAX_LENGTH + 1).unwrap();
+        let error = read_bytes(&mut Cursor::new(&oversized_bytes)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        let mut oversized_key = vec![];
+        write_u64(&mut oversized_key, u64::MAX).unwrap();
+        let error = read_record(&mut Cursor::new(oversized_key)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        let mut oversized_value = vec![];
+        write_bytes(&mut oversized_value, b"key").unwrap();
+        write_u64(&mut oversized_value, MAX_LENGTH + 1).unwrap();
+        let error = read_record(&mut Cursor::new(oversized_value)).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_primitives_round_trip() {
+        let mut buffer = vec![];
+        write_u64(&mut buffer, 42).unwrap();
+        write_bytes(&mut buffer, b"").unwrap();
+        write_bytes(&mut buffer, b"bytes").unwrap();
+
+        let mut reader = Cursor::new(buffer);
+        assert_eq!(read_u64(&mut reader).unwrap(), 42);
+        assert_eq!(read_bytes(&mut reader).unwrap(), b"");
+        assert_eq!(read_bytes(&mut reader).unwrap(), b"bytes");
+        assert!(read_u64(&mut reader).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use crate::bytes;
+
+use std::cmp::Ordering;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Sidecar file mapping every key of a checkpoint to the offset of its
+/// record, so a single key can be found without reading the checkpoint.
+///
+/// Entries are sorted by key, each one the key as a byte string followed by
+/// the offset. They are followed by the position of every entry and then
+/// the number of entries, which lets `lookup` binary search the file.
+pub(crate) fn write_index<W: Write>(
+    writer: &mut W,
+    entries: &mut [(Vec<u8>, u64)],
+) -> std::io::Result<()> {
+    entries.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut buffer = vec![];
+    let mut positions = Vec::with_capacity(entries.len());
+    for (key, offset) in entries.iter() {
+        positions.push(buffer.len() as u64);
+        bytes::write_encoded_bytes_to_buffer(key, &mut buffer);
+        bytes::write_u64_to_buffer(*offset, &mut buffer);
+    }
+    for position in positions.iter() {
+        bytes::write_u64_to_buffer(*position, &mut buffer);
+    }
+    bytes::write_u64_to_buffer(positions.len() as u64, &mut buffer);
+    writer.write_all(&buffer)
+}
+
+/// Offset of the record of `key` in the checkpoint `reader` is the index
+/// of, found by reading a logarithmic number of entries.
+pub(crate) fn lookup<R: Read + Seek>(reader: &mut R, key: &[u8]) -> std::io::Result<Option<u64>> {
+    let length = reader.seek(SeekFrom::End(0))?;
+    let table_end = length.checked_sub(bytes::U64_BYTES_LEN as u64).ok_or_else(corrupt)?;
+    reader.seek(SeekFrom::Start(table_end))?;
+    let count = bytes::read_u64_from_log(reader)?;
+    let table_start = count
+        .checked_mul(bytes::U64_BYTES_LEN as u64)
+        .and_then(|table_length| table_end.checked_sub(table_length))
+        .ok_or_else(corrupt)?;
+
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        reader.seek(SeekFrom::Start(table_start + middle * bytes::U64_BYTES_LEN as u64))?;
+        let position = bytes::read_u64_from_log(reader)?;
+        reader.seek(SeekFrom::Start(position))?;
+        let key_length = bytes::read_u64_from_log(reader)?;
+        if key_length > table_start {
+            return Err(corrupt());
+        }
+        let entry_key = bytes::read_bytes_from_log(reader, key_length)?;
+        match entry_key.as_slice().cmp(key) {
+            Ordering::Less => low = middle + 1,
+            Ordering::Greater => high = middle,
+            Ordering::Equal => return Ok(Some(bytes::read_u64_from_log(reader)?)),
+        }
+    }
+    Ok(None)
+}
+
+fn corrupt() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "Corrupt checkpoint index")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_index_lookup() {
+        let mut entries: Vec<(Vec<u8>, u64)> = (0..100u64)
+            .map(|i| (format!("key{}", i).into_bytes(), i * 10))
+            .collect();
+        let mut index = Cursor::new(vec![]);
+        write_index(&mut index, &mut entries).unwrap();
+
+        for i in 0..100u64 {
+            let key = format!("key{}", i);
+            assert_eq!(lookup(&mut index, key.as_bytes()).unwrap(), Some(i * 10));
+        }
+        assert_eq!(lookup(&mut index, b"key").unwrap(), None);
+        assert_eq!(lookup(&mut index, b"missing").unwrap(), None);
+
+        let mut empty = Cursor::new(vec![]);
+        write_index(&mut empty, &mut []).unwrap();
+        assert_eq!(lookup(&mut empty, b"key0").unwrap(), None);
+        assert!(lookup(&mut Cursor::new(vec![0; 4]), b"key0").is_err());
+    }
+}
@@ -0,0 +1,30 @@
+//! simpledb, a key-value store kept in memory and persisted as checkpoints
+//! plus a write-ahead log.
+
+pub mod cipher;
+pub mod clock;
+pub mod collection;
+pub mod compression;
+pub mod db;
+pub mod entry;
+pub mod error;
+pub mod format;
+pub mod log;
+pub mod metrics;
+pub mod options;
+pub mod snapshot;
+#[cfg(feature = "serde")]
+pub mod typed;
+#[cfg(feature = "async")]
+pub mod async_db;
+
+mod bloom;
+mod bytes;
+mod index;
+mod residency;
+#[cfg(test)]
+mod test_utils;
+
+pub use crate::db::SimpleDB;
+pub use crate::error::DatabaseError;
+pub use crate::options::DbOptions;
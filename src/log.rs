@@ -1,136 +1,1487 @@
 use crate::error::LogError;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt::Debug;
 use std::fs::{File, OpenOptions};
 use std::io::SeekFrom;
-use std::io::{Read, Seek, Write};
-use std::path::Path;
+use std::io::{Cursor, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use crate::bytes::{
+    DEFAULT_MAX_ENTRY_BYTES,
     U64_BYTES_LEN,
     read_bytes_from_log,
+    read_value_with_length,
     read_u64_from_log
 };
 
 use crate::bytes;
+use crate::clock::{Clock, SystemClock};
+use crate::cipher::Cipher;
+use crate::compression::Compression;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum LogOperation {
     Put(Vec<u8>, Vec<u8>),
+    /// A put that expires at the given time, in milliseconds since the unix
+    /// epoch.
+    PutWithExpiry(Vec<u8>, Vec<u8>, u64),
     Delete(Vec<u8>),
+    /// Appends the bytes to the key's value, a missing key is treated as
+    /// holding an empty value.
+    Append(Vec<u8>, Vec<u8>),
+    /// Deletes every key, including the ones in the checkpoint.
+    Clear,
 }
 
+impl LogOperation {
+    /// The key the operation applies to, empty for `Clear`.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            LogOperation::Put(key, _) => key,
+            LogOperation::PutWithExpiry(key, _, _) => key,
+            LogOperation::Delete(key) => key,
+            LogOperation::Append(key, _) => key,
+            LogOperation::Clear => &[],
+        }
+    }
+
+    pub fn value(&self) -> Option<&[u8]> {
+        match self {
+            LogOperation::Put(_, value) => Some(value),
+            LogOperation::PutWithExpiry(_, value, _) => Some(value),
+            LogOperation::Delete(_) => None,
+            LogOperation::Append(_, suffix) => Some(suffix),
+            LogOperation::Clear => None,
+        }
+    }
+}
+
+/// A `LogOperation` borrowing its key and value, so they can be logged
+/// without copying them first.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LogOpRef<'a> {
+    Put(&'a [u8], &'a [u8]),
+    PutWithExpiry(&'a [u8], &'a [u8], u64),
+    Delete(&'a [u8]),
+    Append(&'a [u8], &'a [u8]),
+    Clear,
+}
+
+impl LogOperation {
+    pub fn borrowed(&self) -> LogOpRef<'_> {
+        match self {
+            LogOperation::Put(key, value) => LogOpRef::Put(key, value),
+            LogOperation::PutWithExpiry(key, value, expires_at) => {
+                LogOpRef::PutWithExpiry(key, value, *expires_at)
+            }
+            LogOperation::Delete(key) => LogOpRef::Delete(key),
+            LogOperation::Append(key, suffix) => LogOpRef::Append(key, suffix),
+            LogOperation::Clear => LogOpRef::Clear,
+        }
+    }
+}
+
+/// A decoded log entry along with the time it was appended, in milliseconds
+/// since the unix epoch. Entries written before timestamps were recorded
+/// have no timestamp.
+#[derive(Debug, PartialEq, Clone)]
+pub struct LogRecord {
+    pub operation: LogOperation,
+    pub timestamp: Option<u64>,
+}
+
+/// Collapses a sequence of operations into its net effect, keeping only the
+/// last operation for every key, in the order those operations were logged.
+/// Appends are folded into the operation they follow, and a `Clear` drops
+/// every operation before it.
+pub fn compact_operations(operations: Vec<LogOperation>) -> Vec<LogOperation> {
+    let mut net: HashMap<Vec<u8>, (usize, LogOperation)> = HashMap::new();
+    let mut cleared = false;
+    for (index, op) in operations.into_iter().enumerate() {
+        if op == LogOperation::Clear {
+            net.clear();
+            cleared = true;
+            continue;
+        }
+        let key = op.key().to_vec();
+        let op = match (net.remove(&key), op) {
+            (Some((_, previous)), LogOperation::Append(key, suffix)) => {
+                fold_append(previous, key, suffix)
+            }
+            (_, op) => op,
+        };
+        net.insert(key, (index, op));
+    }
+
+    let mut compacted: Vec<(usize, LogOperation)> = net.into_values().collect();
+    compacted.sort_unstable_by_key(|(index, _)| *index);
+    let clear = if cleared { Some(LogOperation::Clear) } else { None };
+    clear
+        .into_iter()
+        .chain(compacted.into_iter().map(|(_, op)| op))
+        .collect()
+}
+
+/// The single operation with the effect of `previous` followed by appending
+/// `suffix` to `key`.
+fn fold_append(previous: LogOperation, key: Vec<u8>, suffix: Vec<u8>) -> LogOperation {
+    match previous {
+        LogOperation::Put(_, mut value) => {
+            value.extend(suffix);
+            LogOperation::Put(key, value)
+        }
+        LogOperation::PutWithExpiry(_, mut value, expires_at) => {
+            value.extend(suffix);
+            LogOperation::PutWithExpiry(key, value, expires_at)
+        }
+        LogOperation::Delete(_) | LogOperation::Clear => LogOperation::Put(key, suffix),
+        LogOperation::Append(_, mut appended) => {
+            appended.extend(suffix);
+            LogOperation::Append(key, appended)
+        }
+    }
+}
+
+/// Controls how often `append_to_disk` forces the log to stable storage.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum SyncPolicy {
+    /// `sync_data` after every appended operation.
+    #[default]
+    EveryWrite,
+    /// `sync_data` once every N appended operations.
+    EveryN(usize),
+    /// Never sync on append, leave it to the OS (or an explicit sync/commit).
+    Never,
+}
+
+/// A log handle that can be flushed to stable storage.
+pub trait SyncData {
+    fn sync_data(&self) -> std::io::Result<()>;
+}
+
+impl SyncData for File {
+    fn sync_data(&self) -> std::io::Result<()> {
+        File::sync_data(self)
+    }
+}
+
+/// Handle of a database log, a file or, for databases opened with
+/// `SimpleDB::open_in_memory`, a buffer that is never persisted.
+#[derive(Debug)]
+pub enum LogFile {
+    Disk(File),
+    Memory(Cursor<Vec<u8>>),
+}
+
+impl Read for LogFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            LogFile::Disk(file) => file.read(buf),
+            LogFile::Memory(cursor) => cursor.read(buf),
+        }
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            LogFile::Disk(file) => file.write(buf),
+            LogFile::Memory(cursor) => cursor.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            LogFile::Disk(file) => file.flush(),
+            LogFile::Memory(cursor) => cursor.flush(),
+        }
+    }
+}
+
+impl Seek for LogFile {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            LogFile::Disk(file) => file.seek(pos),
+            LogFile::Memory(cursor) => cursor.seek(pos),
+        }
+    }
+}
+
+impl LogFile {
+    fn set_len(&mut self, length: u64) -> std::io::Result<()> {
+        match self {
+            LogFile::Disk(file) => file.set_len(length),
+            LogFile::Memory(cursor) => {
+                cursor.get_mut().truncate(length as usize);
+                Ok(())
+            }
+        }
+    }
+}
 
+impl SyncData for LogFile {
+    fn sync_data(&self) -> std::io::Result<()> {
+        match self {
+            LogFile::Disk(file) => file.sync_data(),
+            LogFile::Memory(_) => Ok(()),
+        }
+    }
+}
+
+/// Outcome of decoding a whole log, see `Log::scan`.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct LogScan {
+    pub valid_entries: usize,
+    pub invalid_entries: usize,
+    /// Whether the log ends in an entry cut short by the end of the file.
+    pub partial_tail: bool,
+}
+
+/// Every entry starts with a length-prefixed header, the length tells the
+/// entry format apart: a bare operation char in the original format, or an
+/// operation char followed by the append timestamp.
+const LEGACY_HEADER_LEN: u64 = 1;
+const TIMESTAMPED_HEADER_LEN: u64 = 1 + U64_BYTES_LEN as u64;
+
+/// Appended bytes are buffered in memory up to this size before being
+/// written to the underlying handle.
+const WRITE_BUFFER_CAPACITY: usize = 64 * 1024;
+
+/// Entries are read from the underlying handle in chunks of this size by
+/// default, see `Log::set_read_chunk_bytes`.
+const DEFAULT_READ_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Opens the handle of the segment with the given index.
+type OpenSegment<T> = Box<dyn FnMut(usize) -> std::io::Result<T> + Send>;
+
+/// The log is written to a sequence of segments: appends go to the last
+/// one, and once it outgrows `max_segment_bytes` it is sealed and a new
+/// segment is started. Reads go through the segments in order, as if they
+/// were a single file.
 pub struct Log<T: Read + Write + Seek> {
+    /// The segment appends go to.
     log: T,
+    /// Earlier segments, oldest first.
+    sealed: Vec<T>,
+    sealed_bytes: u64,
+    /// Index of the segment being read, the active one once it is
+    /// `sealed.len()`.
+    reading: usize,
+    max_segment_bytes: Option<u64>,
+    open_segment: Option<OpenSegment<T>>,
+    sync_policy: SyncPolicy,
+    unsynced_writes: usize,
+    pending: Vec<u8>,
+    /// Bytes read ahead from the segment being read.
+    read_buffer: ReadBuffer,
+    /// Whether `read_next` stopped at an entry cut short by the end of the
+    /// log, see `truncate_torn_tail`.
+    torn_tail: bool,
+    compression: Compression,
+    cipher: Option<Arc<dyn Cipher>>,
+    max_entry_bytes: u64,
 }
 
-impl Log<File> {
+impl Log<LogFile> {
+    /// An empty log kept in memory.
+    pub fn in_memory() -> Self {
+        Log::from_handle(LogFile::Memory(Cursor::new(vec![])))
+    }
+
+    /// Opens the log at `path` along with the segments it was rotated
+    /// into, see `segment_path`.
     pub fn open(path: &Path) -> std::io::Result<Self> {
-        let log = OpenOptions::new().read(true).write(true).open(&path)?;
-        Ok(Log::<File> { log })
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+        Log::open_segments(path, options)
+    }
+
+    pub fn open_read_only(path: &Path) -> std::io::Result<Self> {
+        let mut options = OpenOptions::new();
+        options.read(true);
+        Log::open_segments(path, options)
+    }
+
+    /// Cuts the entry `read_next` stopped at off the log if a crash cut it
+    /// short during an append, so new entries follow the last whole entry
+    /// instead of bytes no read gets past. Returns whether anything was cut,
+    /// an entry cut short in a sealed segment is left in place.
+    pub fn truncate_torn_tail(&mut self) -> Result<bool, LogError> {
+        if !self.torn_tail || self.reading < self.sealed.len() {
+            return Ok(false);
+        }
+
+        self.reader().rewind()?;
+        let mut end = 0;
+        while self.read_record_from_log().is_ok() {
+            end = self.log.stream_position()? - self.read_buffer.remaining() as u64;
+        }
+        self.read_buffer.clear();
+        self.log.set_len(end)?;
+        self.log.sync_data()?;
+        self.torn_tail = false;
+        self.seek_to_end()?;
+        Ok(true)
+    }
+
+    fn open_segments(path: &Path, options: OpenOptions) -> std::io::Result<Self> {
+        let mut segments = vec![LogFile::Disk(options.open(path)?)];
+        loop {
+            match options.open(segment_path(path, segments.len())) {
+                Ok(segment) => segments.push(LogFile::Disk(segment)),
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => break,
+                Err(error) => return Err(error),
+            }
+        }
+
+        let mut log = Log::from_handle(segments.pop().expect("the first segment is always opened"));
+        for mut segment in segments {
+            log.sealed_bytes += segment.seek(SeekFrom::End(0))?;
+            log.sealed.push(segment);
+        }
+        log.reading = log.sealed.len();
+
+        let path = path.to_path_buf();
+        let mut options = options;
+        options.create(true);
+        log.open_segment = Some(Box::new(move |index| {
+            options.open(segment_path(&path, index)).map(LogFile::Disk)
+        }));
+        Ok(log)
     }
+}
 
+/// Path of the segment with the given index of the log at `path`. The first
+/// segment is `path` itself, so an unrotated log is a single file.
+pub fn segment_path(path: &Path, index: usize) -> PathBuf {
+    if index == 0 {
+        return path.to_path_buf();
+    }
+    let mut segment = path.as_os_str().to_owned();
+    segment.push(format!(".{}", index));
+    PathBuf::from(segment)
+}
+
+impl<T: Read + Write + Seek + SyncData> Log<T> {
     pub fn append_to_disk(&mut self, op: LogOperation) -> Result<(), LogError> {
-        self.append(op)?;
+        self.append_borrowed_to_disk(op.borrowed())
+    }
+
+    pub fn append_borrowed_to_disk(&mut self, op: LogOpRef<'_>) -> Result<(), LogError> {
+        self.append_borrowed(op)?;
+        self.unsynced_writes += 1;
+
+        let should_sync = match self.sync_policy {
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::EveryN(n) => self.unsynced_writes >= n,
+            SyncPolicy::Never => false,
+        };
+        if should_sync {
+            self.sync()?;
+        }
+        self.rotate_if_needed()
+    }
+
+    /// Appends every operation in `ops` with a single write to the
+    /// underlying handle, and syncs at most once for all of them under the
+    /// sync policy. The batch is not atomic: a crash partway through the
+    /// write can leave only its first entries in the log, and replay keeps
+    /// those.
+    pub fn append_many(&mut self, ops: &[LogOperation]) -> Result<(), LogError> {
+        let timestamp = SystemClock.now_millis();
+        let start = self.pending.len();
+        for op in ops {
+            if let Err(error) = self.encode_entry(op.borrowed(), timestamp) {
+                self.pending.truncate(start);
+                return Err(error);
+            }
+        }
+        self.write_pending()?;
+        self.unsynced_writes += ops.len();
+
+        let should_sync = match self.sync_policy {
+            SyncPolicy::EveryWrite => !ops.is_empty(),
+            SyncPolicy::EveryN(n) => self.unsynced_writes >= n,
+            SyncPolicy::Never => false,
+        };
+        if should_sync {
+            self.sync()?;
+        }
+        self.rotate_if_needed()
+    }
+
+    /// Writes `op` through to the underlying handle without syncing it,
+    /// whatever the sync policy, leaving the sync to a later `sync`.
+    pub fn append_deferred(&mut self, op: LogOpRef<'_>) -> Result<(), LogError> {
+        self.append_borrowed(op)?;
+        self.write_pending()?;
+        self.unsynced_writes += 1;
+        self.rotate_if_needed()
+    }
+
+    /// Seals the active segment once it outgrows `max_segment_bytes`, after
+    /// syncing it, and continues in a new one.
+    fn rotate_if_needed(&mut self) -> Result<(), LogError> {
+        let max_segment_bytes = match self.max_segment_bytes {
+            Some(max_segment_bytes) => max_segment_bytes,
+            None => return Ok(()),
+        };
+        let open_segment = match self.open_segment.as_mut() {
+            Some(open_segment) => open_segment,
+            None => return Ok(()),
+        };
+        let active_bytes = self.log.seek(SeekFrom::End(0))? + self.pending.len() as u64;
+        if active_bytes < max_segment_bytes {
+            return Ok(());
+        }
+
+        let next = open_segment(self.sealed.len() + 1)?;
+        self.sync()?;
+        let sealed = std::mem::replace(&mut self.log, next);
+        self.sealed.push(sealed);
+        self.sealed_bytes += active_bytes;
+        self.reading = self.sealed.len();
+        self.read_buffer.clear();
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> Result<(), LogError> {
+        self.write_pending()?;
         self.log.sync_data()?;
+        self.unsynced_writes = 0;
         Ok(())
     }
 }
 
 impl<T: Read + Write + Seek> Log<T> {
+    /// Builds a log over any handle, such as a `Cursor` or a custom
+    /// backend. Entries already in the handle are read from its current
+    /// position. Logs built this way never rotate.
+    pub fn from_handle(log: T) -> Self {
+        Log {
+            log,
+            sync_policy: SyncPolicy::default(),
+            unsynced_writes: 0,
+            sealed: vec![],
+            sealed_bytes: 0,
+            reading: 0,
+            max_segment_bytes: None,
+            open_segment: None,
+            pending: vec![],
+            read_buffer: ReadBuffer::new(DEFAULT_READ_CHUNK_BYTES),
+            torn_tail: false,
+            compression: Compression::default(),
+            cipher: None,
+            max_entry_bytes: DEFAULT_MAX_ENTRY_BYTES,
+        }
+    }
 
-    pub fn append(&mut self, op: LogOperation) -> Result<(), LogError> {
-        let mut bytes: Vec<u8> = vec![];
-        match op {
-            LogOperation::Put(key, value) => {
-                bytes::write_encoded_char_to_buffer('p', &mut bytes);
-                bytes::write_encoded_bytes_to_buffer(key, &mut bytes);
-                bytes::write_encoded_bytes_to_buffer(value, &mut bytes);
+    /// Starts a new segment once the active one reaches `limit` bytes,
+    /// `None` keeps appending to it. Only logs opened from a path rotate.
+    pub fn set_max_segment_bytes(&mut self, limit: Option<u64>) {
+        self.max_segment_bytes = limit;
+    }
+
+    /// Number of segments the log is made of, including the active one.
+    pub fn segment_count(&self) -> usize {
+        self.sealed.len() + 1
+    }
+
+    /// Reads entries from the underlying handle `chunk_bytes` at a time
+    /// instead of in one small read per field.
+    pub fn set_read_chunk_bytes(&mut self, chunk_bytes: usize) {
+        self.read_buffer = ReadBuffer::new(chunk_bytes);
+    }
+
+    /// The segment being read, to seek in. Seeking discards the bytes read
+    /// ahead from it.
+    fn reader(&mut self) -> &mut T {
+        self.read_buffer.clear();
+        match self.sealed.get_mut(self.reading) {
+            Some(segment) => segment,
+            None => &mut self.log,
+        }
+    }
+
+    /// The segment being read, to read entries from through the read
+    /// buffer.
+    fn buffered(&mut self) -> Buffered<'_, T> {
+        let segment = match self.sealed.get_mut(self.reading) {
+            Some(segment) => segment,
+            None => &mut self.log,
+        };
+        Buffered {
+            segment,
+            buffer: &mut self.read_buffer,
+        }
+    }
+
+    /// Moves the read position to the next segment while the current one
+    /// is a sealed segment read to its end, and tells whether the end of the
+    /// whole log was reached.
+    fn at_end(&mut self) -> std::io::Result<bool> {
+        loop {
+            if self.read_buffer.remaining() > 0 {
+                return Ok(false);
+            }
+            let reader = self.reader();
+            let position = reader.stream_position()?;
+            let length = reader.seek(SeekFrom::End(0))?;
+            reader.seek(SeekFrom::Start(position))?;
+            if position < length {
+                return Ok(false);
             }
-            LogOperation::Delete(key) => {
-                bytes::write_encoded_char_to_buffer('d', &mut bytes);
-                bytes::write_encoded_bytes_to_buffer(key, &mut bytes);
+            if self.reading >= self.sealed.len() {
+                return Ok(true);
             }
+            self.reading += 1;
+            self.reader().rewind()?;
         }
+    }
+
+    fn seek_to_end(&mut self) -> std::io::Result<()> {
+        self.read_buffer.clear();
+        self.reading = self.sealed.len();
         self.log.seek(SeekFrom::End(0))?;
-        self.log.write_all(&bytes)?;
+        Ok(())
+    }
+
+    pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+        self.sync_policy = policy;
+    }
+
+    /// Codec applied to the values of appended operations, entries are
+    /// decoded whatever codec they were written with.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Encrypts the values of appended operations with `cipher`, and
+    /// decrypts the encrypted values read back.
+    pub fn set_cipher(&mut self, cipher: Option<Arc<dyn Cipher>>) {
+        self.cipher = cipher;
+    }
+
+    /// Largest key or value length accepted when reading entries back, a
+    /// longer declared length fails with `LogError::EntryTooLarge`. Entries
+    /// over the limit are rejected when appended too.
+    pub fn set_max_entry_bytes(&mut self, limit: u64) {
+        self.max_entry_bytes = limit;
+    }
+
+    pub fn size_bytes(&mut self) -> std::io::Result<u64> {
+        self.read_buffer.clear();
+        Ok(self.sealed_bytes + self.log.seek(SeekFrom::End(0))? + self.pending.len() as u64)
+    }
+
+    /// Buffers `op` for writing, the buffer reaches the underlying handle
+    /// once it fills up, on `sync` and before the log is read.
+    pub fn append(&mut self, op: LogOperation) -> Result<(), LogError> {
+        self.append_borrowed(op.borrowed())
+    }
+
+    /// Like `append`, but encodes the key and value straight from the
+    /// borrowed slices.
+    pub fn append_borrowed(&mut self, op: LogOpRef<'_>) -> Result<(), LogError> {
+        self.encode_entry(op, SystemClock.now_millis())?;
+        if self.pending.len() >= WRITE_BUFFER_CAPACITY {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    /// Encodes `op` after the pending bytes. An entry with a key or value
+    /// too long to be read back, see `set_max_entry_bytes`, is rejected
+    /// with `LogError::EntryTooLarge` and leaves them as they were.
+    fn encode_entry(&mut self, op: LogOpRef<'_>, timestamp: u64) -> Result<(), LogError> {
+        let start = self.pending.len();
+        let cipher = self.cipher.as_deref();
+        let length = write_entry(op, timestamp, self.compression, cipher, &mut self.pending);
+        if let Err(error) = self.check_entry_length(length) {
+            self.pending.truncate(start);
+            return Err(error);
+        }
+        Ok(())
+    }
+
+    /// Bytes `op` adds to the log once appended, with the log's compression
+    /// and cipher. Encodes the entry to measure it.
+    pub fn encoded_len(&self, op: LogOpRef<'_>) -> u64 {
+        let mut bytes = vec![];
+        write_entry(op, 0, self.compression, self.cipher.as_deref(), &mut bytes);
+        bytes.len() as u64
+    }
+
+    fn write_pending(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        self.read_buffer.clear();
+        self.log.seek(SeekFrom::End(0))?;
+        self.log.write_all(&self.pending)?;
+        self.pending.clear();
         Ok(())
     }
 
 
     pub fn read_until_empty(&mut self) -> Result<Vec<LogOperation>, LogError> {
-        let mut log_operations = vec![];
+        Ok(self
+            .read_records()?
+            .into_iter()
+            .map(|record| record.operation)
+            .collect())
+    }
+
+    /// Like `read_until_empty`, but keeps the append timestamp of every entry.
+    pub fn read_records(&mut self) -> Result<Vec<LogRecord>, LogError> {
+        let mut log_records = vec![];
 
-        self.log.rewind()?;
+        self.rewind()?;
         let mut end_reached = false;
         while !end_reached {
-            if let Ok(op) = self.read_operation_from_log() {
-                log_operations.push(op);
+            if let Ok(record) = self.read_record_from_log() {
+                log_records.push(record);
             }
             else {
                 end_reached = true;
             }
         }
+        self.seek_to_end()?;
 
-        Ok(log_operations)
+        Ok(log_records)
     }
 
 
-    fn read_operation_from_log(&mut self) -> Result<LogOperation, LogError> {
+    /// Reads every operation from byte `offset` of the log to its end,
+    /// across segments. `offset` must be where an entry starts, such as an
+    /// earlier `size_bytes`, an offset past the end reads nothing.
+    pub fn read_from(&mut self, offset: u64) -> Result<Vec<LogOperation>, LogError> {
+        self.write_pending()?;
+        self.reading = 0;
+        let mut remaining = offset;
+        while self.reading < self.sealed.len() {
+            let length = self.reader().seek(SeekFrom::End(0))?;
+            if remaining < length {
+                break;
+            }
+            remaining -= length;
+            self.reading += 1;
+        }
+        self.reader().seek(SeekFrom::Start(remaining))?;
+
+        let mut operations = vec![];
+        while let Some(record) = self.read_next()? {
+            operations.push(record.operation);
+        }
+        self.seek_to_end()?;
+        Ok(operations)
+    }
+
+    /// Moves back to the first entry, for reading the log with `read_next`.
+    pub fn rewind(&mut self) -> Result<(), LogError> {
+        self.write_pending()?;
+        self.torn_tail = false;
+        self.reading = 0;
+        self.reader().rewind()?;
+        Ok(())
+    }
+
+    /// Decodes the entry at the current position. Unlike `read_until_empty`,
+    /// an entry that fails to decode is an error, only the end of the log or
+    /// an entry cut short by it (as left by a crash during an append) end
+    /// the read with `None`, see `read_entry` to tell them apart.
+    pub fn read_next(&mut self) -> Result<Option<LogRecord>, LogError> {
+        match self.read_record_from_log() {
+            Ok(record) => Ok(Some(record)),
+            Err(LogError::EndReached) => Ok(None),
+            Err(LogError::TruncatedEntry { .. }) => {
+                self.torn_tail = true;
+                Ok(None)
+            }
+            Err(LogError::Io(error)) if error.kind() == std::io::ErrorKind::UnexpectedEof => {
+                self.torn_tail = true;
+                Ok(None)
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Like `read_next`, but an entry cut short by the end of the log is a
+    /// `LogError::TruncatedEntry` when its header or the length of its key
+    /// or value runs past the end, and only the end of the log gives `None`.
+    pub fn read_entry(&mut self) -> Result<Option<LogRecord>, LogError> {
+        match self.read_record_from_log() {
+            Ok(record) => Ok(Some(record)),
+            Err(LogError::EndReached) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Decodes every entry from the start of the log, stopping at the first
+    /// one that fails to decode since nothing after it can be located.
+    pub fn scan(&mut self) -> Result<LogScan, LogError> {
+        let mut scan = LogScan::default();
+        self.rewind()?;
+        while !self.at_end()? {
+            match self.read_record_from_log() {
+                Ok(_) => scan.valid_entries += 1,
+                Err(LogError::InvalidOperation(_))
+                | Err(LogError::UnsupportedEntryFormat(_))
+                | Err(LogError::EntryTooLarge { .. }) => {
+                    scan.invalid_entries += 1;
+                    break;
+                }
+                Err(_) => {
+                    scan.invalid_entries += 1;
+                    scan.partial_tail = true;
+                    break;
+                }
+            }
+        }
+
+        Ok(scan)
+    }
+
+    fn read_record_from_log(&mut self) -> Result<LogRecord, LogError> {
         let mut op_len_buf = [0; 9];
-        
-        match self.log.read_exact(&mut op_len_buf) {
-            Ok(()) => (),
-            Err(_) => return Err(LogError::EndReached.into())
+        if self.at_end()? {
+            return Err(LogError::EndReached);
         }
+        self.check_available(op_len_buf.len() as u64)?;
+        self.buffered().read_exact(&mut op_len_buf)?;
+
+        let mut header_len_buf = [0; U64_BYTES_LEN];
+        header_len_buf.copy_from_slice(&op_len_buf[..U64_BYTES_LEN]);
+        let timestamp = match u64::from_be_bytes(header_len_buf) {
+            LEGACY_HEADER_LEN => None,
+            TIMESTAMPED_HEADER_LEN => Some(read_u64_from_log(&mut self.buffered())?),
+            header_len => return Err(LogError::UnsupportedEntryFormat(header_len)),
+        };
 
         let op = op_len_buf[U64_BYTES_LEN] as char;
+        let operation = self.read_operation_from_log(op)?;
+        Ok(LogRecord { operation, timestamp })
+    }
+
+    fn read_operation_from_log(&mut self, op: char) -> Result<LogOperation, LogError> {
         match op {
             'p' => {
-                let key = self.read_instruction_from_log();
-                let value = self.read_instruction_from_log();
+                let key = self.read_instruction_from_log()?;
+                let value = self.read_value()?;
                 
                 Ok(LogOperation::Put(key, value))
             }
+            'e' => {
+                let key = self.read_instruction_from_log()?;
+                let value = self.read_value()?;
+                let expires_at = read_u64_from_log(&mut self.buffered())?;
+
+                Ok(LogOperation::PutWithExpiry(key, value, expires_at))
+            }
             'd' => {
-                let key = self.read_instruction_from_log();
+                let key = self.read_instruction_from_log()?;
             
                 Ok(LogOperation::Delete(key))
             }
+            'a' => {
+                let key = self.read_instruction_from_log()?;
+                let suffix = self.read_value()?;
+
+                Ok(LogOperation::Append(key, suffix))
+            }
+            'c' => Ok(LogOperation::Clear),
             c => Err(LogError::InvalidOperation(c)),
         }
     }
 
-    fn read_instruction_from_log(&mut self) -> Vec<u8> {
-        let instruction_length = read_u64_from_log(&mut self.log);
-        match read_bytes_from_log(&mut self.log, instruction_length) {
-            Ok(bytes) => bytes,
-            Err(e) => panic!("Unable to read instruction from log: {}", e)
+    fn read_instruction_from_log(&mut self) -> Result<Vec<u8>, LogError> {
+        let instruction_length = read_u64_from_log(&mut self.buffered())?;
+        self.check_entry_length(instruction_length)?;
+        self.check_available(instruction_length)?;
+        Ok(read_bytes_from_log(&mut self.buffered(), instruction_length)?)
+    }
+
+    fn read_value(&mut self) -> Result<Vec<u8>, LogError> {
+        let length = read_u64_from_log(&mut self.buffered())?;
+        self.check_entry_length(bytes::value_len(length))?;
+        self.check_available(bytes::value_len(length))?;
+        let cipher = self.cipher.clone();
+        Ok(read_value_with_length(&mut self.buffered(), length, cipher.as_deref())?)
+    }
+
+    /// Rejects a declared length before anything is allocated for it.
+    fn check_entry_length(&self, length: u64) -> Result<(), LogError> {
+        if length > self.max_entry_bytes || usize::try_from(length).is_err() {
+            return Err(LogError::EntryTooLarge {
+                length,
+                limit: self.max_entry_bytes,
+            });
         }
+        Ok(())
     }
 
+    /// Fails with `LogError::TruncatedEntry` if fewer than `length` bytes
+    /// are left in the segment being read. Entries never span segments.
+    fn check_available(&mut self, length: u64) -> Result<(), LogError> {
+        let buffered = self.read_buffer.remaining() as u64;
+        if length <= buffered {
+            return Ok(());
+        }
+        let segment = self.buffered().segment;
+        let position = segment.stream_position()?;
+        let end = segment.seek(SeekFrom::End(0))?;
+        segment.seek(SeekFrom::Start(position))?;
+        let available = end.saturating_sub(position) + buffered;
+        if length > available {
+            return Err(LogError::TruncatedEntry {
+                expected: length,
+                available,
+            });
+        }
+        Ok(())
+    }
+}
 
+/// Bytes read ahead from a segment, consumed by the entries read next.
+struct ReadBuffer {
+    bytes: Vec<u8>,
+    consumed: usize,
+    chunk_bytes: usize,
 }
 
+impl ReadBuffer {
+    fn new(chunk_bytes: usize) -> Self {
+        ReadBuffer {
+            bytes: vec![],
+            consumed: 0,
+            chunk_bytes: chunk_bytes.max(1),
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.bytes.len() - self.consumed
+    }
+
+    fn clear(&mut self) {
+        self.bytes.clear();
+        self.consumed = 0;
+    }
+}
+
+/// Reads a segment through the read buffer, refilling it a chunk at a
+/// time. Reads of at least a chunk go straight to the segment once the
+/// buffer is drained.
+struct Buffered<'a, T> {
+    segment: &'a mut T,
+    buffer: &'a mut ReadBuffer,
+}
+
+impl<T: Read> Read for Buffered<'_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let buffer = &mut *self.buffer;
+        if buffer.remaining() == 0 {
+            buffer.clear();
+            if buf.len() >= buffer.chunk_bytes {
+                return self.segment.read(buf);
+            }
+            buffer.bytes.resize(buffer.chunk_bytes, 0);
+            match self.segment.read(&mut buffer.bytes) {
+                Ok(read) => buffer.bytes.truncate(read),
+                Err(error) => {
+                    buffer.clear();
+                    return Err(error);
+                }
+            }
+        }
+
+        let available = &buffer.bytes[buffer.consumed..];
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        buffer.consumed += read;
+        Ok(read)
+    }
+}
+
+/// Encodes `op` into `bytes`, returning the longest length of its key and
+/// value as `check_entry_length` sees them when reading the entry back.
+fn write_entry(
+    op: LogOpRef<'_>,
+    timestamp: u64,
+    compression: Compression,
+    cipher: Option<&dyn Cipher>,
+    bytes: &mut Vec<u8>,
+) -> u64 {
+    let write_value = |value: &[u8], bytes: &mut Vec<u8>| {
+        let start = bytes.len();
+        bytes::write_encoded_value_to_buffer(value, compression, cipher, bytes);
+        let mut length = [0; U64_BYTES_LEN];
+        length.copy_from_slice(&bytes[start..start + U64_BYTES_LEN]);
+        bytes::value_len(u64::from_be_bytes(length))
+    };
+    match op {
+        LogOpRef::Put(key, value) => {
+            write_entry_header('p', timestamp, bytes);
+            bytes::write_encoded_bytes_to_buffer(key, bytes);
+            (key.len() as u64).max(write_value(value, bytes))
+        }
+        LogOpRef::PutWithExpiry(key, value, expires_at) => {
+            write_entry_header('e', timestamp, bytes);
+            bytes::write_encoded_bytes_to_buffer(key, bytes);
+            let value_length = write_value(value, bytes);
+            bytes::write_u64_to_buffer(expires_at, bytes);
+            (key.len() as u64).max(value_length)
+        }
+        LogOpRef::Delete(key) => {
+            write_entry_header('d', timestamp, bytes);
+            bytes::write_encoded_bytes_to_buffer(key, bytes);
+            key.len() as u64
+        }
+        LogOpRef::Append(key, suffix) => {
+            write_entry_header('a', timestamp, bytes);
+            bytes::write_encoded_bytes_to_buffer(key, bytes);
+            (key.len() as u64).max(write_value(suffix, bytes))
+        }
+        LogOpRef::Clear => {
+            write_entry_header('c', timestamp, bytes);
+            0
+        }
+    }
+}
+
+fn write_entry_header(op: char, timestamp: u64, buf: &mut Vec<u8>) {
+    bytes::write_u64_to_buffer(TIMESTAMPED_HEADER_LEN, buf);
+    buf.push(op as u8);
+    bytes::write_u64_to_buffer(timestamp, buf);
+}
+
+impl<T: Read + Write + Seek> Drop for Log<T> {
+    fn drop(&mut self) {
+        let _ = self.write_pending();
+    }
+}
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::cell::Cell;
     use std::io::Cursor;
 
     #[test]
     fn test_log() {
         let cursor = Cursor::new(Vec::new());
-        let mut log = Log { log: cursor} ;
+        let mut log = Log::from_handle(cursor);
 
         let expected_op_1 = LogOperation::Put("Hello".into(), "World".into());
         let expected_op_2 = LogOperation::Delete("Hello".into());
+        let expected_op_3 = LogOperation::PutWithExpiry("Hello".into(), "There".into(), 42);
 
         log.append(expected_op_1.clone()).unwrap();
         log.append(expected_op_2.clone()).unwrap();
+        log.append(expected_op_3.clone()).unwrap();
 
         let ops = log.read_until_empty().unwrap();
 
-        assert_eq!(vec![expected_op_1, expected_op_2], ops);
+        assert_eq!(vec![expected_op_1, expected_op_2, expected_op_3], ops);
+    }
+
+    #[test]
+    fn test_from_handle() {
+        let ops = vec![
+            LogOperation::Put("Hello".into(), "World".into()),
+            LogOperation::Append("Hello".into(), "!".into()),
+            LogOperation::Delete("Hello".into()),
+        ];
+
+        let mut bytes = vec![];
+        let mut log = Log::from_handle(Cursor::new(&mut bytes));
+        for op in ops.iter() {
+            log.append(op.clone()).unwrap();
+        }
+        drop(log);
+
+        let mut log = Log::from_handle(Cursor::new(bytes));
+        assert_eq!(log.read_until_empty().unwrap(), ops);
+    }
+
+    #[test]
+    fn test_append_borrowed() {
+        let ops = vec![
+            LogOperation::Put("Hello".into(), "World".into()),
+            LogOperation::PutWithExpiry("Hello".into(), "There".into(), 42),
+            LogOperation::Delete("Hello".into()),
+        ];
+
+        let mut owned = Log::from_handle(Cursor::new(Vec::new()));
+        let mut borrowed = Log::from_handle(Cursor::new(Vec::new()));
+        for op in ops.iter() {
+            owned.append(op.clone()).unwrap();
+            borrowed.append_borrowed(op.borrowed()).unwrap();
+        }
+        assert_eq!(owned.size_bytes().unwrap(), borrowed.size_bytes().unwrap());
+        borrowed
+            .append_borrowed(LogOpRef::Put(b"key", b"value"))
+            .unwrap();
+
+        let mut expected = owned.read_until_empty().unwrap();
+        assert_eq!(expected, ops);
+        expected.push(LogOperation::Put("key".into(), "value".into()));
+        assert_eq!(borrowed.read_until_empty().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_entry_too_large() {
+        let mut log = Log::from_handle(Cursor::new(Vec::new()));
+        log.append(LogOperation::Put("Hello".into(), "World".into()))
+            .unwrap();
+
+        let mut corrupt = vec![];
+        write_entry_header('p', 0, &mut corrupt);
+        bytes::write_u64_to_buffer(u64::MAX >> 1, &mut corrupt);
+        corrupt.extend(b"Hello");
+        log.write_pending().unwrap();
+        log.log.seek(SeekFrom::End(0)).unwrap();
+        log.log.write_all(&corrupt).unwrap();
+
+        log.rewind().unwrap();
+        assert!(log.read_next().unwrap().is_some());
+        assert!(matches!(
+            log.read_next(),
+            Err(LogError::EntryTooLarge {
+                length,
+                limit: DEFAULT_MAX_ENTRY_BYTES,
+            }) if length == u64::MAX >> 1
+        ));
+
+        log.set_max_entry_bytes(4);
+        log.rewind().unwrap();
+        assert!(matches!(
+            log.read_next(),
+            Err(LogError::EntryTooLarge { length: 5, limit: 4 })
+        ));
+        assert_eq!(log.scan().unwrap().invalid_entries, 1);
+    }
+
+    #[test]
+    fn test_append_over_max_entry_bytes() {
+        let mut log = Log::from_handle(SyncCountingCursor::new());
+        log.set_max_entry_bytes(4);
+        log.append(LogOperation::Put("key".into(), "val".into())).unwrap();
+        assert!(matches!(
+            log.append(LogOperation::Put("key".into(), "value".into())),
+            Err(LogError::EntryTooLarge { length: 5, limit: 4 })
+        ));
+        assert!(matches!(
+            log.append_many(&[
+                LogOperation::Delete("key".into()),
+                LogOperation::Append("long key".into(), "!".into()),
+            ]),
+            Err(LogError::EntryTooLarge { length: 8, limit: 4 })
+        ));
+
+        assert_eq!(
+            log.read_until_empty().unwrap(),
+            vec![LogOperation::Put("key".into(), "val".into())]
+        );
+    }
+
+    #[test]
+    fn test_compact_appends() {
+        let operations = vec![
+            LogOperation::Append("a".into(), "1".into()),
+            LogOperation::Put("b".into(), "x".into()),
+            LogOperation::Append("a".into(), "2".into()),
+            LogOperation::Append("b".into(), "y".into()),
+            LogOperation::Delete("c".into()),
+            LogOperation::Append("c".into(), "z".into()),
+        ];
+
+        assert_eq!(
+            compact_operations(operations),
+            vec![
+                LogOperation::Append("a".into(), "12".into()),
+                LogOperation::Put("b".into(), "xy".into()),
+                LogOperation::Put("c".into(), "z".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compact_clear() {
+        let operations = vec![
+            LogOperation::Put("a".into(), "1".into()),
+            LogOperation::Put("b".into(), "2".into()),
+            LogOperation::Clear,
+            LogOperation::Append("b".into(), "3".into()),
+            LogOperation::Put("c".into(), "4".into()),
+        ];
+
+        assert_eq!(
+            compact_operations(operations),
+            vec![
+                LogOperation::Clear,
+                LogOperation::Append("b".into(), "3".into()),
+                LogOperation::Put("c".into(), "4".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut log = Log::from_handle(Cursor::new(Vec::new()));
+        log.append(LogOperation::Put("Hello".into(), "World".into()))
+            .unwrap();
+        log.append(LogOperation::Delete("Hello".into())).unwrap();
+
+        assert_eq!(
+            log.scan().unwrap(),
+            LogScan {
+                valid_entries: 2,
+                invalid_entries: 0,
+                partial_tail: false,
+            }
+        );
+
+        let mut truncated = vec![];
+        bytes::write_encoded_char_to_buffer('p', &mut truncated);
+        bytes::write_u64_to_buffer(100, &mut truncated);
+        truncated.extend(b"abc");
+        log.log.seek(SeekFrom::End(0)).unwrap();
+        log.log.write_all(&truncated).unwrap();
+
+        assert_eq!(
+            log.scan().unwrap(),
+            LogScan {
+                valid_entries: 2,
+                invalid_entries: 1,
+                partial_tail: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_scan_invalid_operation() {
+        let mut log = Log::from_handle(Cursor::new(Vec::new()));
+        log.append(LogOperation::Delete("Hello".into())).unwrap();
+        log.write_pending().unwrap();
+
+        let mut invalid = vec![];
+        bytes::write_encoded_char_to_buffer('x', &mut invalid);
+        log.log.seek(SeekFrom::End(0)).unwrap();
+        log.log.write_all(&invalid).unwrap();
+
+        let scan = log.scan().unwrap();
+        assert_eq!(scan.valid_entries, 1);
+        assert_eq!(scan.invalid_entries, 1);
+        assert!(!scan.partial_tail);
     }
 
+    #[test]
+    fn test_compact_operations() {
+        let ops = vec![
+            LogOperation::Put("a".into(), "1".into()),
+            LogOperation::Put("b".into(), "1".into()),
+            LogOperation::Put("a".into(), "2".into()),
+            LogOperation::Delete("b".into()),
+        ];
+
+        assert_eq!(
+            compact_operations(ops),
+            vec![
+                LogOperation::Put("a".into(), "2".into()),
+                LogOperation::Delete("b".into()),
+            ]
+        );
+    }
+
+    struct SyncCountingCursor {
+        cursor: Cursor<Vec<u8>>,
+        syncs: Cell<usize>,
+        writes: usize,
+        reads: usize,
+    }
+
+    impl SyncCountingCursor {
+        fn new() -> Self {
+            SyncCountingCursor {
+                cursor: Cursor::new(Vec::new()),
+                syncs: Cell::new(0),
+                writes: 0,
+                reads: 0,
+            }
+        }
+    }
+
+    impl Read for SyncCountingCursor {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reads += 1;
+            self.cursor.read(buf)
+        }
+    }
+
+    impl Write for SyncCountingCursor {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.writes += 1;
+            self.cursor.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.cursor.flush()
+        }
+    }
+
+    impl Seek for SyncCountingCursor {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.cursor.seek(pos)
+        }
+    }
+
+    impl SyncData for SyncCountingCursor {
+        fn sync_data(&self) -> std::io::Result<()> {
+            self.syncs.set(self.syncs.get() + 1);
+            Ok(())
+        }
+    }
+
+    fn count_syncs(policy: SyncPolicy, appends: usize) -> usize {
+        let mut log = Log::from_handle(SyncCountingCursor::new());
+        log.set_sync_policy(policy);
+        for _ in 0..appends {
+            log.append_to_disk(LogOperation::Delete("Hello".into()))
+                .unwrap();
+        }
+        log.log.syncs.get()
+    }
+
+    #[test]
+    fn test_sync_policy_every_write() {
+        assert_eq!(count_syncs(SyncPolicy::EveryWrite, 10), 10);
+    }
+
+    #[test]
+    fn test_sync_policy_every_n() {
+        assert_eq!(count_syncs(SyncPolicy::EveryN(3), 10), 3);
+    }
+
+    #[test]
+    fn test_sync_policy_never() {
+        assert_eq!(count_syncs(SyncPolicy::Never, 10), 0);
+    }
+
+    #[test]
+    fn test_append_deferred() {
+        let mut log = Log::from_handle(SyncCountingCursor::new());
+        log.set_sync_policy(SyncPolicy::EveryWrite);
+        log.append_deferred(LogOpRef::Put(b"key", b"value")).unwrap();
+        assert_eq!(log.log.syncs.get(), 0);
+        assert!(log.pending.is_empty());
+        assert!(!log.log.cursor.get_ref().is_empty());
+
+        log.sync().unwrap();
+        assert_eq!(log.log.syncs.get(), 1);
+    }
+
+    #[test]
+    fn test_appends_are_buffered() {
+        let mut log = Log::from_handle(SyncCountingCursor::new());
+        log.set_sync_policy(SyncPolicy::Never);
+        for _ in 0..100 {
+            log.append_to_disk(LogOperation::Delete("Hello".into()))
+                .unwrap();
+        }
+        assert_eq!(log.log.writes, 0);
+
+        log.sync().unwrap();
+        assert_eq!(log.log.writes, 1);
+        assert_eq!(log.read_until_empty().unwrap().len(), 100);
+    }
+
+    #[test]
+    fn test_every_write_policy_writes_through() {
+        let mut log = Log::from_handle(SyncCountingCursor::new());
+        for _ in 0..10 {
+            log.append_to_disk(LogOperation::Delete("Hello".into()))
+                .unwrap();
+        }
+        assert_eq!(log.log.writes, 10);
+        assert_eq!(log.log.syncs.get(), 10);
+    }
+
+    #[test]
+    fn test_log_records_are_timestamped() {
+        let mut log = Log::from_handle(Cursor::new(Vec::new()));
+        let before = SystemClock.now_millis();
+
+        log.append(LogOperation::Put("Hello".into(), "World".into())).unwrap();
+        log.append(LogOperation::Delete("Hello".into())).unwrap();
+
+        let after = SystemClock.now_millis();
+        let records = log.read_records().unwrap();
+        assert_eq!(records.len(), 2);
+
+        let timestamps: Vec<u64> = records.iter().map(|r| r.timestamp.unwrap()).collect();
+        assert!(timestamps[0] <= timestamps[1]);
+        assert!(timestamps.iter().all(|t| (before..=after).contains(t)));
+    }
+
+    #[test]
+    fn test_read_legacy_entries() {
+        let mut log = Log::from_handle(Cursor::new(Vec::new()));
+        let mut legacy = vec![];
+        bytes::write_encoded_char_to_buffer('d', &mut legacy);
+        bytes::write_encoded_bytes_to_buffer(b"Hello", &mut legacy);
+        log.log.write_all(&legacy).unwrap();
+        log.append(LogOperation::Delete("World".into())).unwrap();
+
+        let records = log.read_records().unwrap();
+        assert_eq!(records[0].operation, LogOperation::Delete("Hello".into()));
+        assert_eq!(records[0].timestamp, None);
+        assert_eq!(records[1].operation, LogOperation::Delete("World".into()));
+        assert!(records[1].timestamp.is_some());
+    }
+
+    #[test]
+    fn test_truncated_entry() {
+        let mut log = Log::from_handle(Cursor::new(Vec::new()));
+        log.append(LogOperation::Put("Hello".into(), "World".into()))
+            .unwrap();
+
+        let mut truncated = vec![];
+        write_entry_header('p', 0, &mut truncated);
+        bytes::write_u64_to_buffer(100, &mut truncated);
+        truncated.extend(b"0123456789");
+        log.write_pending().unwrap();
+        log.log.seek(SeekFrom::End(0)).unwrap();
+        log.log.write_all(&truncated).unwrap();
+
+        log.rewind().unwrap();
+        assert!(log.read_entry().unwrap().is_some());
+        assert!(matches!(
+            log.read_entry(),
+            Err(LogError::TruncatedEntry {
+                expected: 100,
+                available: 10,
+            })
+        ));
+
+        log.rewind().unwrap();
+        assert!(log.read_next().unwrap().is_some());
+        assert!(log.read_next().unwrap().is_none());
+        assert!(log.scan().unwrap().partial_tail);
+    }
+
+    #[test]
+    fn test_truncate_torn_tail() {
+        let first = LogOperation::Put("Hello".into(), "World".into());
+        let mut torn = vec![];
+        write_entry(first.borrowed(), 0, Compression::default(), None, &mut torn);
+        for cut in [3, 12, 30] {
+            let mut log = Log::in_memory();
+            log.append(first.clone()).unwrap();
+            log.write_pending().unwrap();
+            log.log.seek(SeekFrom::End(0)).unwrap();
+            log.log.write_all(&torn[..cut]).unwrap();
+
+            log.rewind().unwrap();
+            assert!(log.read_next().unwrap().is_some());
+            assert!(log.read_next().unwrap().is_none());
+            assert!(log.truncate_torn_tail().unwrap());
+            log.append(LogOperation::Delete("Hello".into())).unwrap();
+            assert_eq!(
+                log.read_until_empty().unwrap(),
+                vec![first.clone(), LogOperation::Delete("Hello".into())]
+            );
+            assert!(!log.truncate_torn_tail().unwrap());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_io_error_kind() {
+        let path = std::env::temp_dir().join(format!("simpledb_io_error_{}", std::process::id()));
+        File::create(&path).unwrap();
+        let mut log = Log::open_read_only(&path).unwrap();
+
+        log.append(LogOperation::Put("Hello".into(), "World".into()))
+            .unwrap();
+        let error = log.sync().unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        let io_error = match &error {
+            LogError::Io(io_error) => io_error,
+            error => panic!("Expected an IO error, got {:?}", error),
+        };
+        assert_eq!(io_error.raw_os_error(), Some(libc::EBADF));
+        assert!(std::error::Error::source(&error).is_some());
+        let message = error.to_string();
+        assert!(message.contains(&format!("{:?}", io_error.kind())), "{}", message);
+        assert!(message.contains("Bad file descriptor"), "{}", message);
+
+        match crate::error::DatabaseError::from(error) {
+            crate::error::DatabaseError::Io(io_error) => {
+                assert_eq!(io_error.raw_os_error(), Some(libc::EBADF))
+            }
+            error => panic!("Expected an IO error, got {:?}", error),
+        }
+    }
+
+    #[test]
+    fn test_chunked_reads() {
+        let ops: Vec<LogOperation> = (0..1000u64)
+            .map(|i| match i % 4 {
+                0 => LogOperation::Put(format!("key{}", i).into_bytes(), vec![1; i as usize]),
+                1 => LogOperation::PutWithExpiry(format!("key{}", i).into_bytes(), vec![], i),
+                2 => LogOperation::Append(format!("key{}", i).into_bytes(), vec![2; 3]),
+                _ => LogOperation::Delete(format!("key{}", i).into_bytes()),
+            })
+            .collect();
+        let read_with_chunks = |chunk_bytes: usize| {
+            let mut log = Log::from_handle(SyncCountingCursor::new());
+            for op in ops.iter() {
+                log.append(op.clone()).unwrap();
+            }
+            log.set_read_chunk_bytes(chunk_bytes);
+            log.sync().unwrap();
+            log.log.reads = 0;
+            let read = log.read_until_empty().unwrap();
+            (read, log.log.reads)
+        };
+
+        let (unbuffered, unbuffered_reads) = read_with_chunks(1);
+        let (small_chunks, _) = read_with_chunks(64);
+        let (chunked, chunked_reads) = read_with_chunks(DEFAULT_READ_CHUNK_BYTES);
+        assert_eq!(unbuffered, ops);
+        assert_eq!(small_chunks, ops);
+        assert_eq!(chunked, ops);
+        assert!(chunked_reads * 100 < unbuffered_reads, "{} reads", chunked_reads);
+    }
+
+    #[test]
+    fn test_append_many() {
+        let ops = vec![
+            LogOperation::Put("Hello".into(), "World".into()),
+            LogOperation::PutWithExpiry("Hello".into(), "There".into(), 42),
+            LogOperation::Append("Hello".into(), "!".into()),
+            LogOperation::Delete("Hello".into()),
+            LogOperation::Clear,
+        ];
+
+        let mut log = Log::from_handle(SyncCountingCursor::new());
+        log.append_many(&ops).unwrap();
+        assert_eq!(log.log.writes, 1);
+        assert_eq!(log.log.syncs.get(), 1);
+        assert!(log.pending.is_empty());
+
+        let mut individual = Log::from_handle(SyncCountingCursor::new());
+        for op in ops.iter() {
+            individual.append_to_disk(op.clone()).unwrap();
+        }
+        assert_eq!(individual.log.syncs.get(), ops.len());
+        assert_eq!(log.read_until_empty().unwrap(), ops);
+        assert_eq!(individual.read_until_empty().unwrap(), ops);
+
+        log.append_many(&[]).unwrap();
+        assert_eq!(log.log.syncs.get(), 1);
+    }
 }
@@ -0,0 +1,102 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Hooks called as the database is used, for feeding an external metrics
+/// system. Every hook does nothing by default.
+pub trait Metrics: Send + Sync {
+    fn on_put(&self) {}
+
+    fn on_get_hit(&self) {}
+
+    fn on_get_miss(&self) {}
+
+    fn on_commit(&self) {}
+
+    /// Called with the number of bytes every logged operation added to the
+    /// log.
+    fn on_log_append(&self, _bytes: u64) {}
+}
+
+/// The `Metrics` registered through `DbOptions::metrics`.
+#[derive(Clone)]
+pub(crate) struct MetricsHook(pub(crate) Arc<dyn Metrics>);
+
+impl fmt::Debug for MetricsHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MetricsHook")
+    }
+}
+
+/// Totals returned by `SimpleDB::op_counters`.
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct OpCounters {
+    pub get_hits: u64,
+    pub get_misses: u64,
+    /// Puts, with or without a TTL.
+    pub puts: u64,
+    pub deletes: u64,
+    pub commits: u64,
+    /// Commits that succeeded but failed to remove the files of the
+    /// versions they replaced, which are left on disk.
+    pub cleanup_failures: u64,
+}
+
+/// Always-on counters behind `SimpleDB::op_counters`, updated without
+/// taking a lock.
+#[derive(Default)]
+pub(crate) struct Counters {
+    get_hits: AtomicU64,
+    get_misses: AtomicU64,
+    puts: AtomicU64,
+    deletes: AtomicU64,
+    commits: AtomicU64,
+    cleanup_failures: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn on_get(&self, hit: bool) {
+        let counter = if hit { &self.get_hits } else { &self.get_misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_put(&self) {
+        self.puts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_delete(&self) {
+        self.deletes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_commit(&self) {
+        self.commits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_cleanup_failure(&self) {
+        self.cleanup_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> OpCounters {
+        OpCounters {
+            get_hits: self.get_hits.load(Ordering::Relaxed),
+            get_misses: self.get_misses.load(Ordering::Relaxed),
+            puts: self.puts.load(Ordering::Relaxed),
+            deletes: self.deletes.load(Ordering::Relaxed),
+            commits: self.commits.load(Ordering::Relaxed),
+            cleanup_failures: self.cleanup_failures.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn reset(&self) {
+        for counter in [
+            &self.get_hits,
+            &self.get_misses,
+            &self.puts,
+            &self.deletes,
+            &self.commits,
+            &self.cleanup_failures,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use crate::cipher::{Cipher, CipherHook};
+use crate::compression::Compression;
+use crate::db::{
+    SimpleDB, CHECKPOINT_FILE_NAME, COMPACTED_LOG_FILE_NAME, INDEX_FILE_NAME, LOCK_FILE_NAME,
+    LOG_FILE_NAME, NEW_VERSION_FILE_NAME, VERSION_FILE_NAME,
+};
+use crate::error::DatabaseError;
+use crate::log::{LogOperation, SyncPolicy};
+use crate::metrics::{Metrics, MetricsHook};
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Configuration used to open a `SimpleDB`, in the style of
+/// `std::fs::OpenOptions`.
+#[derive(Debug, Clone, Default)]
+pub struct DbOptions {
+    pub(crate) sync_policy: SyncPolicy,
+    pub(crate) auto_commit_bytes: Option<u64>,
+    pub(crate) auto_commit_ops: Option<usize>,
+    pub(crate) max_log_bytes: Option<u64>,
+    pub(crate) read_only: bool,
+    pub(crate) max_value_bytes: Option<usize>,
+    pub(crate) compression: Compression,
+    pub(crate) encryption: Option<CipherHook>,
+    pub(crate) layout: FileLayout,
+    pub(crate) bloom_false_positive_rate: Option<f64>,
+    pub(crate) max_records: Option<usize>,
+    pub(crate) retain_versions: usize,
+    pub(crate) log_segment_bytes: Option<u64>,
+    pub(crate) compact_on_open: bool,
+    pub(crate) metrics: Option<MetricsHook>,
+    pub(crate) reject_empty_keys: bool,
+    pub(crate) compaction_threshold_bytes: u64,
+    pub(crate) on_replay: Option<ReplayHook>,
+    pub(crate) key_transform: Option<KeyTransform>,
+    pub(crate) checksum_values: bool,
+    pub(crate) lock_timeout: Option<Duration>,
+    pub(crate) recover_poisoned_locks: bool,
+    pub(crate) max_replay_ops: Option<usize>,
+    pub(crate) sorted_checkpoints: bool,
+}
+
+impl DbOptions {
+    pub fn new() -> Self {
+        DbOptions::default()
+    }
+
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Commits automatically once a write grows the log past `threshold`
+    /// bytes, `None` disables auto-commit.
+    pub fn auto_commit_bytes(mut self, threshold: Option<u64>) -> Self {
+        self.auto_commit_bytes = threshold;
+        self
+    }
+
+    /// Commits automatically once `threshold` writes were logged since the
+    /// last commit, whatever their size. Writes replayed on open count
+    /// towards it. `None` disables it.
+    pub fn auto_commit_ops(mut self, threshold: Option<usize>) -> Self {
+        self.auto_commit_ops = threshold;
+        self
+    }
+
+    /// Caps the log at `limit` bytes: a write that would grow it past the
+    /// cap commits first and lands in the fresh log instead. Writes that
+    /// don't fit even in a fresh log fail with `DatabaseError::LogFull`.
+    /// `None` leaves the log uncapped.
+    pub fn max_log_bytes(mut self, limit: Option<u64>) -> Self {
+        self.max_log_bytes = limit;
+        self
+    }
+
+    /// Opens the database without write access, the database must already
+    /// exist.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Rejects writes of values longer than `limit` bytes, `None` allows
+    /// values of any size.
+    pub fn max_value_bytes(mut self, limit: Option<usize>) -> Self {
+        self.max_value_bytes = limit;
+        self
+    }
+
+    /// Codec used for values written to the log and checkpoint. Databases
+    /// written with a different codec, or none, still open.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Encrypts values written to the log and checkpoint with `cipher`,
+    /// keys are stored in plaintext. Records stay unencrypted in memory. The
+    /// same cipher must be used every time the database is opened.
+    pub fn encryption(mut self, cipher: Arc<dyn Cipher>) -> Self {
+        self.encryption = Some(CipherHook(cipher));
+        self
+    }
+
+    /// Keeps a bloom filter of the keys so lookups of absent keys mostly
+    /// skip the records map, sized for `rate` false positives. `None`
+    /// disables the filter.
+    pub fn bloom_false_positive_rate(mut self, rate: Option<f64>) -> Self {
+        self.bloom_false_positive_rate = rate;
+        self
+    }
+
+    /// Keeps at most `max_records` records in memory once they are in a
+    /// checkpoint, least recently used ones are read back from disk when
+    /// needed. Records written since the last commit always stay in memory.
+    pub fn max_records(mut self, max_records: Option<usize>) -> Self {
+        self.max_records = max_records;
+        self
+    }
+
+    /// Keeps the checkpoint and log of the last `count` versions before the
+    /// current one on commit, so `rollback_to_version` can return to them.
+    pub fn retain_versions(mut self, count: usize) -> Self {
+        self.retain_versions = count;
+        self
+    }
+
+    /// Splits the log into segments of about `limit` bytes, `logfile.N`
+    /// followed by `logfile.N.1`, `logfile.N.2` and so on. `None` keeps the
+    /// log in a single file.
+    pub fn log_segment_bytes(mut self, limit: Option<u64>) -> Self {
+        self.log_segment_bytes = limit;
+        self
+    }
+
+    /// Commits right after opening an existing database whose log holds
+    /// entries, so the next open doesn't have to replay them again. Has no
+    /// effect on read-only opens.
+    pub fn compact_on_open(mut self, compact_on_open: bool) -> Self {
+        self.compact_on_open = compact_on_open;
+        self
+    }
+
+    /// Fails writes to the empty key with `DatabaseError::InvalidKey`
+    /// instead of logging them. Empty keys are allowed by default.
+    pub fn reject_empty_keys(mut self, reject: bool) -> Self {
+        self.reject_empty_keys = reject;
+        self
+    }
+
+    /// Fails writes with a `timeout` lock error once they have waited
+    /// `timeout` for a lock held by another thread, `None` waits forever.
+    /// A write that times out waiting for readers has already been logged,
+    /// so it still takes effect the next time the database is opened.
+    pub fn lock_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Keeps using the records after a thread panicked mid-write instead of
+    /// failing every later operation with a lock error. Writes only insert
+    /// or remove whole entries, so the records stay consistent.
+    pub fn recover_poisoned_locks(mut self, recover: bool) -> Self {
+        self.recover_poisoned_locks = recover;
+        self
+    }
+
+    /// Fails the open with `DatabaseError::LogReplay` instead of replaying
+    /// a log of more than `limit` entries, `None` replays logs of any size.
+    pub fn max_replay_ops(mut self, limit: Option<usize>) -> Self {
+        self.max_replay_ops = limit;
+        self
+    }
+
+    /// Writes checkpoint records in key order, so databases holding the
+    /// same records write byte-identical checkpoints. Values encrypted
+    /// through `encryption` still differ, each one gets a nonce of its own.
+    pub fn sorted_checkpoints(mut self, sorted: bool) -> Self {
+        self.sorted_checkpoints = sorted;
+        self
+    }
+
+    /// Keeps a checksum of every value in memory, so `get_checked` can
+    /// detect values corrupted after they were written.
+    pub fn checksum_values(mut self, checksum_values: bool) -> Self {
+        self.checksum_values = checksum_values;
+        self
+    }
+
+    /// Log size past which `start_background_compaction` commits, by
+    /// default any log holding entries is committed.
+    pub fn compaction_threshold_bytes(mut self, threshold: u64) -> Self {
+        self.compaction_threshold_bytes = threshold;
+        self
+    }
+
+    /// Reports puts, gets, commits and log appends to `metrics`.
+    pub fn metrics(mut self, metrics: Arc<dyn Metrics>) -> Self {
+        self.metrics = Some(MetricsHook(metrics));
+        self
+    }
+
+    /// Calls `hook` with every operation replayed from the log on open, in
+    /// log order. The operations are replayed the same either way.
+    pub fn on_replay<F: FnMut(&LogOperation) + Send + 'static>(mut self, hook: F) -> Self {
+        self.on_replay = Some(ReplayHook(Arc::new(Mutex::new(hook))));
+        self
+    }
+
+    /// Normalizes keys with `transform` before they are looked up or
+    /// written, e.g. lowercasing them so lookups are case-insensitive. The
+    /// normalized key is the one logged and stored. `transform` must be
+    /// deterministic and idempotent, and the same one must be used every
+    /// time the database is opened. Prefix and range operations take
+    /// their bounds as-is.
+    pub fn key_transform<F>(mut self, transform: F) -> Self
+    where
+        F: Fn(&[u8]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        self.key_transform = Some(KeyTransform(Arc::new(transform)));
+        self
+    }
+
+    pub fn file_layout(mut self, layout: FileLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    pub fn open(self, path: PathBuf) -> Result<SimpleDB, DatabaseError> {
+        SimpleDB::open_with_options(path, self)
+    }
+
+    /// Opens `version` of the database at `path` read-only with these
+    /// options, see `SimpleDB::open_version`.
+    pub fn open_version(self, path: PathBuf, version: u64) -> Result<SimpleDB, DatabaseError> {
+        SimpleDB::open_version_with_options(path, version, self)
+    }
+
+    /// Rejects options no database can be opened with.
+    pub(crate) fn validate(&self) -> Result<(), DatabaseError> {
+        match self.bloom_false_positive_rate {
+            Some(rate) if !(rate > 0.0 && rate < 1.0) => Err(DatabaseError::InvalidOption {
+                reason: format!("bloom false positive rate {} is not between 0 and 1", rate),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn cipher(&self) -> Option<Arc<dyn Cipher>> {
+        self.encryption.as_ref().map(|hook| Arc::clone(&hook.0))
+    }
+}
+
+type ReplayFn = dyn FnMut(&LogOperation) + Send;
+
+/// The hook registered through `DbOptions::on_replay`.
+#[derive(Clone)]
+pub(crate) struct ReplayHook(pub(crate) Arc<Mutex<ReplayFn>>);
+
+impl fmt::Debug for ReplayHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ReplayHook")
+    }
+}
+
+type KeyTransformFn = dyn Fn(&[u8]) -> Vec<u8> + Send + Sync;
+
+/// The function registered through `DbOptions::key_transform`.
+#[derive(Clone)]
+pub(crate) struct KeyTransform(pub(crate) Arc<KeyTransformFn>);
+
+impl fmt::Debug for KeyTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyTransform")
+    }
+}
+
+/// Base names of the files a `SimpleDB` keeps in its directory. Checkpoint
+/// and log names get a `.<version>` suffix.
+#[derive(Debug, Clone)]
+pub struct FileLayout {
+    pub(crate) checkpoint: String,
+    pub(crate) index: String,
+    pub(crate) log: String,
+    pub(crate) compacted_log: String,
+    pub(crate) version: String,
+    pub(crate) new_version: String,
+    pub(crate) lock: String,
+    pub(crate) version_dirs: bool,
+}
+
+impl Default for FileLayout {
+    fn default() -> Self {
+        FileLayout {
+            checkpoint: String::from(CHECKPOINT_FILE_NAME),
+            index: String::from(INDEX_FILE_NAME),
+            log: String::from(LOG_FILE_NAME),
+            compacted_log: String::from(COMPACTED_LOG_FILE_NAME),
+            version: String::from(VERSION_FILE_NAME),
+            new_version: String::from(NEW_VERSION_FILE_NAME),
+            lock: String::from(LOCK_FILE_NAME),
+            version_dirs: false,
+        }
+    }
+}
+
+impl FileLayout {
+    pub fn new() -> Self {
+        FileLayout::default()
+    }
+
+    pub fn checkpoint(mut self, name: &str) -> Self {
+        self.checkpoint = String::from(name);
+        self
+    }
+
+    /// Name of the index written next to every checkpoint, see
+    /// `SimpleDB::lookup_in_checkpoint`.
+    pub fn index(mut self, name: &str) -> Self {
+        self.index = String::from(name);
+        self
+    }
+
+    pub fn log(mut self, name: &str) -> Self {
+        self.log = String::from(name);
+        self
+    }
+
+    /// Name of the temporary file `SimpleDB::compact_log` writes before
+    /// replacing the log.
+    pub fn compacted_log(mut self, name: &str) -> Self {
+        self.compacted_log = String::from(name);
+        self
+    }
+
+    pub fn version(mut self, name: &str) -> Self {
+        self.version = String::from(name);
+        self
+    }
+
+    pub fn new_version(mut self, name: &str) -> Self {
+        self.new_version = String::from(name);
+        self
+    }
+
+    pub fn lock(mut self, name: &str) -> Self {
+        self.lock = String::from(name);
+        self
+    }
+
+    /// Keeps the checkpoint and log of each version under a `v<N>/`
+    /// subdirectory, `v1/checkpoint` and `v1/logfile`, instead of naming
+    /// them `checkpoint.1` and `logfile.1`. The version, new version and
+    /// lock files stay at the top level.
+    pub fn version_dirs(mut self, version_dirs: bool) -> Self {
+        self.version_dirs = version_dirs;
+        self
+    }
+}
@@ -0,0 +1,136 @@
+use crate::db::{Expiries, SimpleCollection};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Tracks which records are held in memory when `DbOptions::max_records`
+/// caps them. Records that are unchanged since the last checkpoint can be
+/// evicted in least recently used order, their values are then read back
+/// from the checkpoint on demand.
+pub(crate) struct Residency {
+    max_records: usize,
+    /// Offset of every record in the current checkpoint.
+    checkpoint_index: HashMap<Vec<u8>, u64>,
+    evicted: HashSet<Vec<u8>>,
+    /// Keys written since the checkpoint, which differ from it.
+    dirty: HashSet<Vec<u8>>,
+    /// Last use of every resident record.
+    last_used: HashMap<Vec<u8>, u64>,
+    /// Resident records that match the checkpoint, the candidates for
+    /// eviction, by last use.
+    clean_by_last_use: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+}
+
+impl Residency {
+    pub(crate) fn new(
+        max_records: usize,
+        checkpoint_index: HashMap<Vec<u8>, u64>,
+        dirty: HashSet<Vec<u8>>,
+    ) -> Self {
+        Residency {
+            max_records,
+            checkpoint_index,
+            evicted: HashSet::new(),
+            dirty,
+            last_used: HashMap::new(),
+            clean_by_last_use: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Starts tracking the use of `keys`, oldest first.
+    pub(crate) fn track<'a, I: IntoIterator<Item = &'a Vec<u8>>>(&mut self, keys: I) {
+        for key in keys {
+            self.touch(key);
+        }
+    }
+
+    /// Marks `key` as the most recently used record.
+    pub(crate) fn touch(&mut self, key: &[u8]) {
+        self.tick += 1;
+        if let Some(last_used) = self.last_used.insert(key.to_vec(), self.tick) {
+            self.clean_by_last_use.remove(&last_used);
+        }
+        if self.is_clean(key) {
+            self.clean_by_last_use.insert(self.tick, key.to_vec());
+        }
+    }
+
+    fn is_clean(&self, key: &[u8]) -> bool {
+        !self.dirty.contains(key) && self.checkpoint_index.contains_key(key)
+    }
+
+    /// Records a write to `key`, which is resident afterwards unless it was
+    /// deleted.
+    pub(crate) fn written(&mut self, key: &[u8], deleted: bool) {
+        self.evicted.remove(key);
+        self.dirty.insert(key.to_vec());
+        if deleted {
+            if let Some(last_used) = self.last_used.remove(key) {
+                self.clean_by_last_use.remove(&last_used);
+            }
+        } else {
+            self.touch(key);
+        }
+    }
+
+    /// Records that every record was deleted.
+    pub(crate) fn cleared(&mut self) {
+        self.evicted.clear();
+        self.last_used.clear();
+        self.clean_by_last_use.clear();
+    }
+
+    /// Offset of `key` in the current checkpoint, if it was evicted.
+    pub(crate) fn evicted_offset(&self, key: &[u8]) -> Option<u64> {
+        if self.evicted.contains(key) {
+            self.checkpoint_index.get(key).copied()
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn evicted_keys(&self) -> impl Iterator<Item = &Vec<u8>> {
+        self.evicted.iter()
+    }
+
+    pub(crate) fn evicted_len(&self) -> usize {
+        self.evicted.len()
+    }
+
+    /// Starts over from a new checkpoint, every record matches it.
+    pub(crate) fn checkpointed(&mut self, checkpoint_index: HashMap<Vec<u8>, u64>) {
+        self.checkpoint_index = checkpoint_index;
+        self.dirty.clear();
+        self.clean_by_last_use = self
+            .last_used
+            .iter()
+            .filter(|(key, _)| self.checkpoint_index.contains_key(*key))
+            .map(|(key, last_used)| (*last_used, key.clone()))
+            .collect();
+    }
+
+    /// Evicts least recently used records from `records` until it is within
+    /// the cap. Records that differ from the checkpoint or have a TTL stay.
+    pub(crate) fn evict(&mut self, records: &mut SimpleCollection, expiries: &Expiries) {
+        let mut excess = records.len().saturating_sub(self.max_records);
+        while excess > 0 {
+            let last_used = match self.clean_by_last_use.keys().next() {
+                Some(last_used) => *last_used,
+                None => break,
+            };
+            let key = match self.clean_by_last_use.remove(&last_used) {
+                Some(key) => key,
+                None => break,
+            };
+            // Records with a TTL are left out until they are used again.
+            if expiries.contains_key(&key) {
+                continue;
+            }
+            self.last_used.remove(&key);
+            records.remove(&key);
+            self.evicted.insert(key);
+            excess -= 1;
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use crate::db::{is_expired, Expiries, SimpleCollection};
+
+use std::sync::Arc;
+
+/// A frozen view of a `SimpleDB`, see `SimpleDB::snapshot`.
+///
+/// The records are shared with the database until its next write, which
+/// copies them, so taking a snapshot is cheap but holding one across writes
+/// is not.
+pub struct Snapshot {
+    records: Arc<SimpleCollection>,
+    expiries: Expiries,
+    taken_at: u64,
+}
+
+impl Snapshot {
+    pub(crate) fn new(records: Arc<SimpleCollection>, expiries: Expiries, taken_at: u64) -> Self {
+        Snapshot {
+            records,
+            expiries,
+            taken_at,
+        }
+    }
+
+    /// Returns the value `key` had when the snapshot was taken.
+    pub fn get<S: Into<Vec<u8>>>(&self, key: S) -> Option<Vec<u8>> {
+        let key: Vec<u8> = key.into();
+        if is_expired(&self.expiries, &key, self.taken_at) {
+            return None;
+        }
+        self.records.get(&key).cloned()
+    }
+
+    pub fn contains_key<S: Into<Vec<u8>>>(&self, key: S) -> bool {
+        self.get(key).is_some()
+    }
+}
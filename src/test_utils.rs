@@ -1,26 +1,97 @@
+use std::env;
+use std::fs::remove_dir_all;
 use std::panic;
+use std::path::PathBuf;
+
+use crate::db::{CrashPoint, SimpleDB};
 
 pub fn run_test<T>(
     test: T,
-    setup: Option<Box<dyn FnOnce() -> ()>>,
-    teardown: Option<Box<dyn FnOnce() -> ()>>,
-) -> ()
-where
-    T: FnOnce() -> () + panic::UnwindSafe,
+    setup: Option<Box<dyn FnOnce()>>,
+    teardown: Option<Box<dyn FnOnce()>>,
+) where
+    T: FnOnce() + panic::UnwindSafe,
 {
-    match setup {
-        Some(func) => func(),
-        None => (),
-    };
+    if let Some(func) = setup {
+        func();
+    }
 
-    let result = panic::catch_unwind(|| test());
+    let result = panic::catch_unwind(test);
 
-    match teardown {
-        Some(func) => func(),
-        None => (),
-    };
+    if let Some(func) = teardown {
+        func();
+    }
 
     if let Err(err) = result {
         panic::resume_unwind(err);
     }
 }
+
+/// Directory the tests open their database in.
+pub fn get_temp_test_current_path() -> PathBuf {
+    let mut current_path = env::current_dir().unwrap();
+    current_path.push("_temp_test");
+    current_path
+}
+
+/// Directory for tests that need a second database next to the first.
+pub fn get_other_temp_test_path() -> PathBuf {
+    let mut path = get_temp_test_current_path();
+    path.set_file_name("_temp_test_other");
+    path
+}
+
+pub fn delete_db_files() {
+    remove_dir_all(get_temp_test_current_path()).unwrap();
+}
+
+pub fn delete_all_db_files() {
+    delete_db_files();
+    let other_temp_test_path = get_other_temp_test_path();
+    if other_temp_test_path.exists() {
+        remove_dir_all(other_temp_test_path).unwrap();
+    }
+}
+
+/// Runs `commit` with a crash injected at `point`, then leaks `db` without
+/// running its destructor, leaving the files on disk (including a stale
+/// `LOCK`) as a crash would.
+pub fn crash_commit_at(mut db: SimpleDB, point: CrashPoint) {
+    db.set_crash_point(Some(point));
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| db.commit()));
+    assert!(result.is_err(), "commit finished before reaching {:?}", point);
+    db.release_lock();
+    std::mem::forget(db);
+}
+
+pub fn crash_before_checkpoint_rename(db: SimpleDB) {
+    crash_commit_at(db, CrashPoint::BeforeCheckpointRename)
+}
+
+pub fn crash_after_checkpoint_write(db: SimpleDB) {
+    crash_commit_at(db, CrashPoint::AfterCheckpointWrite)
+}
+
+pub fn crash_after_new_version_file(db: SimpleDB) {
+    crash_commit_at(db, CrashPoint::AfterNewVersionFile)
+}
+
+pub fn crash_before_rename(db: SimpleDB) {
+    crash_commit_at(db, CrashPoint::BeforeRename)
+}
+
+/// A JSON array of user records, the kind of value `Deflate` is for.
+pub fn json_value() -> Vec<u8> {
+    let users: Vec<String> = (0..200)
+        .map(|id| {
+            format!(
+                r#"{{"id":{},"name":"user{}","email":"user{}@example.com","active":{}}}"#,
+                id,
+                id,
+                id,
+                id % 3 != 0
+            )
+        })
+        .collect();
+    format!("[{}]", users.join(",")).into_bytes()
+}
@@ -0,0 +1,100 @@
+use crate::db::SimpleDB;
+use crate::error::DatabaseError;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+
+/// A `SimpleDB` whose values are all of type `V`, stored in bincode's
+/// binary format. Keys stay raw bytes.
+pub struct TypedDb<V> {
+    db: SimpleDB,
+    value: PhantomData<fn() -> V>,
+}
+
+impl<V: Serialize + DeserializeOwned> TypedDb<V> {
+    pub fn new(db: SimpleDB) -> Self {
+        TypedDb {
+            db,
+            value: PhantomData,
+        }
+    }
+
+    pub fn open(path: PathBuf) -> Result<Self, DatabaseError> {
+        Ok(TypedDb::new(SimpleDB::open(path)?))
+    }
+
+    /// Returns the value stored under `key`, failing if it doesn't decode as
+    /// a `V`.
+    pub fn get<S: Into<Vec<u8>>>(&self, key: S) -> Result<Option<V>, DatabaseError> {
+        match self.db.get(key) {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn put<S: Into<Vec<u8>>>(&self, key: S, value: &V) -> Result<(), DatabaseError> {
+        self.db.put(key, bincode::serialize(value)?)
+    }
+
+    pub fn delete<S: Into<Vec<u8>>>(&self, key: S) -> Result<(), DatabaseError> {
+        self.db.delete(key)
+    }
+
+    /// The underlying database, for operations that don't depend on `V`
+    /// such as `commit`.
+    pub fn inner(&self) -> &SimpleDB {
+        &self.db
+    }
+
+    pub fn into_inner(self) -> SimpleDB {
+        self.db
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{delete_db_files, get_temp_test_current_path, run_test};
+    use serde::Deserialize;
+    use serial_test::serial;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct User {
+        name: String,
+        age: u32,
+    }
+
+    #[test]
+    #[serial]
+    fn test_typed_db() {
+        run_test(
+            || {
+                let user = User {
+                    name: String::from("bob"),
+                    age: 42,
+                };
+                let db = TypedDb::<User>::open(get_temp_test_current_path()).unwrap();
+                db.put("bob", &user).unwrap();
+                assert_eq!(db.get("bob").unwrap(), Some(user));
+                assert_eq!(db.get("alice").unwrap(), None);
+                drop(db);
+
+                let db = TypedDb::<User>::open(get_temp_test_current_path()).unwrap();
+                assert_eq!(
+                    db.get("bob").unwrap(),
+                    Some(User {
+                        name: String::from("bob"),
+                        age: 42,
+                    })
+                );
+
+                db.inner().put("raw", "x").unwrap();
+                assert!(matches!(db.get("raw"), Err(DatabaseError::Encoding(_))));
+            },
+            None,
+            Some(Box::new(delete_db_files)),
+        )
+    }
+}